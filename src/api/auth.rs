@@ -0,0 +1,163 @@
+//! Who may call which endpoint of the API. A request authenticates with a token, sent as
+//! `Authorization: Bearer <token>` or as `X-Api-Key: <token>`.
+
+use ring::constant_time::verify_slices_are_equal;
+use tiny_http::Header;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// No token, when the read endpoints need one
+    Anonymous,
+    ReadOnly,
+    Admin,
+}
+
+/// Endpoints that only read what the node knows. Every other endpoint, including any that is
+/// added later and not listed here, requires the admin role.
+const READ_ONLY_PATHS: [&str; 20] = [
+    "/miner/status",
+    "/tx-generator/status",
+    "/blockchain/state",
+    "/blockchain/state-proof",
+    "/blockchain/block",
+    "/blockchain/address",
+    "/blockchain/tips",
+    "/blockchain/transaction",
+    "/blockchain/address-transactions",
+    "/blockchain/longest-chain",
+    "/blockchain/longest-chain-tx",
+    "/blockchain/longest-chain-tx-count",
+    "/blockchain/stats",
+    "/rpc",
+    "/events",
+    "/network/peers",
+    "/network/metrics",
+    "/metrics",
+    "/light/headers",
+    "/light/transactions",
+];
+
+/// The role an endpoint requires. The JSON-RPC methods that need more are checked by the RPC.
+pub fn required_role(path: &str) -> Role {
+    if READ_ONLY_PATHS.contains(&path) {
+        Role::ReadOnly
+    } else {
+        Role::Admin
+    }
+}
+
+/// The tokens of a node. Without an admin token, everyone is an admin, as before tokens were
+/// introduced. Without a read-only token, the read endpoints are open to everyone.
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    admin_token: Option<String>,
+    read_token: Option<String>,
+}
+
+impl Auth {
+    /// A read-only token only makes sense with an admin token, otherwise the admin endpoints
+    /// would be more open than the read ones.
+    pub fn new(admin_token: Option<String>, read_token: Option<String>) -> Result<Self, String> {
+        if admin_token.is_none() && read_token.is_some() {
+            return Err("a read-only token requires an admin token".to_string());
+        }
+        if admin_token.iter().chain(read_token.iter()).any(|t| t.is_empty()) {
+            return Err("tokens cannot be empty".to_string());
+        }
+        Ok(Self { admin_token, read_token })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    /// The role of a request, from the token in its headers. `None` if the token is unknown.
+    pub fn role(&self, headers: &[Header]) -> Option<Role> {
+        let matches = |expected: &Option<String>, token: &str| match expected {
+            Some(e) => verify_slices_are_equal(e.as_bytes(), token.as_bytes()).is_ok(),
+            None => false,
+        };
+        match token(headers) {
+            Some(t) if matches(&self.admin_token, t) => Some(Role::Admin),
+            Some(t) if matches(&self.read_token, t) => Some(Role::ReadOnly),
+            Some(_) if self.is_enabled() => None,
+            _ if !self.is_enabled() => Some(Role::Admin),
+            _ if self.read_token.is_some() => Some(Role::Anonymous),
+            _ => Some(Role::ReadOnly),
+        }
+    }
+
+    /// The role of a request allowed on `path`, or the HTTP status and the message to deny it.
+    pub fn authorize(&self, headers: &[Header], path: &str) -> Result<Role, (u16, &'static str)> {
+        match self.role(headers) {
+            None => Err((401, "invalid token")),
+            Some(role) if role >= required_role(path) => Ok(role),
+            Some(_) if token(headers).is_some() => Err((403, "this endpoint requires the admin role")),
+            Some(_) => Err((401, "missing token")),
+        }
+    }
+}
+
+/// The token of a request, from the `Authorization` or the `X-Api-Key` header.
+fn token(headers: &[Header]) -> Option<&str> {
+    headers.iter().find_map(|h| {
+        if h.field.equiv("Authorization") {
+            let value = h.value.as_str();
+            value.get(..7).filter(|p| p.eq_ignore_ascii_case("bearer ")).map(|_| value[7..].trim())
+        } else if h.field.equiv("X-Api-Key") {
+            Some(h.value.as_str().trim())
+        } else {
+            None
+        }
+    })
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(lines: &[&str]) -> Vec<Header> {
+        lines.iter().map(|l| l.parse::<Header>().unwrap()).collect()
+    }
+
+    #[test]
+    fn roles() {
+        let open = Auth::default();
+        assert_eq!(open.role(&[]), Some(Role::Admin));
+
+        let auth = Auth::new(Some("secret".into()), None).unwrap();
+        assert_eq!(auth.role(&[]), Some(Role::ReadOnly));
+        assert_eq!(auth.role(&headers(&["Authorization: Bearer secret"])), Some(Role::Admin));
+        assert_eq!(auth.role(&headers(&["X-Api-Key: secret"])), Some(Role::Admin));
+        assert_eq!(auth.role(&headers(&["Authorization: Bearer wrong"])), None);
+        assert_eq!(auth.role(&headers(&["Authorization: Basic secret"])), Some(Role::ReadOnly));
+
+        let auth = Auth::new(Some("secret".into()), Some("reader".into())).unwrap();
+        assert_eq!(auth.role(&[]), Some(Role::Anonymous));
+        assert_eq!(auth.role(&headers(&["authorization: bearer reader"])), Some(Role::ReadOnly));
+
+        assert!(Auth::new(None, Some("reader".into())).is_err());
+        assert!(Auth::new(Some("".into()), None).is_err());
+    }
+
+    #[test]
+    fn endpoint_roles() {
+        assert_eq!(required_role("/miner/start"), Role::Admin);
+        assert_eq!(required_role("/transaction"), Role::Admin);
+        assert_eq!(required_role("/blockchain/longest-chain"), Role::ReadOnly);
+        assert_eq!(required_role("/metrics"), Role::ReadOnly);
+        assert_eq!(required_role("/miner/generate"), Role::Admin);
+        assert_eq!(required_role("/not-an-endpoint"), Role::Admin);
+
+        let auth = Auth::new(Some("secret".into()), Some("reader".into())).unwrap();
+        let reader = headers(&["X-Api-Key: reader"]);
+        assert_eq!(auth.authorize(&reader, "/metrics"), Ok(Role::ReadOnly));
+        assert_eq!(auth.authorize(&reader, "/miner/start").unwrap_err().0, 403);
+        assert_eq!(auth.authorize(&[], "/metrics").unwrap_err().0, 401);
+        assert_eq!(auth.authorize(&headers(&["X-Api-Key: nope"]), "/metrics").unwrap_err().0, 401);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
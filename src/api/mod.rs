@@ -1,30 +1,79 @@
+pub mod auth;
+pub mod prometheus;
+pub mod rpc;
+
 use serde::Serialize;
-use crate::blockchain::Blockchain;
-use crate::types::transaction_generator::{TransactionGenerator};
+use crate::blockchain::{Blockchain, ChainState};
+use crate::blockchain::stats::ChainStats;
+use crate::blockchain::params::{Network, Params};
+use crate::config::MempoolConfig;
+use auth::Auth;
+use crate::blockchain::events::{Event, TransactionInfo, EVENT_TYPES};
+use crate::types::transaction_generator::Handle as TxGeneratorHandle;
 use crate::miner::Handle as MinerHandle;
-// use crate::transaction_generator::Handle as TXHandle;
+use crate::miner::State;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
-use crate::types::transaction::SignedTransaction;
+use crate::network::light::LightClient;
+use crate::types::block::Block;
+use crate::types::transaction::{SignedTransaction, Rejection, check_for_mempool};
 use crate::types::hash::{H256, Hashable};
+use crate::types::state;
 
 use log::info;
+use std::convert::TryFrom;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::io::{Read, Write};
+use std::time::Duration;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
+use tiny_http::StatusCode;
 use url::Url;
 use crate::types::address::Address;
 
 pub struct Server {
-    handle: HTTPServer,
+    handle: Arc<HTTPServer>,
     miner: MinerHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    tx_handler: TransactionGenerator,
-    states: Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>>
+    tx_handler: TxGeneratorHandle,
+    states: Arc<Mutex<HashMap<H256, state::State>>>,
+    mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    orphans: Arc<Mutex<HashMap<H256, Vec<Block>>>>,
+    /// Most transactions in the mempool
+    max_mempool: usize,
+    chain: &'static Params,
+    auth: Auth,
+}
+
+/// What the API of a full node serves and controls.
+pub struct Context<'a> {
+    pub miner: &'a MinerHandle,
+    pub network: &'a NetworkServerHandle,
+    pub tx_generator: &'a TxGeneratorHandle,
+    pub chain: &'a ChainState,
+    pub mempool_config: &'a MempoolConfig,
+    pub params: &'static Params,
+    pub auth: &'a Auth,
+}
+
+/// A running API server.
+pub struct Handle {
+    http: Arc<HTTPServer>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Handle {
+    /// Stop accepting requests. The requests already accepted are still answered.
+    pub fn stop(self) {
+        self.http.unblock();
+        let _ = self.thread.join();
+    }
 }
 
 #[derive(Serialize)]
@@ -33,6 +82,238 @@ struct ApiResponse {
     message: String,
 }
 
+/// Accounts returned by `/blockchain/state` when no limit is given
+const DEFAULT_STATE_LIMIT: usize = 100;
+/// Most accounts returned by `/blockchain/state` at once
+const MAX_STATE_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+struct AccountResponse {
+    address: String,
+    nonce: u64,
+    balance: u64,
+}
+
+/// A page of the accounts after a block, ordered by address.
+#[derive(Serialize)]
+struct StateResponse {
+    block: String,
+    height: u32,
+    /// Accounts matching the request, of which `accounts` starts at `offset`
+    total: usize,
+    offset: usize,
+    accounts: Vec<AccountResponse>,
+}
+
+/// An account of the state after a block, or its absence, with the proof that it is
+/// committed to by the state root in the block header.
+#[derive(Serialize)]
+struct StateProofResponse {
+    block: String,
+    state_root: String,
+    address: String,
+    exists: bool,
+    nonce: Option<u64>,
+    balance: Option<u64>,
+    /// Depth at which the path of the sparse Merkle proof ends
+    depth: usize,
+    /// Which siblings of the path, by depth, are not empty
+    non_empty: String,
+    /// The non-empty siblings, from the leaf up
+    siblings: Vec<String>,
+    /// For an absent account, the key and value of the other account alone in its subtree
+    leaf: Option<(String, String)>,
+}
+
+/// A transaction of the longest chain, found by its hash.
+#[derive(Serialize)]
+struct TransactionResponse {
+    #[serde(flatten)]
+    transaction: TransactionInfo,
+    block: String,
+    height: u32,
+    /// Position of the transaction in the block
+    position: usize,
+    confirmations: u32,
+}
+
+impl TransactionResponse {
+    /// Describe the transaction at `position` of a block of the longest chain.
+    fn new(blockchain: &Blockchain, block: &Block, position: usize, params: &Params) -> Self {
+        Self {
+            transaction: TransactionInfo::new(&block.content.data[position], params),
+            block: block.hash().to_string(),
+            height: block.height,
+            position,
+            confirmations: blockchain.confirmations(&block.hash()).unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockResponse {
+    hash: String,
+    height: u32,
+    parent: String,
+    nonce: u32,
+    difficulty: String,
+    /// Milliseconds since the epoch. The header keeps a u128, which serde_json cannot put in a
+    /// JSON number, and a u64 lasts far longer than any chain.
+    timestamp: u64,
+    merkle_root: String,
+    state_root: String,
+    /// Size of the serialized block in bytes
+    size: usize,
+    /// Zero for a block outside the longest chain
+    confirmations: u32,
+    transactions: Vec<TransactionInfo>,
+}
+
+impl BlockResponse {
+    fn new(blockchain: &Blockchain, block: &Block, params: &Params) -> Self {
+        let header = &block.header;
+        Self {
+            hash: block.hash().to_string(),
+            height: block.height,
+            parent: header.parent.to_string(),
+            nonce: header.nonce,
+            difficulty: header.difficulty.to_string(),
+            timestamp: u64::try_from(header.timestamp).unwrap_or(u64::MAX),
+            merkle_root: header.merkle_root.to_string(),
+            state_root: header.state_root.to_string(),
+            size: bincode::serialized_size(block).unwrap() as usize,
+            confirmations: blockchain.confirmations(&block.hash()).unwrap_or(0),
+            transactions: block.content.data.iter().map(|tx| TransactionInfo::new(tx, params)).collect(),
+        }
+    }
+}
+
+/// The account of an address in the state at the tip of the longest chain.
+#[derive(Serialize)]
+struct AddressResponse {
+    address: String,
+    block: String,
+    exists: bool,
+    nonce: u64,
+    balance: u64,
+}
+
+/// A block without children, the tip of the longest chain or of a fork.
+#[derive(Serialize)]
+struct TipResponse {
+    hash: String,
+    height: u32,
+    longest: bool,
+}
+
+/// The outcome of a submitted transaction.
+#[derive(Serialize)]
+struct SubmitResponse {
+    accepted: bool,
+    hash: String,
+    /// Why the transaction was rejected
+    reason: Option<Rejection>,
+}
+
+/// Most bytes read from the body of a submitted transaction
+const MAX_TRANSACTION_BODY: u64 = 64 * 1024;
+
+/// Decode a transaction submitted as JSON, or as the hex encoding of its bincode serialization.
+fn decode_transaction(body: &str) -> Result<SignedTransaction, String> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        return serde_json::from_str(body).map_err(|e| format!("error parsing JSON transaction: {}", e));
+    }
+    let bytes = hex::decode(body).map_err(|e| format!("error decoding hex transaction: {}", e))?;
+    bincode::config()
+        .limit(MAX_TRANSACTION_BODY)
+        .deserialize(&bytes)
+        .map_err(|e| format!("error deserializing transaction: {}", e))
+}
+
+/// Check a transaction against the state at the tip of the longest chain, and if it is valid,
+/// add it to the mempool and relay it to our peers.
+fn submit_transaction(
+    network: &NetworkServerHandle,
+    blockchain: &Arc<Mutex<Blockchain>>,
+    states: &Arc<Mutex<HashMap<H256, state::State>>>,
+    mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    max_mempool: usize,
+    tx: SignedTransaction,
+) -> Result<H256, Rejection> {
+    let tx_hash = tx.hash();
+    let blockchain_guard = blockchain.lock().unwrap();
+    let check = match states.lock().unwrap().get(&blockchain_guard.tip()) {
+        Some(state) => check_for_mempool(&tx, state),
+        None => Err(Rejection::UnknownSender),
+    };
+    let events = blockchain_guard.events().clone();
+    std::mem::drop(blockchain_guard);
+    check?;
+    let mut mempool_guard = mempool.lock().unwrap();
+    if mempool_guard.len() >= max_mempool && !mempool_guard.contains_key(&tx_hash) {
+        return Err(Rejection::MempoolFull);
+    }
+    mempool_guard.insert(tx_hash, tx.clone());
+    std::mem::drop(mempool_guard);
+    events.publish(Event::mempool_added(&tx));
+    network.relay_transactions(vec![tx]);
+    Ok(tx_hash)
+}
+
+/// Interval of the comments sent on an idle event stream, which keep proxies from closing it
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Send events as Server-Sent Events until the client goes away. Only the events of the given
+/// types, and involving one of the given addresses, are sent; all of them if none are given.
+fn stream_events(
+    req: tiny_http::Request,
+    events: Receiver<Event>,
+    types: Vec<String>,
+    addresses: Vec<Address>,
+    params: &Params,
+) {
+    let headers = vec![
+        "Content-Type: text/event-stream".parse::<Header>().unwrap(),
+        "Cache-Control: no-cache".parse::<Header>().unwrap(),
+    ];
+    let response = Response::new(StatusCode(200), headers, std::io::empty(), Some(0), None);
+    // tiny_http buffers response bodies, so write the events to the connection itself
+    let mut stream = req.upgrade("text/event-stream", response);
+    loop {
+        let chunk = match events.recv_timeout(EVENT_KEEPALIVE) {
+            Ok(event) => {
+                if !types.is_empty() && !types.iter().any(|t| t == event.name()) {
+                    continue;
+                }
+                if !addresses.is_empty() && !addresses.iter().any(|a| event.involves(a)) {
+                    continue;
+                }
+                let event = event.format_addresses(params);
+                format!("event: {}\ndata: {}\n\n", event.name(), serde_json::to_string(&event).unwrap())
+            }
+            Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if stream.write_all(chunk.as_bytes()).and_then(|_| stream.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Most blocks mined by one call of `/miner/generate`
+const MAX_GENERATE: u64 = 1000;
+
+/// Most bytes read from the body of a JSON-RPC request
+const MAX_RPC_BODY: u64 = 1024 * 1024;
+
+/// The header chain followed by a light client.
+#[derive(Serialize)]
+struct HeadersResponse {
+    tip: String,
+    height: u32,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -45,6 +326,22 @@ macro_rules! respond_result {
         $req.respond(resp).unwrap();
     }};
 }
+macro_rules! respond_error {
+    ( $req:expr, $status:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let payload = ApiResponse {
+            success: false,
+            message: $message.to_string(),
+        };
+        let mut resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_status_code($status)
+            .with_header(content_type);
+        if $status == 401 {
+            resp.add_header("WWW-Authenticate: Bearer".parse::<Header>().unwrap());
+        }
+        $req.respond(resp).unwrap();
+    }};
+}
 macro_rules! respond_json {
     ( $req:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -55,30 +352,33 @@ macro_rules! respond_json {
 }
 
 impl Server {
-    pub fn start(
-        addr: std::net::SocketAddr,
-        miner: &MinerHandle,
-        network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>, 
-        tx_handler: &TransactionGenerator,
-        states: &Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>>
-    ) {
-        let handle = HTTPServer::http(&addr).unwrap();
+    pub fn start(addr: std::net::SocketAddr, context: &Context) -> Handle {
+        let handle = Arc::new(HTTPServer::http(addr).unwrap());
         let server = Self {
-            handle,
-            miner: miner.clone(),
-            network: network.clone(),
-            blockchain: Arc::clone(blockchain),
-            tx_handler: tx_handler.clone(),
-            states: Arc::clone(states)
+            handle: Arc::clone(&handle),
+            miner: context.miner.clone(),
+            network: context.network.clone(),
+            blockchain: Arc::clone(&context.chain.blockchain),
+            tx_handler: context.tx_generator.clone(),
+            states: Arc::clone(&context.chain.states),
+            mempool: Arc::clone(&context.chain.mempool),
+            orphans: Arc::clone(&context.chain.orphans),
+            max_mempool: context.mempool_config.max_transactions,
+            chain: context.params,
+            auth: context.auth.clone(),
         };
-        thread::spawn(move || {
+        let thread = thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let blockchain = Arc::clone(&server.blockchain);
                 let tx_handler = server.tx_handler.clone();
                 let states = Arc::clone(&server.states);
+                let mempool = Arc::clone(&server.mempool);
+                let orphans = Arc::clone(&server.orphans);
+                let max_mempool = server.max_mempool;
+                let chain = server.chain;
+                let auth = server.auth.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -89,6 +389,13 @@ impl Server {
                             return;
                         }
                     };
+                    let role = match auth.authorize(req.headers(), url.path()) {
+                        Ok(role) => role,
+                        Err((status, message)) => {
+                            respond_error!(req, status, message);
+                            return;
+                        }
+                    };
                     match url.path() {
                         "/miner/start" => {
                             let params = url.query_pairs();
@@ -111,50 +418,426 @@ impl Server {
                                     return;
                                 }
                             };
+                            if miner.status().state == State::Stopped {
+                                respond_result!(req, false, "miner is stopped");
+                                return;
+                            }
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/resume" => {
+                            let status = miner.status();
+                            if status.state == State::Stopped {
+                                respond_result!(req, false, "miner is stopped");
+                                return;
+                            }
+                            if status.lambda.is_none() {
+                                respond_result!(req, false, "miner was never started");
+                                return;
+                            }
+                            miner.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stop" => {
+                            miner.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
+                        // Mine blocks at once, so that tests can build a chain without waiting
+                        "/miner/generate" => {
+                            if chain.network != Network::Regtest {
+                                respond_result!(req, false, "generate is only available on regtest");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) if v > 0 && v <= MAX_GENERATE => v,
+                                Some(_) => {
+                                    respond_result!(req, false, format!("blocks must be between 1 and {}", MAX_GENERATE));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing blocks");
+                                    return;
+                                }
+                            };
+                            let hashes: Vec<String> = miner.generate(blocks).iter().map(|h| h.to_string()).collect();
+                            respond_json!(req, hashes);
+                        }
+                        // The accounts after a block, by hash or by height in the longest chain:
+                        // /blockchain/state?block=10&address=...&offset=0&limit=100
                         "/blockchain/state" => {
+                            let mut hash = None;
+                            let mut height = None;
+                            let mut addresses = Vec::new();
+                            let mut offset = 0;
+                            let mut limit = DEFAULT_STATE_LIMIT;
+                            for (key, value) in url.query_pairs() {
+                                match key.as_ref() {
+                                    "hash" => {
+                                        let mut bytes = [0u8; 32];
+                                        if hex::decode_to_slice(value.as_ref(), &mut bytes).is_err() {
+                                            respond_result!(req, false, "hash must be 32 hex encoded bytes");
+                                            return;
+                                        }
+                                        hash = Some(H256::from(bytes));
+                                    }
+                                    "block" | "height" => match value.parse::<usize>() {
+                                        Ok(v) => height = Some(v),
+                                        Err(e) => {
+                                            respond_result!(req, false, format!("error parsing {}: {}", key, e));
+                                            return;
+                                        }
+                                    },
+                                    "address" => match chain.parse_address(&value) {
+                                        Ok(address) => addresses.push(address),
+                                        Err(e) => {
+                                            respond_result!(req, false, e);
+                                            return;
+                                        }
+                                    },
+                                    "offset" => match value.parse::<usize>() {
+                                        Ok(v) => offset = v,
+                                        Err(e) => {
+                                            respond_result!(req, false, format!("error parsing offset: {}", e));
+                                            return;
+                                        }
+                                    },
+                                    "limit" => match value.parse::<usize>() {
+                                        Ok(v) if v > 0 && v <= MAX_STATE_LIMIT => limit = v,
+                                        _ => {
+                                            respond_result!(
+                                                req,
+                                                false,
+                                                format!("limit must be between 1 and {}", MAX_STATE_LIMIT)
+                                            );
+                                            return;
+                                        }
+                                    },
+                                    _ => {}
+                                }
+                            }
+                            let blockchain = blockchain.lock().unwrap();
+                            let block_hash = match (hash, height) {
+                                (Some(_), Some(_)) => {
+                                    respond_result!(req, false, "use either hash or block, not both");
+                                    return;
+                                }
+                                (Some(h), None) => h,
+                                (None, Some(height)) => match blockchain.all_blocks_in_longest_chain().get(height) {
+                                    Some(h) => *h,
+                                    None => {
+                                        respond_error!(req, 404, format!("no block #{}", height));
+                                        return;
+                                    }
+                                },
+                                (None, None) => {
+                                    respond_result!(req, false, "missing block or hash");
+                                    return;
+                                }
+                            };
+                            let block_height = match blockchain.blocks.get(&block_hash) {
+                                Some(block) => block.height,
+                                None => {
+                                    respond_error!(req, 404, "block not found");
+                                    return;
+                                }
+                            };
+                            std::mem::drop(blockchain);
+                            let mut accounts = match states.lock().unwrap().get(&block_hash) {
+                                Some(state) => state::sorted_accounts(state),
+                                None => {
+                                    respond_error!(req, 404, "state of this block is unknown");
+                                    return;
+                                }
+                            };
+                            if !addresses.is_empty() {
+                                accounts.retain(|a| addresses.contains(&a.address));
+                            }
+                            respond_json!(req, StateResponse {
+                                block: block_hash.to_string(),
+                                height: block_height,
+                                total: accounts.len(),
+                                offset,
+                                accounts: accounts
+                                    .iter()
+                                    .skip(offset)
+                                    .take(limit)
+                                    .map(|a| AccountResponse {
+                                        address: chain.format_address(&a.address),
+                                        nonce: a.nonce,
+                                        balance: a.balance,
+                                    })
+                                    .collect(),
+                            });
+                        }
+                        "/blockchain/state-proof" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
-                            let block = match params.get("block") {
-                                Some(v) => v,
+                            let block = match params.get("block").map(|v| v.parse::<usize>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing block: {}", e));
+                                    return;
+                                }
                                 None => {
                                     respond_result!(req, false, "missing block");
                                     return;
                                 }
                             };
-                            let block = match block.parse::<u64>() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing block: {}", e)
-                                    );
+                            let address = match params.get("address").map(|v| chain.parse_address(v)) {
+                                Some(Ok(address)) => address,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
                                     return;
                                 }
                             };
-                            println!("Request Block #: {:?}", block); 
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
-                            let mut v_string: Vec<String> = Vec::new();
-
-                            let block_usize = block as usize;
-                            if block_usize < v.len() {
-                                let state = &states.lock().unwrap()[&v[block_usize]];
-                                for (address, (nonce, bal)) in state.iter() {
-                                    let s_addr = format!("{}", address.to_string());
-                                    let s_nonce = nonce.to_string();
-                                    let s_bal = bal.to_string();
-                                    let tuple: String = s_addr + ", " + &s_nonce + ", " + &s_bal;
-                                    v_string.push(tuple);
+                            let block_hash = match v.get(block) {
+                                Some(h) => *h,
+                                None => {
+                                    respond_error!(req, 404, format!("no block #{}", block));
+                                    return;
+                                }
+                            };
+                            let state_root = blockchain.blocks[&block_hash].header.state_root;
+                            std::mem::drop(blockchain);
+                            let proof = match states.lock().unwrap().get(&block_hash) {
+                                Some(state) => state::prove(state, &address),
+                                None => {
+                                    respond_error!(req, 404, "state of this block is unknown");
+                                    return;
+                                }
+                            };
+                            respond_json!(req, StateProofResponse {
+                                block: block_hash.to_string(),
+                                state_root: state_root.to_string(),
+                                address: chain.format_address(&address),
+                                exists: proof.account.is_some(),
+                                nonce: proof.account.map(|a| a.nonce),
+                                balance: proof.account.map(|a| a.balance),
+                                depth: proof.proof.depth,
+                                non_empty: hex::encode(proof.proof.non_empty),
+                                siblings: proof.proof.siblings.iter().map(|h| h.to_string()).collect(),
+                                leaf: proof.proof.leaf.map(|(key, value)| (key.to_string(), value.to_string())),
+                            });
+                        }
+                        // Look a block up by hash, or by height in the longest chain
+                        "/blockchain/block" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain = blockchain.lock().unwrap();
+                            let block_hash = if let Some(hash) = params.get("hash") {
+                                let mut bytes = [0u8; 32];
+                                if hex::decode_to_slice(hash, &mut bytes).is_err() {
+                                    respond_result!(req, false, "hash must be 32 hex encoded bytes");
+                                    return;
+                                }
+                                H256::from(bytes)
+                            } else if let Some(height) = params.get("height") {
+                                let height = match height.parse::<usize>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing height: {}", e));
+                                        return;
+                                    }
+                                };
+                                match blockchain.all_blocks_in_longest_chain().get(height) {
+                                    Some(h) => *h,
+                                    None => {
+                                        respond_error!(req, 404, format!("no block #{}", height));
+                                        return;
+                                    }
                                 }
                             } else {
-                                println!("Out of range, no block # {:?}", block_usize);
+                                respond_result!(req, false, "missing hash or height");
+                                return;
+                            };
+                            match blockchain.blocks.get(&block_hash) {
+                                Some(block) => respond_json!(req, BlockResponse::new(&blockchain, block, chain)),
+                                None => respond_error!(req, 404, "block not found"),
                             }
-
-                            respond_json!(req, v_string);
+                        }
+                        "/blockchain/address" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address").map(|v| chain.parse_address(v)) {
+                                Some(Ok(address)) => address,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let tip = blockchain.lock().unwrap().tip();
+                            let account = match states.lock().unwrap().get(&tip) {
+                                Some(state) => state.get(&address).copied(),
+                                None => {
+                                    respond_result!(req, false, "state of the tip is unknown");
+                                    return;
+                                }
+                            };
+                            let (nonce, balance) = account.unwrap_or((0, 0));
+                            respond_json!(req, AddressResponse {
+                                address: chain.format_address(&address),
+                                block: tip.to_string(),
+                                exists: account.is_some(),
+                                nonce,
+                                balance,
+                            });
+                        }
+                        "/blockchain/tips" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let mut tips: Vec<TipResponse> = blockchain
+                                .tips()
+                                .iter()
+                                .map(|h| TipResponse {
+                                    hash: h.to_string(),
+                                    height: blockchain.blocks[h].height,
+                                    longest: *h == blockchain.tip(),
+                                })
+                                .collect();
+                            tips.sort_by_key(|t| std::cmp::Reverse(t.height));
+                            respond_json!(req, tips);
+                        }
+                        "/blockchain/transaction" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mut bytes = [0u8; 32];
+                            match params.get("hash").map(|v| hex::decode_to_slice(v, &mut bytes)) {
+                                Some(Ok(())) => {}
+                                Some(Err(_)) => {
+                                    respond_result!(req, false, "hash must be 32 hex encoded bytes");
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            }
+                            let blockchain = blockchain.lock().unwrap();
+                            match blockchain.find_transaction(&H256::from(bytes)) {
+                                Some((block, position)) => {
+                                    respond_json!(req, TransactionResponse::new(&blockchain, block, position, chain));
+                                }
+                                None => {
+                                    respond_error!(req, 404, "transaction not in the longest chain");
+                                }
+                            }
+                        }
+                        "/blockchain/address-transactions" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address").map(|v| chain.parse_address(v)) {
+                                Some(Ok(address)) => address,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let tx_hashes = match blockchain.address_transactions(&address) {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "the transaction index is disabled, see --tx-index");
+                                    return;
+                                }
+                            };
+                            let txs: Vec<TransactionResponse> = blockchain
+                                .find_transactions(&tx_hashes)
+                                .into_iter()
+                                .map(|(block, position)| TransactionResponse::new(&blockchain, block, position, chain))
+                                .collect();
+                            respond_json!(req, txs);
+                        }
+                        // Accept a transaction signed elsewhere, as JSON or hex encoded bincode
+                        "/transaction" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            let mut req = req;
+                            if let Err(e) = req.as_reader().take(MAX_TRANSACTION_BODY).read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let tx = match decode_transaction(&body) {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let tx_hash = tx.hash();
+                            let check = submit_transaction(&network, &blockchain, &states, &mempool, max_mempool, tx);
+                            respond_json!(req, SubmitResponse {
+                                accepted: check.is_ok(),
+                                hash: tx_hash.to_string(),
+                                reason: check.err(),
+                            });
+                        }
+                        "/rpc" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "use POST");
+                                return;
+                            }
+                            let mut body = String::new();
+                            let mut req = req;
+                            if let Err(e) = req.as_reader().take(MAX_RPC_BODY).read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let rpc = rpc::Rpc { network, blockchain, states, mempool, max_mempool, chain, role };
+                            match rpc.handle(&body) {
+                                Some(response) => respond_json!(req, response),
+                                // only notifications, there is nothing to answer
+                                None => req.respond(Response::empty(204)).unwrap(),
+                            }
+                        }
+                        // Stream events, optionally filtered by type and by address:
+                        // /events?type=new_tip&type=reorg&address=...
+                        "/events" => {
+                            let mut types = Vec::new();
+                            let mut addresses = Vec::new();
+                            for (key, value) in url.query_pairs() {
+                                match key.as_ref() {
+                                    "type" if EVENT_TYPES.contains(&value.as_ref()) => types.push(value.into_owned()),
+                                    "type" => {
+                                        respond_result!(req, false, format!("unknown event type {}", value));
+                                        return;
+                                    }
+                                    "address" => match chain.parse_address(&value) {
+                                        Ok(address) => addresses.push(address),
+                                        Err(e) => {
+                                            respond_result!(req, false, e);
+                                            return;
+                                        }
+                                    },
+                                    _ => {}
+                                }
+                            }
+                            let events = blockchain.lock().unwrap().events().subscribe();
+                            stream_events(req, events, types, addresses, chain);
                         }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
@@ -178,13 +861,63 @@ impl Server {
                                 }
                             };
 
+                            if tx_handler.status().state == State::Stopped {
+                                respond_result!(req, false, "transaction generator is stopped");
+                                return;
+                            }
                             tx_handler.start(theta);
                             respond_result!(req, true, "ok");
                         }
+                        "/tx-generator/pause" => {
+                            tx_handler.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/resume" => {
+                            let status = tx_handler.status();
+                            if status.state == State::Stopped {
+                                respond_result!(req, false, "transaction generator is stopped");
+                                return;
+                            }
+                            if status.theta.is_none() {
+                                respond_result!(req, false, "transaction generator was never started");
+                                return;
+                            }
+                            tx_handler.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/stop" => {
+                            tx_handler.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/status" => {
+                            respond_json!(req, tx_handler.status());
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/network/metrics" => {
+                            respond_json!(req, network.metrics().snapshot());
+                        }
+                        "/metrics" => {
+                            let peers = network.peers();
+                            let miner = miner.status();
+                            let mempool_size = mempool.lock().unwrap().len();
+                            let orphans = orphans.lock().unwrap().values().map(|v| v.len()).sum();
+                            let text = prometheus::render(&prometheus::Sources {
+                                blockchain: &blockchain.lock().unwrap(),
+                                mempool_size,
+                                orphans,
+                                peers: &peers,
+                                network: &network.metrics().snapshot(),
+                                miner: &miner,
+                            });
+                            let content_type = "Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(text).with_header(content_type)).unwrap();
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -217,8 +950,12 @@ impl Server {
                             respond_json!(req, v_string);
                         }
                         "/blockchain/longest-chain-tx-count" => {
-                            // unimplemented!()
-                            respond_result!(req, false, "unimplemented!");
+                            let blockchain = blockchain.lock().unwrap();
+                            respond_json!(req, blockchain.longest_chain_tx_count());
+                        }
+                        "/blockchain/stats" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            respond_json!(req, ChainStats::new(&blockchain));
                         }
                         _ => {
                             let content_type =
@@ -239,5 +976,125 @@ impl Server {
             }
         });
         info!("API server listening at {}", &addr);
+        Handle { http: handle, thread }
+    }
+
+    /// Start the API of a light client, which only knows the header chain and the transactions
+    /// it watches.
+    pub fn start_light(
+        addr: std::net::SocketAddr,
+        network: &NetworkServerHandle,
+        client: &Arc<Mutex<LightClient>>,
+        chain: &'static Params,
+        auth: &Auth,
+    ) -> Handle {
+        let handle = Arc::new(HTTPServer::http(addr).unwrap());
+        let http = Arc::clone(&handle);
+        let network = network.clone();
+        let client = Arc::clone(client);
+        let auth = auth.clone();
+        let thread = thread::spawn(move || {
+            for req in http.incoming_requests() {
+                let network = network.clone();
+                let client = Arc::clone(&client);
+                let auth = auth.clone();
+                thread::spawn(move || {
+                    // a valid url requires a base
+                    let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
+                    let url = match base_url.join(req.url()) {
+                        Ok(u) => u,
+                        Err(e) => {
+                            respond_result!(req, false, format!("error parsing url: {}", e));
+                            return;
+                        }
+                    };
+                    if let Err((status, message)) = auth.authorize(req.headers(), url.path()) {
+                        respond_error!(req, status, message);
+                        return;
+                    }
+                    match url.path() {
+                        "/light/headers" => {
+                            let client = client.lock().unwrap();
+                            respond_json!(req, HeadersResponse {
+                                tip: client.headers().tip().to_string(),
+                                height: client.headers().height(),
+                            });
+                        }
+                        // Watch a transaction and ask our peers for the proof of its inclusion
+                        "/light/watch" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mut bytes = [0u8; 32];
+                            match params.get("tx").map(|v| hex::decode_to_slice(v, &mut bytes)) {
+                                Some(Ok(())) => {}
+                                Some(Err(_)) => {
+                                    respond_result!(req, false, "tx must be 32 hex encoded bytes");
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            }
+                            let tx_hash = H256::from(bytes);
+                            client.lock().unwrap().watch(tx_hash);
+                            network.broadcast(Message::GetTxProofs(vec![tx_hash]));
+                            respond_result!(req, true, "ok");
+                        }
+                        // Watch the transactions of an address, relayed through our filter
+                        "/light/watch-address" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address").map(|v| chain.parse_address(v)) {
+                                Some(Ok(address)) => address,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let mut client = client.lock().unwrap();
+                            client.watch_address(address);
+                            let filter = client.filter();
+                            std::mem::drop(client);
+                            network.broadcast(Message::FilterLoad(filter));
+                            respond_result!(req, true, "ok");
+                        }
+                        "/light/transactions" => {
+                            respond_json!(req, client.lock().unwrap().status());
+                        }
+                        "/network/ping" => {
+                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            respond_result!(req, true, "ok");
+                        }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/network/metrics" => {
+                            respond_json!(req, network.metrics().snapshot());
+                        }
+                        _ => {
+                            let content_type =
+                                "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = ApiResponse {
+                                success: false,
+                                message: "endpoint not found".to_string(),
+                            };
+                            let resp = Response::from_string(
+                                serde_json::to_string_pretty(&payload).unwrap(),
+                            )
+                            .with_header(content_type)
+                            .with_status_code(404);
+                            req.respond(resp).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        info!("Light client API server listening at {}", &addr);
+        Handle { http: handle, thread }
     }
 }
@@ -0,0 +1,161 @@
+//! The `/metrics` endpoint, in the text format scraped by Prometheus.
+
+use crate::blockchain::Blockchain;
+use crate::miner::{State, Status as MinerStatus};
+use crate::network::metrics::MetricsSnapshot;
+use crate::network::peer::{Direction, PeerInfo};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Metric families, each written with its `# HELP` and `# TYPE` lines before its samples.
+#[derive(Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        writeln!(self.text, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.text, "# TYPE {} {}", name, kind).unwrap();
+        self
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) -> &mut Self {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            write!(self.text, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.text, " {}", value).unwrap();
+        self
+    }
+
+    pub fn into_string(self) -> String {
+        self.text
+    }
+}
+
+/// Escape a label value: backslashes, double quotes and line feeds.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// What the node reports, gathered by the API from the subsystems.
+pub struct Sources<'a> {
+    pub blockchain: &'a Blockchain,
+    pub mempool_size: usize,
+    pub orphans: usize,
+    pub peers: &'a [PeerInfo],
+    pub network: &'a MetricsSnapshot,
+    pub miner: &'a MinerStatus,
+}
+
+pub fn render(sources: &Sources) -> String {
+    let mut e = Exposition::default();
+    let blockchain = sources.blockchain;
+    let tip = &blockchain.blocks[&blockchain.tip()];
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+    e.family("bitcoin_chain_height", "gauge", "Height of the tip of the longest chain.")
+        .sample("bitcoin_chain_height", &[], tip.height);
+    e.family("bitcoin_chain_tip_age_seconds", "gauge", "Seconds since the timestamp of the tip.")
+        .sample("bitcoin_chain_tip_age_seconds", &[], now.saturating_sub(tip.header.timestamp) as f64 / 1000.0);
+    e.family("bitcoin_chain_blocks", "gauge", "Blocks known, forks included.")
+        .sample("bitcoin_chain_blocks", &[], blockchain.blocks.len());
+    e.family("bitcoin_chain_reorgs_total", "counter", "Times the longest chain moved to another branch.")
+        .sample("bitcoin_chain_reorgs_total", &[], blockchain.reorgs());
+    e.family("bitcoin_orphan_blocks", "gauge", "Blocks waiting for their parent.")
+        .sample("bitcoin_orphan_blocks", &[], sources.orphans);
+    e.family("bitcoin_mempool_transactions", "gauge", "Transactions in the mempool.")
+        .sample("bitcoin_mempool_transactions", &[], sources.mempool_size);
+
+    e.family("bitcoin_peers", "gauge", "Connected peers, by direction.");
+    for (direction, label) in [(Direction::Incoming, "incoming"), (Direction::Outgoing, "outgoing")].iter() {
+        let n = sources.peers.iter().filter(|p| p.direction == *direction).count();
+        e.sample("bitcoin_peers", &[("direction", label)], n);
+    }
+
+    let network = sources.network;
+    let by_command = |e: &mut Exposition, name: &str, sent: &BTreeMap<&str, u64>, received: &BTreeMap<&str, u64>| {
+        for (direction, counters) in [("sent", sent), ("received", received)].iter() {
+            for (command, n) in counters.iter() {
+                e.sample(name, &[("direction", direction), ("command", command)], n);
+            }
+        }
+    };
+    e.family("bitcoin_p2p_messages_total", "counter", "Messages exchanged with peers, by command.");
+    by_command(&mut e, "bitcoin_p2p_messages_total", &network.sent, &network.received);
+    e.family("bitcoin_p2p_bytes_total", "counter", "Bytes of the frames exchanged with peers, by command.");
+    by_command(&mut e, "bitcoin_p2p_bytes_total", &network.bytes_sent, &network.bytes_received);
+    e.family("bitcoin_p2p_suppressed_announcements_total", "counter", "Hashes not announced because the peer knew them.")
+        .sample("bitcoin_p2p_suppressed_announcements_total", &[], network.suppressed_announcements);
+    e.family("bitcoin_p2p_suppressed_requests_total", "counter", "Hashes not requested because they were in flight.")
+        .sample("bitcoin_p2p_suppressed_requests_total", &[], network.suppressed_requests);
+    e.family("bitcoin_blocks_rejected_total", "counter", "Blocks from peers that failed validation, by reason.");
+    for (reason, n) in network.rejected_blocks.iter() {
+        e.sample("bitcoin_blocks_rejected_total", &[("reason", reason)], n);
+    }
+
+    let miner = sources.miner;
+    e.family("bitcoin_miner_running", "gauge", "Whether the miner is running.")
+        .sample("bitcoin_miner_running", &[], (miner.state == State::Running) as u8);
+    e.family("bitcoin_miner_hash_rate", "gauge", "Nonces tried per second.")
+        .sample("bitcoin_miner_hash_rate", &[], miner.hash_rate);
+    e.family("bitcoin_miner_hashes_total", "counter", "Nonces tried.")
+        .sample("bitcoin_miner_hashes_total", &[], miner.hashes);
+    e.family("bitcoin_miner_blocks_mined_total", "counter", "Blocks mined by this node.")
+        .sample("bitcoin_miner_blocks_mined_total", &[], miner.blocks_mined);
+    e.into_string()
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::metrics::Metrics;
+    use crate::types::block::generate_random_block;
+
+    #[test]
+    fn exposition_format() {
+        let mut e = Exposition::default();
+        e.family("x_total", "counter", "Some x.")
+            .sample("x_total", &[("a", "1"), ("b", "say \"hi\"\n")], 3);
+        assert_eq!(
+            e.into_string(),
+            "# HELP x_total Some x.\n# TYPE x_total counter\nx_total{a=\"1\",b=\"say \\\"hi\\\"\\n\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn render_node_metrics() {
+        let mut blockchain = Blockchain::new();
+        blockchain.insert(&generate_random_block(&blockchain.tip()));
+        let metrics = Metrics::default();
+        metrics.record_sent(0, 40);
+        metrics.record_rejected_block("state_root");
+        let miner = MinerStatus { state: State::Running, lambda: Some(0), blocks_mined: 2, hashes: 10, hash_rate: 2.5 };
+        let text = render(&Sources {
+            blockchain: &blockchain,
+            mempool_size: 3,
+            orphans: 0,
+            peers: &[],
+            network: &metrics.snapshot(),
+            miner: &miner,
+        });
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"bitcoin_chain_height 1"));
+        assert!(lines.contains(&"bitcoin_mempool_transactions 3"));
+        assert!(lines.contains(&"bitcoin_peers{direction=\"outgoing\"} 0"));
+        assert!(lines.contains(&"bitcoin_blocks_rejected_total{reason=\"state_root\"} 1"));
+        assert!(lines.contains(&"bitcoin_miner_hash_rate 2.5"));
+        let command = crate::network::message::Message::command_name(0);
+        assert!(lines.contains(&format!("bitcoin_p2p_bytes_total{{direction=\"sent\",command=\"{}\"}} 40", command).as_str()));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,304 @@
+//! A JSON-RPC 2.0 interface with some of the methods of Bitcoin Core, so that tools written
+//! for it can drive our nodes. Requests are POSTed to `/rpc`, one at a time or in a batch.
+
+use super::{BlockResponse, decode_transaction, submit_transaction};
+use super::auth::Role;
+use crate::blockchain::Blockchain;
+use crate::blockchain::params::Params as ChainParams;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use crate::types::state::State;
+use crate::types::transaction::SignedTransaction;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Error codes of the JSON-RPC 2.0 specification
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// Error codes of Bitcoin Core
+pub const INVALID_ADDRESS_OR_KEY: i64 = -5;
+pub const INVALID_PARAMETER: i64 = -8;
+pub const DESERIALIZATION_ERROR: i64 = -22;
+pub const VERIFY_REJECTED: i64 = -26;
+// Server defined error codes
+pub const FORBIDDEN: i64 = -32001;
+
+/// Most requests in a batch
+pub const MAX_BATCH: usize = 100;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self { code, message: message.to_string() }
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+/// The parameters of a call, given by position or by name.
+enum Params {
+    Positional(Vec<Value>),
+    Named(Map<String, Value>),
+}
+
+impl Params {
+    fn get(&self, index: usize, name: &str) -> Option<&Value> {
+        let value = match self {
+            Params::Positional(v) => v.get(index),
+            Params::Named(m) => m.get(name),
+        };
+        value.filter(|v| !v.is_null())
+    }
+
+    fn str(&self, index: usize, name: &str) -> Result<&str, RpcError> {
+        match self.get(index, name) {
+            Some(Value::String(s)) => Ok(s),
+            Some(_) => Err(RpcError::new(INVALID_PARAMS, format!("{} must be a string", name))),
+            None => Err(RpcError::new(INVALID_PARAMS, format!("missing {}", name))),
+        }
+    }
+
+    fn u64(&self, index: usize, name: &str) -> Result<Option<u64>, RpcError> {
+        match self.get(index, name) {
+            Some(v) => match v.as_u64() {
+                Some(n) => Ok(Some(n)),
+                None => Err(RpcError::new(INVALID_PARAMS, format!("{} must be a non negative integer", name))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn hash(&self, index: usize, name: &str) -> Result<H256, RpcError> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(self.str(index, name)?, &mut bytes)
+            .map_err(|_| RpcError::new(INVALID_PARAMETER, format!("{} must be 32 hex encoded bytes", name)))?;
+        Ok(H256::from(bytes))
+    }
+
+    /// An address, with or without the prefix of the network.
+    fn address(&self, index: usize, name: &str, chain: &ChainParams) -> Result<Address, RpcError> {
+        chain
+            .parse_address(self.str(index, name)?)
+            .map_err(|e| RpcError::new(INVALID_ADDRESS_OR_KEY, format!("{}: {}", name, e)))
+    }
+}
+
+pub struct Rpc {
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub states: Arc<Mutex<HashMap<H256, State>>>,
+    pub mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    pub max_mempool: usize,
+    pub chain: &'static ChainParams,
+    /// The role of the caller, methods that change the node require the admin role
+    pub role: Role,
+}
+
+impl Rpc {
+    /// Answer a request or a batch of requests. `None` if there is nothing to answer, when every
+    /// request is a notification.
+    pub fn handle(&self, body: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(body) {
+            Ok(v) => v,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+        };
+        match request {
+            Value::Array(batch) if batch.is_empty() => {
+                Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "empty batch")))
+            }
+            Value::Array(batch) if batch.len() > MAX_BATCH => Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, format!("more than {} requests in the batch", MAX_BATCH)),
+            )),
+            Value::Array(batch) => {
+                let responses: Vec<Value> = batch.into_iter().filter_map(|r| self.call(r)).collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            request => self.call(request),
+        }
+    }
+
+    /// Answer a single request, unless it is a notification, without an id.
+    fn call(&self, request: Value) -> Option<Value> {
+        let mut request = match request {
+            Value::Object(m) => m,
+            _ => return Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "not an object"))),
+        };
+        let id = request.remove("id");
+        let id_is_valid = matches!(id, None | Some(Value::Null) | Some(Value::String(_)) | Some(Value::Number(_)));
+        let method = match (request.get("jsonrpc"), request.get("method")) {
+            (Some(Value::String(v)), Some(Value::String(m))) if v == "2.0" && id_is_valid => m.clone(),
+            _ => {
+                let id = id.filter(|_| id_is_valid).unwrap_or(Value::Null);
+                return Some(error_response(id, RpcError::new(INVALID_REQUEST, "invalid request")));
+            }
+        };
+        let params = match request.remove("params") {
+            None => Params::Positional(Vec::new()),
+            Some(Value::Array(v)) => Params::Positional(v),
+            Some(Value::Object(m)) => Params::Named(m),
+            Some(_) => {
+                let error = RpcError::new(INVALID_REQUEST, "params must be an array or an object");
+                return Some(error_response(id.unwrap_or(Value::Null), error));
+            }
+        };
+        let result = self.dispatch(&method, &params);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    fn dispatch(&self, method: &str, params: &Params) -> Result<Value, RpcError> {
+        match method {
+            // Height of the tip of the longest chain
+            "getblockcount" => {
+                let blockchain = self.blockchain.lock().unwrap();
+                Ok(json!(blockchain.blocks[&blockchain.tip()].height))
+            }
+            "getblockhash" => {
+                let height = params
+                    .u64(0, "height")?
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "missing height"))?;
+                let chain = self.blockchain.lock().unwrap().all_blocks_in_longest_chain();
+                match chain.get(height as usize) {
+                    Some(hash) => Ok(json!(hash.to_string())),
+                    None => Err(RpcError::new(INVALID_PARAMETER, "block height out of range")),
+                }
+            }
+            // With verbosity 0, the hex encoding of the serialized block
+            "getblock" => {
+                let hash = params.hash(0, "blockhash")?;
+                let verbosity = params.u64(1, "verbosity")?.unwrap_or(1);
+                let blockchain = self.blockchain.lock().unwrap();
+                let block = blockchain
+                    .blocks
+                    .get(&hash)
+                    .ok_or_else(|| RpcError::new(INVALID_ADDRESS_OR_KEY, "block not found"))?;
+                if verbosity == 0 {
+                    Ok(json!(hex::encode(bincode::serialize(block).unwrap())))
+                } else {
+                    Ok(json!(BlockResponse::new(&blockchain, block, self.chain)))
+                }
+            }
+            "getrawmempool" => {
+                let mempool = self.mempool.lock().unwrap();
+                Ok(json!(mempool.keys().map(|h| h.to_string()).collect::<Vec<String>>()))
+            }
+            // Accepts the hex encoding of a serialized transaction, like POST /transaction
+            "sendrawtransaction" => {
+                if self.role < Role::Admin {
+                    return Err(RpcError::new(FORBIDDEN, "sendrawtransaction requires the admin role"));
+                }
+                let tx = decode_transaction(params.str(0, "hexstring")?)
+                    .map_err(|e| RpcError::new(DESERIALIZATION_ERROR, e))?;
+                match submit_transaction(&self.network, &self.blockchain, &self.states, &self.mempool, self.max_mempool, tx) {
+                    Ok(hash) => Ok(json!(hash.to_string())),
+                    Err(reason) => Err(RpcError::new(VERIFY_REJECTED, reason)),
+                }
+            }
+            // Balance of an address in the state at the tip of the longest chain
+            "getbalance" => {
+                let address = params.address(0, "address", self.chain)?;
+                let tip = self.blockchain.lock().unwrap().tip();
+                let states = self.states.lock().unwrap();
+                let state = states
+                    .get(&tip)
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMETER, "state of the tip is unknown"))?;
+                Ok(json!(state.get(&address).map_or(0, |(_, balance)| *balance)))
+            }
+            "getpeerinfo" => Ok(json!(self.network.peers())),
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method not found: {}", method))),
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_rpc() -> Rpc {
+        let blockchain = Blockchain::new();
+        let mut states = HashMap::new();
+        let mut state = State::new();
+        state.insert(Address::from_bytes(&[1u8; 20]), (0, 42));
+        states.insert(blockchain.tip(), state);
+        let (network, _receiver) = NetworkServerHandle::new_for_test();
+        Rpc {
+            network,
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            states: Arc::new(Mutex::new(states)),
+            mempool: Arc::new(Mutex::new(HashMap::new())),
+            max_mempool: 10,
+            chain: &crate::blockchain::params::MAIN,
+            role: Role::Admin,
+        }
+    }
+
+    fn call(rpc: &Rpc, body: &str) -> Value {
+        rpc.handle(body).unwrap()
+    }
+
+    #[test]
+    fn methods() {
+        let rpc = test_rpc();
+        let genesis = rpc.blockchain.lock().unwrap().tip();
+        let r = call(&rpc, r#"{"jsonrpc":"2.0","method":"getblockcount","id":1}"#);
+        assert_eq!(r, json!({"jsonrpc": "2.0", "result": 0, "id": 1}));
+        let r = call(&rpc, r#"{"jsonrpc":"2.0","method":"getblockhash","params":[0],"id":"a"}"#);
+        assert_eq!(r["result"], json!(genesis.to_string()));
+        let r = call(&rpc, &format!(r#"{{"jsonrpc":"2.0","method":"getblock","params":{{"blockhash":"{}"}},"id":2}}"#, genesis));
+        assert_eq!(r["result"]["hash"], json!(genesis.to_string()));
+        let address = hex::encode([1u8; 20]);
+        let r = call(&rpc, &format!(r#"{{"jsonrpc":"2.0","method":"getbalance","params":["{}"],"id":3}}"#, address));
+        assert_eq!(r["result"], json!(42));
+        let r = call(&rpc, r#"{"jsonrpc":"2.0","method":"getblockhash","params":[1],"id":4}"#);
+        assert_eq!(r["error"]["code"], json!(INVALID_PARAMETER));
+        let r = call(&rpc, r#"{"jsonrpc":"2.0","method":"getblock","params":[null],"id":5}"#);
+        assert_eq!(r["error"]["code"], json!(INVALID_PARAMS));
+    }
+
+    #[test]
+    fn batches_and_errors() {
+        let rpc = test_rpc();
+        assert_eq!(call(&rpc, "{")["error"]["code"], json!(PARSE_ERROR));
+        assert_eq!(call(&rpc, "[]")["error"]["code"], json!(INVALID_REQUEST));
+        assert_eq!(call(&rpc, r#"{"method":"getblockcount","id":1}"#)["error"]["code"], json!(INVALID_REQUEST));
+        let r = call(&rpc, r#"{"jsonrpc":"2.0","method":"nope","id":1}"#);
+        assert_eq!(r["error"]["code"], json!(METHOD_NOT_FOUND));
+        // notifications are not answered
+        assert!(rpc.handle(r#"{"jsonrpc":"2.0","method":"getblockcount"}"#).is_none());
+        let r = call(&rpc, r#"[
+            {"jsonrpc":"2.0","method":"getblockcount","id":1},
+            {"jsonrpc":"2.0","method":"getrawmempool"},
+            1,
+            {"jsonrpc":"2.0","method":"sendrawtransaction","params":["zz"],"id":2}
+        ]"#);
+        let r = r.as_array().unwrap();
+        assert_eq!(r.len(), 3);
+        assert_eq!(r[0]["result"], json!(0));
+        assert_eq!(r[1]["error"]["code"], json!(INVALID_REQUEST));
+        assert_eq!(r[2]["error"]["code"], json!(DESERIALIZATION_ERROR));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
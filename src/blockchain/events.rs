@@ -0,0 +1,274 @@
+use super::params::Params;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::Hashable;
+use crate::types::transaction::SignedTransaction;
+use crossbeam::channel::{bounded, Receiver, Sender};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+/// Most events queued for a subscriber. A subscriber that falls further behind is dropped.
+pub const MAX_QUEUED_EVENTS: usize = 1000;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransactionInfo {
+    pub hash: String,
+    pub sender: String,
+    pub receiver: String,
+    pub value: u64,
+    pub nonce: u64,
+}
+
+impl TransactionInfo {
+    /// The transaction as the API shows it, with the addresses in the format of the network.
+    pub fn new(tx: &SignedTransaction, params: &Params) -> Self {
+        let mut info = Self::from(tx);
+        info.sender = params.format_address(&tx.t.sender);
+        info.receiver = params.format_address(&tx.t.receiver);
+        info
+    }
+}
+
+impl From<&SignedTransaction> for TransactionInfo {
+    fn from(tx: &SignedTransaction) -> Self {
+        Self {
+            hash: tx.hash().to_string(),
+            sender: tx.t.sender.to_string(),
+            receiver: tx.t.receiver.to_string(),
+            value: tx.t.value,
+            nonce: tx.t.acc_nonce,
+        }
+    }
+}
+
+/// The names of the types of events, as serialized
+pub const EVENT_TYPES: [&str; 4] = ["new_tip", "reorg", "mempool_added", "mempool_removed"];
+
+/// What happened to the longest chain or to the mempool.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A block became the tip of the longest chain
+    NewTip {
+        hash: String,
+        height: u32,
+        transactions: Vec<TransactionInfo>,
+    },
+    /// The longest chain moved to another branch. A `NewTip` follows for each block of the
+    /// new branch.
+    Reorg {
+        old_tip: String,
+        new_tip: String,
+        /// The last block the two branches have in common
+        fork: String,
+        /// Blocks that left the longest chain, from the old tip down
+        disconnected: Vec<String>,
+        /// Blocks that joined the longest chain, from the fork up
+        connected: Vec<String>,
+    },
+    /// A transaction entered the mempool, or came back to it because its block left the
+    /// longest chain
+    MempoolAdded { transaction: TransactionInfo },
+    /// A transaction left the mempool, because it was included in a block of the longest chain
+    MempoolRemoved { transaction: TransactionInfo },
+}
+
+impl Event {
+    pub fn new_tip(block: &Block) -> Self {
+        Event::NewTip {
+            hash: block.hash().to_string(),
+            height: block.height,
+            transactions: block.content.data.iter().map(TransactionInfo::from).collect(),
+        }
+    }
+
+    pub fn mempool_added(tx: &SignedTransaction) -> Self {
+        Event::MempoolAdded { transaction: TransactionInfo::from(tx) }
+    }
+
+    pub fn mempool_removed(tx: &SignedTransaction) -> Self {
+        Event::MempoolRemoved { transaction: TransactionInfo::from(tx) }
+    }
+
+    /// The type of the event, as serialized.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::NewTip { .. } => "new_tip",
+            Event::Reorg { .. } => "reorg",
+            Event::MempoolAdded { .. } => "mempool_added",
+            Event::MempoolRemoved { .. } => "mempool_removed",
+        }
+    }
+
+    /// The event as the API shows it, with the addresses of its transactions in the format of
+    /// the network. Published events have plain hex addresses.
+    pub fn format_addresses(mut self, params: &Params) -> Self {
+        let format = |tx: &mut TransactionInfo| {
+            tx.sender.insert_str(0, params.address_prefix);
+            tx.receiver.insert_str(0, params.address_prefix);
+        };
+        match &mut self {
+            Event::NewTip { transactions, .. } => transactions.iter_mut().for_each(format),
+            Event::Reorg { .. } => {}
+            Event::MempoolAdded { transaction } | Event::MempoolRemoved { transaction } => format(transaction),
+        }
+        self
+    }
+
+    /// Whether a transaction of the event is sent or received by the address. Reorganizations
+    /// concern every address.
+    pub fn involves(&self, address: &Address) -> bool {
+        let address = address.to_string();
+        let involved = |tx: &TransactionInfo| tx.sender == address || tx.receiver == address;
+        match self {
+            Event::NewTip { transactions, .. } => transactions.iter().any(involved),
+            Event::Reorg { .. } => true,
+            Event::MempoolAdded { transaction } | Event::MempoolRemoved { transaction } => involved(transaction),
+        }
+    }
+}
+
+/// Where events are published, for the API to stream them to its clients.
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl Events {
+    /// Receive the events published from now on, until the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = bounded(MAX_QUEUED_EVENTS);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        // drop the subscribers that are gone or too far behind
+        self.subscribers.lock().unwrap().retain(|s| s.try_send(event.clone()).is_ok());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::types::hash::H256;
+    use std::collections::HashMap;
+    use crate::types::address::Address;
+    use crate::types::block::generate_random_block;
+    use crate::types::key_pair;
+    use crate::types::state::State;
+    use crate::types::transaction::{generate_random_transaction, sign, SignedTransaction, Transaction};
+    use ring::digest;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn tip_changes_and_reorgs() {
+        let mut blockchain = Blockchain::new();
+        let events = blockchain.events().subscribe();
+        let genesis = blockchain.tip();
+        let a1 = generate_random_block(&genesis);
+        let b1 = generate_random_block(&genesis);
+        let b2 = generate_random_block(&b1.hash());
+        blockchain.insert(&a1);
+        // a fork as long as the longest chain does not change the tip
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+
+        assert_eq!(blockchain.reorgs(), 1);
+
+        let received: Vec<Event> = events.try_iter().collect();
+        let names: Vec<&str> = received.iter().map(|e| e.name()).collect();
+        assert_eq!(names, vec!["new_tip", "reorg", "new_tip", "new_tip"]);
+        assert_eq!(received[1], Event::Reorg {
+            old_tip: a1.hash().to_string(),
+            new_tip: b2.hash().to_string(),
+            fork: genesis.to_string(),
+            disconnected: vec![a1.hash().to_string()],
+            connected: vec![b1.hash().to_string(), b2.hash().to_string()],
+        });
+        match &received[3] {
+            Event::NewTip { hash, height, .. } => assert_eq!((hash, *height), (&b2.hash().to_string(), 2)),
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    /// A transaction with this nonce, signed by the key of its sender, and the sender.
+    fn signed(key: &Ed25519KeyPair, nonce: u64) -> (SignedTransaction, Address) {
+        let h = digest::digest(&digest::SHA256, key.public_key().as_ref());
+        let sender = Address::from_public_key_bytes(&hex::encode(h).into_bytes());
+        let t = Transaction::new(sender, Address::default(), 1, nonce);
+        let sig = sign(&t, key).as_ref().to_vec();
+        (SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec()), sender)
+    }
+
+    #[test]
+    fn mempool_follows_the_longest_chain() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let keys: Vec<Ed25519KeyPair> = (0..4).map(|_| key_pair::random()).collect();
+        let (kept, _) = signed(&keys[0], 1);
+        let (moved, moved_sender) = signed(&keys[1], 1);
+        let (dropped, dropped_sender) = signed(&keys[2], 1);
+        let (stale, stale_sender) = signed(&keys[3], 1);
+        let mut mempool: HashMap<H256, SignedTransaction> =
+            [&kept, &moved, &dropped].iter().map(|tx| (tx.hash(), (*tx).clone())).collect();
+        let events = blockchain.events().subscribe();
+
+        let mut a1 = generate_random_block(&genesis);
+        a1.content.data = vec![moved.clone(), dropped.clone(), stale.clone()];
+        blockchain.insert_with_mempool(&a1, &State::new(), &mut mempool);
+        assert_eq!(mempool.keys().collect::<Vec<_>>(), vec![&kept.hash()]);
+
+        // the fork confirms one of the transactions of the block it disconnects, and another
+        // transaction with the nonce of a third one
+        let mut b1 = generate_random_block(&genesis);
+        b1.content.data = vec![moved.clone()];
+        let b2 = generate_random_block(&b1.hash());
+        let state: State = [(moved_sender, (1, 9)), (dropped_sender, (0, 10)), (stale_sender, (1, 9))]
+            .iter()
+            .cloned()
+            .collect();
+        blockchain.insert_with_mempool(&b1, &state, &mut mempool);
+        blockchain.insert_with_mempool(&b2, &state, &mut mempool);
+        let mut expected = vec![kept.hash(), dropped.hash()];
+        expected.sort();
+        let mut left: Vec<H256> = mempool.keys().cloned().collect();
+        left.sort();
+        assert_eq!(left, expected);
+
+        let mempool_events: Vec<Event> = events
+            .try_iter()
+            .filter(|e| matches!(e, Event::MempoolAdded { .. } | Event::MempoolRemoved { .. }))
+            .collect();
+        let removed = |tx: &SignedTransaction| Event::mempool_removed(tx);
+        assert_eq!(mempool_events.len(), 4);
+        assert!(mempool_events[..2].contains(&removed(&moved)) && mempool_events[..2].contains(&removed(&dropped)));
+        assert_eq!(mempool_events[2], Event::mempool_added(&dropped));
+        assert_eq!(mempool_events[3], removed(&stale));
+    }
+
+    #[test]
+    fn filter_by_address() {
+        let tx = SignedTransaction::new(generate_random_transaction(), vec![], vec![]);
+        let event = Event::mempool_added(&tx);
+        assert!(event.involves(&tx.t.sender));
+        assert!(event.involves(&tx.t.receiver));
+        assert!(!event.involves(&Address::from_bytes(&[7u8; 20])));
+        let serialized = serde_json::to_value(&event).unwrap();
+        assert_eq!(serialized["type"], "mempool_added");
+        assert_eq!(serialized["transaction"]["hash"], tx.hash().to_string());
+
+        let regtest = &crate::blockchain::params::REGTEST;
+        let formatted = event.format_addresses(regtest);
+        assert_eq!(formatted, Event::MempoolAdded { transaction: TransactionInfo::new(&tx, regtest) });
+        match formatted {
+            Event::MempoolAdded { transaction } => assert_eq!(transaction.sender, regtest.format_address(&tx.t.sender)),
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
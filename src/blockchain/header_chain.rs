@@ -0,0 +1,216 @@
+use crate::types::block::Header;
+use crate::types::hash::{H256, Hashable};
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The parent of the header is not in the chain
+    UnknownParent,
+    /// The hash of the header is above the difficulty of its parent
+    BadProofOfWork,
+    /// The header changes the difficulty of its parent
+    BadDifficulty,
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::UnknownParent => write!(f, "unknown parent"),
+            HeaderError::BadProofOfWork => write!(f, "hash above the difficulty"),
+            HeaderError::BadDifficulty => write!(f, "difficulty differs from the parent"),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// The headers of a blockchain without the transactions, as followed by a light client. Headers
+/// are checked like blocks are by a full node, except for their content.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    /// Every header with its height
+    headers: HashMap<H256, (Header, u32)>,
+    tip: H256,
+}
+
+impl HeaderChain {
+    pub fn new(genesis: &Header) -> Self {
+        let hash = genesis.hash();
+        let mut headers = HashMap::new();
+        headers.insert(hash, (*genesis, 0));
+        Self { headers, tip: hash }
+    }
+
+    /// Insert a header on top of a known one. Returns false if it was already in the chain.
+    pub fn insert(&mut self, header: &Header) -> Result<bool, HeaderError> {
+        let hash = header.hash();
+        if self.headers.contains_key(&hash) {
+            return Ok(false);
+        }
+        let (parent, parent_height) = match self.headers.get(&header.parent) {
+            Some(p) => p,
+            None => return Err(HeaderError::UnknownParent),
+        };
+        if header.difficulty != parent.difficulty {
+            return Err(HeaderError::BadDifficulty);
+        }
+        if hash > parent.difficulty {
+            return Err(HeaderError::BadProofOfWork);
+        }
+        let height = parent_height + 1;
+        self.headers.insert(hash, (*header, height));
+        if height > self.height() {
+            self.tip = hash;
+        }
+        Ok(true)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Header> {
+        self.headers.get(hash).map(|(h, _)| h)
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Get the last header's hash of the longest chain
+    pub fn tip(&self) -> H256 {
+        self.tip
+    }
+
+    /// Height of the tip, the genesis being at height 0
+    pub fn height(&self) -> u32 {
+        self.headers[&self.tip].1
+    }
+
+    /// Get all headers' hashes of the longest chain, ordered from genesis to the tip
+    pub fn longest_chain(&self) -> Vec<H256> {
+        let mut chain = Vec::new();
+        let mut cur = self.tip;
+        loop {
+            chain.push(cur);
+            let (header, height) = &self.headers[&cur];
+            if *height == 0 {
+                break;
+            }
+            cur = header.parent;
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Number of headers of the longest chain from this one to the tip, both included. `None` if
+    /// the header is not in the longest chain.
+    pub fn confirmations(&self, hash: &H256) -> Option<u32> {
+        let (_, height) = self.headers.get(hash)?;
+        let mut cur = self.tip;
+        while self.headers[&cur].1 > *height {
+            cur = self.headers[&cur].0.parent;
+        }
+        if cur == *hash {
+            Some(self.height() - height + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Hashes of the longest chain that tell a peer where our chain forks from theirs: the last
+    /// ten headers, then exponentially further apart down to genesis.
+    pub fn locator(&self) -> Vec<H256> {
+        let chain = self.longest_chain();
+        let mut locator = Vec::new();
+        let mut step = 1;
+        let mut i = chain.len() - 1;
+        while i > 0 {
+            locator.push(chain[i]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            i = i.saturating_sub(step);
+        }
+        locator.push(chain[0]);
+        locator
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    /// A genesis header any hash satisfies, so that random headers are valid.
+    fn easy_genesis() -> Header {
+        let mut header = generate_random_block(&H256::default()).header;
+        header.difficulty = [255u8; 32].into();
+        header
+    }
+
+    fn child(parent: &Header) -> Header {
+        let mut header = generate_random_block(&parent.hash()).header;
+        header.difficulty = parent.difficulty;
+        header
+    }
+
+    #[test]
+    fn follow_longest_chain() {
+        let genesis = easy_genesis();
+        let mut chain = HeaderChain::new(&genesis);
+        let a1 = child(&genesis);
+        let a2 = child(&a1);
+        let b1 = child(&genesis);
+        assert_eq!(chain.insert(&a1), Ok(true));
+        assert_eq!(chain.insert(&a1), Ok(false));
+        assert_eq!(chain.insert(&a2), Ok(true));
+        assert_eq!(chain.insert(&b1), Ok(true));
+        assert_eq!(chain.tip(), a2.hash());
+        assert_eq!(chain.longest_chain(), vec![genesis.hash(), a1.hash(), a2.hash()]);
+        assert_eq!(chain.confirmations(&a1.hash()), Some(2));
+        assert_eq!(chain.confirmations(&b1.hash()), None);
+
+        let b2 = child(&b1);
+        let b3 = child(&b2);
+        chain.insert(&b2).unwrap();
+        chain.insert(&b3).unwrap();
+        assert_eq!(chain.tip(), b3.hash());
+        assert_eq!(chain.confirmations(&a1.hash()), None);
+        assert_eq!(chain.confirmations(&b1.hash()), Some(3));
+    }
+
+    #[test]
+    fn reject_invalid_headers() {
+        let genesis = easy_genesis();
+        let mut chain = HeaderChain::new(&genesis);
+        let orphan = child(&child(&genesis));
+        assert_eq!(chain.insert(&orphan), Err(HeaderError::UnknownParent));
+
+        let mut changed = child(&genesis);
+        changed.difficulty = H256::default();
+        assert_eq!(chain.insert(&changed), Err(HeaderError::BadDifficulty));
+
+        let mut hard = HeaderChain::new(&Header { difficulty: H256::default(), ..genesis });
+        let mut header = child(&genesis);
+        header.parent = hard.tip();
+        header.difficulty = H256::default();
+        assert_eq!(hard.insert(&header), Err(HeaderError::BadProofOfWork));
+    }
+
+    #[test]
+    fn locator_ends_at_genesis() {
+        let genesis = easy_genesis();
+        let mut chain = HeaderChain::new(&genesis);
+        let mut parent = genesis;
+        for _ in 0..40 {
+            let header = child(&parent);
+            chain.insert(&header).unwrap();
+            parent = header;
+        }
+        let locator = chain.locator();
+        assert_eq!(locator[0], chain.tip());
+        assert_eq!(*locator.last().unwrap(), genesis.hash());
+        assert!(locator.len() < 20);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,60 +1,64 @@
+pub mod events;
+pub mod header_chain;
+pub mod params;
+pub mod stats;
+pub mod store;
+pub mod tx_index;
+
 use crate::types::block::{Block, Header, Content};
 use crate::types::hash::{H256, Hashable};
-use std::collections::HashMap;
+use crate::types::address::Address;
+use crate::types::state::State;
+use crate::types::transaction::{check_for_mempool, SignedTransaction};
+use events::{Event, Events};
+use tx_index::TxIndex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 use ring::{digest};
-use rand::Rng;
 
 //Arc<Mutex<Blockchain>>
 
 #[derive( Debug, Clone)]
 pub struct Blockchain {
     pub blocks: HashMap<H256, Block>,
-    pub tip: H256
+    pub tip: H256,
+    genesis: H256,
+    /// Where the transactions of the longest chain are, if enabled
+    index: Option<TxIndex>,
+    /// Changes of the longest chain and of the mempool, for the API to stream
+    events: Events,
+    /// Times the longest chain moved to another branch
+    reorgs: u64,
 }
 
+/// Difficulty of the default genesis block, which every block inherits
+pub const DEFAULT_DIFFICULTY: [u8; 32] = [0u8,0u8,255u8,255u8,1u8,1u8,0u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8];
+
 impl Blockchain {
     pub fn get_genesis_block_hash(&self) -> H256 {
-        let mut ctx = digest::Context::new(&digest::SHA256);
-        ctx.update("genesis".as_ref());
-        let rand_hash = H256::from(ctx.finish());
-
-        // Rand Nonce
-        let mut rng = rand::thread_rng();
-        let rand_nonce: u32 = rng.gen();   // Generate random value for nonce
-
-        let difficulty:[u8; 32] = [0u8,0u8,255u8,255u8,1u8,1u8,0u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8];
-        let header = Header {
-            parent: rand_hash,
-            nonce: 0u32,
-            difficulty: difficulty.into(), // TODO: Set appropriate difficulty
-            timestamp: 0,//SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis(),
-            merkle_root: rand_hash
-        };
-        
-        let block = Block{header: header, content: Content {data: Vec::new()}, height: 0};
-        return block.hash();
+        self.genesis
     }
 
     /// Create a new blockchain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_genesis(DEFAULT_DIFFICULTY.into(), 0)
+    }
+
+    /// Create a new blockchain, only containing a genesis block of this difficulty and timestamp
+    pub fn with_genesis(difficulty: H256, timestamp: u128) -> Self {
         let mut ctx = digest::Context::new(&digest::SHA256);
         ctx.update("genesis".as_ref());
         let rand_hash = H256::from(ctx.finish());
 
-        // Rand Nonce
-        let mut rng = rand::thread_rng();
-        let rand_nonce: u32 = rng.gen();   // Generate random value for nonce
-
-        // let first_part_difficulty: [u8; 16] = [0u8; 16];
-        // let second_part_difficulty: [u8; 16] = [1u8; 16]; 
-        let difficulty:[u8; 32] = [0u8,0u8,255u8,255u8,1u8,1u8,0u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8];
         let header = Header {
             parent: rand_hash,
             nonce: 0u32,
-            difficulty: difficulty.into(), // TODO: Set appropriate difficulty
-            timestamp: 0,//SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis(),
-            merkle_root: rand_hash
+            difficulty,
+            timestamp,
+            merkle_root: rand_hash,
+            // the genesis allocation is chosen by each node at start-up, so it is not committed
+            state_root: rand_hash
         };
          
 
@@ -66,12 +70,33 @@ impl Blockchain {
         
         Self {
             blocks: blocks,
-            tip: block_hash
+            tip: block_hash,
+            genesis: block_hash,
+            index: None,
+            events: Events::default(),
+            reorgs: 0,
         }
     }
 
     /// Insert a block into blockchain
     pub fn insert(&mut self, block: &Block) {
+        self.connect(block);
+    }
+
+    /// Insert a block, and keep the mempool in line with the longest chain: the transactions of
+    /// the blocks that joined it leave the mempool, and those of the blocks that left it come
+    /// back, unless the new branch has them too or they are no longer valid. `state` is the
+    /// state after `block`, the only block that can become the tip.
+    pub fn insert_with_mempool(&mut self, block: &Block, state: &State, mempool: &mut HashMap<H256, SignedTransaction>) {
+        if let Some((disconnected, connected)) = self.connect(block) {
+            self.update_mempool(&disconnected, &connected, state, mempool);
+        }
+    }
+
+    /// Insert a block, returning the blocks that left and joined the longest chain if the tip
+    /// moved, see `branch_change`.
+    fn connect(&mut self, block: &Block) -> Option<(Vec<H256>, Vec<H256>)> {
+        let mut change = None;
         let mut b = block.clone();
         if self.blocks.contains_key(&block.header.parent) {
             b.height = self.blocks[&block.header.parent].height + 1;
@@ -80,12 +105,21 @@ impl Blockchain {
 
             self.blocks.insert(b_hash, b);
             if b_height > self.blocks[&self.tip].height {
+                let old_tip = self.tip;
                 self.tip = b_hash;
+                let (disconnected, connected) = self.branch_change(old_tip);
+                if !disconnected.is_empty() {
+                    self.reorgs += 1;
+                }
+                self.update_index(&disconnected, &connected);
+                self.publish_tip_change(old_tip, &disconnected, &connected);
                 println!("New longest chain, block added as tip: {:?}", b_hash);
+                change = Some((disconnected, connected));
             }
 
             println!("Inserting block with parent: {:?}", block.header.parent);
         }
+        change
 
         // let mut b = block.clone();
         // // Do we want to do something like 
@@ -109,6 +143,172 @@ impl Blockchain {
         // }
     }
 
+    /// Index the transactions of the longest chain, by hash and by address, from now on.
+    pub fn enable_tx_index(&mut self) {
+        let mut index = TxIndex::default();
+        for hash in self.all_blocks_in_longest_chain() {
+            index.add_block(&self.blocks[&hash]);
+        }
+        self.index = Some(index);
+    }
+
+    /// The blocks that left the longest chain when the tip moved from `old_tip`, from the old
+    /// tip down to the fork, and the blocks that joined it, from the fork up to the new tip.
+    fn branch_change(&self, old_tip: H256) -> (Vec<H256>, Vec<H256>) {
+        let parent = |hash: H256| self.blocks[&hash].header.parent;
+        let mut old = old_tip;
+        let mut new = self.tip;
+        let mut disconnected = Vec::new();
+        let mut connected = Vec::new();
+        while self.blocks[&new].height > self.blocks[&old].height {
+            connected.push(new);
+            new = parent(new);
+        }
+        while old != new {
+            disconnected.push(old);
+            old = parent(old);
+            connected.push(new);
+            new = parent(new);
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    /// Move the index from the old branch to the new one.
+    fn update_index(&mut self, disconnected: &[H256], connected: &[H256]) {
+        let index = match self.index.as_mut() {
+            Some(i) => i,
+            None => return,
+        };
+        for hash in disconnected {
+            index.remove_block(&self.blocks[hash]);
+        }
+        for hash in connected {
+            index.add_block(&self.blocks[hash]);
+        }
+    }
+
+    fn update_mempool(
+        &self,
+        disconnected: &[H256],
+        connected: &[H256],
+        state: &State,
+        mempool: &mut HashMap<H256, SignedTransaction>,
+    ) {
+        let confirmed: HashSet<H256> = connected
+            .iter()
+            .flat_map(|hash| self.blocks[hash].content.data.iter().map(|tx| tx.hash()))
+            .collect();
+        for hash in disconnected {
+            for tx in self.blocks[hash].content.data.iter() {
+                let tx_hash = tx.hash();
+                if confirmed.contains(&tx_hash) || mempool.contains_key(&tx_hash) {
+                    continue;
+                }
+                // double spends and used nonces of the losing branch are dropped
+                if check_for_mempool(tx, state).is_ok() {
+                    mempool.insert(tx_hash, tx.clone());
+                    self.events.publish(Event::mempool_added(tx));
+                } else {
+                    self.events.publish(Event::mempool_removed(tx));
+                }
+            }
+        }
+        for tx_hash in confirmed.iter() {
+            if let Some(tx) = mempool.remove(tx_hash) {
+                self.events.publish(Event::mempool_removed(&tx));
+            }
+        }
+    }
+
+    fn publish_tip_change(&self, old_tip: H256, disconnected: &[H256], connected: &[H256]) {
+        if !disconnected.is_empty() {
+            self.events.publish(Event::Reorg {
+                old_tip: old_tip.to_string(),
+                new_tip: self.tip.to_string(),
+                fork: self.blocks[&connected[0]].header.parent.to_string(),
+                disconnected: disconnected.iter().map(|h| h.to_string()).collect(),
+                connected: connected.iter().map(|h| h.to_string()).collect(),
+            });
+        }
+        for hash in connected {
+            self.events.publish(Event::new_tip(&self.blocks[hash]));
+        }
+    }
+
+    /// Times the longest chain moved to another branch.
+    pub fn reorgs(&self) -> u64 {
+        self.reorgs
+    }
+
+    /// Where changes of the longest chain are published. The mempool, which has no owner of
+    /// its own, publishes its changes here too.
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// A transaction of the longest chain: its block and its position in the block. Without
+    /// the index, the longest chain is scanned.
+    pub fn find_transaction(&self, tx_hash: &H256) -> Option<(&Block, usize)> {
+        if let Some(index) = &self.index {
+            return index.transaction(tx_hash).map(|(block, position)| (&self.blocks[&block], position));
+        }
+        self.all_blocks_in_longest_chain().iter().rev().find_map(|hash| {
+            let block = &self.blocks[hash];
+            block.content.data.iter().position(|tx| tx.hash() == *tx_hash).map(|position| (block, position))
+        })
+    }
+
+    /// The transactions of the longest chain among `tx_hashes`, in the same order, skipping the
+    /// others. Without the index, the longest chain is scanned once for all of them.
+    pub fn find_transactions(&self, tx_hashes: &[H256]) -> Vec<(&Block, usize)> {
+        if self.index.is_some() {
+            return tx_hashes.iter().filter_map(|h| self.find_transaction(h)).collect();
+        }
+        let mut wanted: HashMap<H256, Option<(&Block, usize)>> = tx_hashes.iter().map(|h| (*h, None)).collect();
+        for hash in self.all_blocks_in_longest_chain() {
+            let block = &self.blocks[&hash];
+            for (position, tx) in block.content.data.iter().enumerate() {
+                if let Some(found) = wanted.get_mut(&tx.hash()) {
+                    *found = Some((block, position));
+                }
+            }
+        }
+        tx_hashes.iter().filter_map(|h| wanted[h]).collect()
+    }
+
+    /// The transactions of the longest chain sent or received by an address, from the oldest,
+    /// or `None` if the index is disabled.
+    pub fn address_transactions(&self, address: &Address) -> Option<Vec<H256>> {
+        self.index.as_ref().map(|index| index.address(address).to_vec())
+    }
+
+    /// Number of blocks of the longest chain from this one to the tip, both included. `None` if
+    /// the block is not in the longest chain.
+    pub fn confirmations(&self, hash: &H256) -> Option<u32> {
+        let height = self.blocks.get(hash)?.height;
+        let mut cur = self.tip;
+        while self.blocks[&cur].height > height {
+            cur = self.blocks[&cur].header.parent;
+        }
+        if cur == *hash {
+            Some(self.blocks[&self.tip].height - height + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Number of transactions in the blocks of the longest chain
+    pub fn longest_chain_tx_count(&self) -> usize {
+        self.all_blocks_in_longest_chain().iter().map(|h| self.blocks[h].content.data.len()).sum()
+    }
+
+    /// Hashes of the blocks without children: the tip of the longest chain and of every fork.
+    pub fn tips(&self) -> Vec<H256> {
+        let parents: HashSet<H256> = self.blocks.values().map(|b| b.header.parent).collect();
+        self.blocks.keys().filter(|h| !parents.contains(h)).copied().collect()
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         return self.tip;
@@ -135,6 +335,16 @@ impl Blockchain {
     }
 }
 
+/// The chain, the states after its blocks, the blocks whose parent is missing and the mempool,
+/// shared by the threads of a full node.
+#[derive(Clone)]
+pub struct ChainState {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub states: Arc<Mutex<HashMap<H256, State>>>,
+    pub orphans: Arc<Mutex<HashMap<H256, Vec<Block>>>>,
+    pub mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -152,6 +362,26 @@ mod tests {
         assert_eq!(blockchain.tip(), block.hash());
     }
 
+    #[test]
+    fn tips_of_forks() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let block_1 = generate_random_block(&genesis_hash);
+        let block_2 = generate_random_block(&block_1.hash());
+        let fork_block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert(&block_1);
+        blockchain.insert(&block_2);
+        blockchain.insert(&fork_block_1);
+        let mut tips = blockchain.tips();
+        tips.sort();
+        let mut expected = vec![block_2.hash(), fork_block_1.hash()];
+        expected.sort();
+        assert_eq!(tips, expected);
+        assert_eq!(blockchain.confirmations(&block_1.hash()), Some(2));
+        assert_eq!(blockchain.confirmations(&fork_block_1.hash()), None);
+        assert_eq!(blockchain.reorgs(), 0);
+    }
+
     use ntest::timeout;
     use super::*;
     #[test]
@@ -0,0 +1,165 @@
+//! The networks a node can join. Each has its own genesis, so that their chains never mix, and
+//! its own magic, so that their nodes drop each other at the first frame.
+
+use crate::types::address::Address;
+use serde::Deserialize;
+use std::str::FromStr;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Main,
+    Test,
+    /// A local network, where blocks are mined at once, for tests
+    Regtest,
+}
+
+/// What differs between networks.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Params {
+    pub network: Network,
+    /// At the start of every P2P frame
+    pub magic: [u8; 4],
+    pub p2p_port: u16,
+    pub api_port: u16,
+    /// Addresses given to the API may start with it, addresses of other networks are refused
+    pub address_prefix: &'static str,
+    /// Of the genesis block, which every block inherits
+    pub difficulty: [u8; 32],
+    /// Of the genesis block, telling the genesis of each network apart
+    pub genesis_timestamp: u64,
+    /// Coins each node gives itself in the genesis state
+    pub ico_amount: u64,
+}
+
+pub const MAIN: Params = Params {
+    network: Network::Main,
+    magic: [0xf9, 0xbe, 0xb4, 0xd9],
+    p2p_port: 6000,
+    api_port: 7000,
+    address_prefix: "bc1",
+    difficulty: super::DEFAULT_DIFFICULTY,
+    genesis_timestamp: 0,
+    ico_amount: 50,
+};
+
+/// About 16 times easier to mine than the main network.
+pub const TEST: Params = Params {
+    network: Network::Test,
+    magic: [0x0b, 0x11, 0x09, 0x07],
+    p2p_port: 16000,
+    api_port: 17000,
+    address_prefix: "tb1",
+    difficulty: [0, 15, 255, 255, 1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    genesis_timestamp: 1,
+    ico_amount: 50,
+};
+
+/// Every hash meets the difficulty, so the first nonce tried mines a block.
+pub const REGTEST: Params = Params {
+    network: Network::Regtest,
+    magic: [0xfa, 0xbf, 0xb5, 0xda],
+    p2p_port: 26000,
+    api_port: 27000,
+    address_prefix: "bcrt1",
+    difficulty: [255; 32],
+    genesis_timestamp: 2,
+    ico_amount: 1000,
+};
+
+// deriving it needs `#[default]` on the variant, which only Rust 1.62 and later accept
+#[allow(clippy::derivable_impls)]
+impl Default for Network {
+    fn default() -> Self {
+        Network::Main
+    }
+}
+
+impl Network {
+    pub fn params(self) -> &'static Params {
+        match self {
+            Network::Main => &MAIN,
+            Network::Test => &TEST,
+            Network::Regtest => &REGTEST,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {}, expected main, test or regtest", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Network::Main => write!(f, "main"),
+            Network::Test => write!(f, "test"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+impl Params {
+    /// The address with the prefix of the network.
+    pub fn format_address(&self, address: &Address) -> String {
+        format!("{}{}", self.address_prefix, address)
+    }
+
+    /// An address as 20 hex encoded bytes, with or without the prefix of the network.
+    pub fn parse_address(&self, s: &str) -> Result<Address, String> {
+        let hex_part = s.strip_prefix(self.address_prefix).unwrap_or(s);
+        match hex::decode(hex_part) {
+            Ok(v) if v.len() == 20 => Ok(Address::from_bytes(&v)),
+            _ => Err(format!("address must be 20 hex encoded bytes, optionally prefixed with {}", self.address_prefix)),
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn addresses_of_a_network() {
+        let address = Address::from_bytes(&[0xab; 20]);
+        let formatted = REGTEST.format_address(&address);
+        assert_eq!(formatted, format!("bcrt1{}", "ab".repeat(20)));
+        assert_eq!(REGTEST.parse_address(&formatted), Ok(address));
+        assert_eq!(REGTEST.parse_address(&"ab".repeat(20)), Ok(address));
+        assert!(MAIN.parse_address(&formatted).is_err());
+        assert_eq!(TEST.parse_address(&TEST.format_address(&address)), Ok(address));
+        assert_eq!(
+            MAIN.parse_address("abab"),
+            Err("address must be 20 hex encoded bytes, optionally prefixed with bc1".to_string())
+        );
+    }
+
+    #[test]
+    fn networks_differ() {
+        let all = [Network::Main, Network::Test, Network::Regtest];
+        for (i, a) in all.iter().enumerate() {
+            assert_eq!(a.to_string().parse::<Network>(), Ok(*a));
+            for b in all[i + 1..].iter() {
+                let (a, b) = (a.params(), b.params());
+                assert_ne!(a.magic, b.magic);
+                assert_ne!(a.p2p_port, b.p2p_port);
+                assert_ne!(a.genesis_timestamp, b.genesis_timestamp);
+            }
+        }
+        assert_eq!(MAIN.magic, crate::network::frame::MAGIC);
+        assert!("regnet".parse::<Network>().is_err());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
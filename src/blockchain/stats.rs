@@ -0,0 +1,150 @@
+use super::Blockchain;
+use crate::types::hash::H256;
+use serde::Serialize;
+
+/// Most recent blocks of the longest chain the network hash rate is estimated over
+pub const HASH_RATE_WINDOW: usize = 120;
+
+/// Seconds between consecutive blocks of the longest chain, from their timestamps.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BlockIntervals {
+    pub average: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub height: u32,
+    /// Blocks known, forks included
+    pub blocks: usize,
+    /// Transactions of the longest chain
+    pub transactions: usize,
+    pub transactions_per_block: f64,
+    /// `None` until the longest chain has two blocks after the genesis
+    pub block_interval: Option<BlockIntervals>,
+    /// Blocks off the longest chain, sometimes called orphans
+    pub stale_blocks: usize,
+    /// Share of the blocks after the genesis that are off the longest chain
+    pub stale_rate: f64,
+    /// Hashes per second the recent blocks of the longest chain took, from their difficulty
+    /// and their timestamps
+    pub network_hash_rate: Option<f64>,
+}
+
+/// Hashes expected to find a block: 2^256 over the number of hashes at or below the difficulty.
+pub fn expected_hashes(difficulty: &H256) -> f64 {
+    let target = difficulty.as_ref().iter().fold(0.0, |acc, b| acc * 256.0 + *b as f64);
+    2f64.powi(256) / (target + 1.0)
+}
+
+/// The nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl ChainStats {
+    pub fn new(blockchain: &Blockchain) -> Self {
+        let chain = blockchain.all_blocks_in_longest_chain();
+        // the genesis has no transactions, and no meaningful timestamp
+        let mined: Vec<_> = chain.iter().skip(1).map(|h| &blockchain.blocks[h]).collect();
+        let transactions = blockchain.longest_chain_tx_count();
+
+        let mut intervals: Vec<f64> = mined
+            .windows(2)
+            .map(|w| (w[1].header.timestamp as f64 - w[0].header.timestamp as f64) / 1000.0)
+            .collect();
+        let block_interval = if intervals.is_empty() {
+            None
+        } else {
+            let average = intervals.iter().sum::<f64>() / intervals.len() as f64;
+            intervals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            Some(BlockIntervals {
+                average,
+                p50: percentile(&intervals, 50.0),
+                p90: percentile(&intervals, 90.0),
+                p99: percentile(&intervals, 99.0),
+            })
+        };
+
+        let recent = &mined[mined.len().saturating_sub(HASH_RATE_WINDOW)..];
+        let network_hash_rate = match (recent.first(), recent.last()) {
+            (Some(first), Some(last)) if last.header.timestamp > first.header.timestamp => {
+                // the work of the first block was done before its timestamp
+                let work: f64 = recent[1..].iter().map(|b| expected_hashes(&b.header.difficulty)).sum();
+                Some(work / ((last.header.timestamp - first.header.timestamp) as f64 / 1000.0))
+            }
+            _ => None,
+        };
+
+        let stale_blocks = blockchain.blocks.len() - chain.len();
+        let after_genesis = blockchain.blocks.len() - 1;
+        Self {
+            height: blockchain.blocks[&blockchain.tip()].height,
+            blocks: blockchain.blocks.len(),
+            transactions,
+            transactions_per_block: if mined.is_empty() { 0.0 } else { transactions as f64 / mined.len() as f64 },
+            block_interval,
+            stale_blocks,
+            stale_rate: if after_genesis == 0 { 0.0 } else { stale_blocks as f64 / after_genesis as f64 },
+            network_hash_rate,
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::block::{generate_random_block, Block};
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+    use crate::types::hash::Hashable;
+
+    fn block(parent: &H256, timestamp: u128, txs: usize) -> Block {
+        let mut block = generate_random_block(parent);
+        block.header.timestamp = timestamp;
+        block.content.data = (0..txs)
+            .map(|_| SignedTransaction::new(generate_random_transaction(), vec![], vec![]))
+            .collect();
+        block
+    }
+
+    #[test]
+    fn chain_stats() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let empty = ChainStats::new(&blockchain);
+        assert_eq!((empty.height, empty.transactions, empty.block_interval, empty.network_hash_rate), (0, 0, None, None));
+
+        let b1 = block(&genesis, 10_000, 2);
+        let b2 = block(&b1.hash(), 12_000, 0);
+        let b3 = block(&b2.hash(), 18_000, 4);
+        let stale = block(&b1.hash(), 13_000, 1);
+        for b in [&b1, &b2, &b3, &stale].iter() {
+            blockchain.insert(b);
+        }
+        let stats = ChainStats::new(&blockchain);
+        assert_eq!(stats.height, 3);
+        assert_eq!(stats.transactions, 6);
+        assert_eq!(blockchain.longest_chain_tx_count(), 6);
+        assert_eq!(stats.transactions_per_block, 2.0);
+        assert_eq!(stats.stale_blocks, 1);
+        assert_eq!(stats.stale_rate, 0.25);
+        assert_eq!(stats.block_interval, Some(BlockIntervals { average: 4.0, p50: 2.0, p90: 6.0, p99: 6.0 }));
+        let work = expected_hashes(&b2.header.difficulty) + expected_hashes(&b3.header.difficulty);
+        assert_eq!(stats.network_hash_rate, Some(work / 8.0));
+    }
+
+    #[test]
+    fn work_of_difficulty() {
+        let mut easiest = [255u8; 32];
+        assert_eq!(expected_hashes(&easiest.into()), 1.0);
+        easiest[0] = 0;
+        assert!((expected_hashes(&easiest.into()) - 256.0).abs() < 1e-9);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
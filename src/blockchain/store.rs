@@ -0,0 +1,328 @@
+//! The blocks, the states and the mempool of a full node, written to the data directory while
+//! it runs and when it shuts down, and read back when it starts, so that a restart does not sync
+//! from the genesis.
+
+use super::{Blockchain, ChainState};
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::State;
+use crate::types::transaction::SignedTransaction;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// How often the chain is written while the node runs, if its tip moved
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The chain as written to disk. `flush_periodically` keeps it between flushes, so that each
+/// flush only copies the blocks and states added since the previous one.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Stored {
+    /// Every known block, the genesis first and parents before children
+    pub blocks: Vec<Block>,
+    pub states: HashMap<H256, State>,
+    pub mempool: Vec<SignedTransaction>,
+    /// The blocks already in `blocks`
+    #[serde(skip)]
+    copied: HashSet<H256>,
+}
+
+impl Stored {
+    pub fn new(
+        blockchain: &Blockchain,
+        states: &HashMap<H256, State>,
+        mempool: &HashMap<H256, SignedTransaction>,
+    ) -> Self {
+        let mut stored = Self::default();
+        let (added, longest) = stored.copy_blocks(blockchain);
+        stored.copy_states(&added, states);
+        stored.mempool = mempool.values().cloned().collect();
+        stored.order(&longest);
+        stored
+    }
+
+    /// Copy what `chain` gained since the last call. Each lock is held only while its own part
+    /// is copied, and the blocks are ordered once all are released.
+    pub fn refresh(&mut self, chain: &ChainState) {
+        let (added, longest) = self.copy_blocks(&chain.blockchain.lock().unwrap());
+        // the states of blocks are inserted under the blockchain lock, so those of the copied
+        // blocks are all there by now
+        self.copy_states(&added, &chain.states.lock().unwrap());
+        self.mempool = chain.mempool.lock().unwrap().values().cloned().collect();
+        self.order(&longest);
+    }
+
+    /// Copy the blocks not copied yet, and return their hashes and those of the longest chain.
+    fn copy_blocks(&mut self, blockchain: &Blockchain) -> (Vec<H256>, HashSet<H256>) {
+        let added: Vec<H256> = blockchain.blocks.keys().filter(|hash| !self.copied.contains(*hash)).cloned().collect();
+        self.blocks.extend(added.iter().map(|hash| blockchain.blocks[hash].clone()));
+        self.copied.extend(added.iter().cloned());
+        (added, blockchain.all_blocks_in_longest_chain().into_iter().collect())
+    }
+
+    fn copy_states(&mut self, added: &[H256], states: &HashMap<H256, State>) {
+        for hash in added {
+            if let Some(state) = states.get(hash) {
+                self.states.insert(*hash, state.clone());
+            }
+        }
+    }
+
+    /// At each height the block of the longest chain comes first, so that inserting them in
+    /// order ends on the same tip.
+    fn order(&mut self, longest: &HashSet<H256>) {
+        self.blocks.sort_by_cached_key(|b| (b.height, !longest.contains(&b.hash())));
+    }
+
+    /// Write to a temporary file synced to disk, then move it over `path`, so that a crash while
+    /// writing keeps the previous file.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let bytes = bincode::serialize(self).map_err(|e| format!("error encoding the chain: {}", e))?;
+        let tmp = path.with_extension("tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, path)
+        };
+        write().map_err(|e| format!("error writing {}: {}", path.display(), e))
+    }
+
+    /// `None` if nothing was stored yet.
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("error reading {}: {}", path.display(), e)),
+        };
+        bincode::deserialize(&bytes)
+            .map(Some)
+            .map_err(|e| format!("error decoding {}: {}", path.display(), e))
+    }
+
+    /// Put the stored blocks, states and transactions back into those of a node that just
+    /// started, which must have the same genesis. The file is not trusted more than a peer: each
+    /// block must extend a known one with a valid proof of work, and each state must match the
+    /// root in the header of its block. The genesis state is not committed in a header, so it
+    /// must be the one `states` already has from the configuration.
+    pub fn restore(
+        self,
+        blockchain: &mut Blockchain,
+        states: &mut HashMap<H256, State>,
+        mempool: &mut HashMap<H256, SignedTransaction>,
+    ) -> Result<(), String> {
+        match self.blocks.first() {
+            Some(genesis) if genesis.hash() == blockchain.get_genesis_block_hash() => {}
+            _ => return Err("the stored chain has another genesis".to_string()),
+        }
+        for block in &self.blocks[1..] {
+            let hash = block.hash();
+            let parent = match blockchain.blocks.get(&block.header.parent) {
+                Some(parent) => parent,
+                None => return Err(format!("stored block {} comes before its parent", hash)),
+            };
+            if block.header.difficulty != parent.header.difficulty || hash > block.header.difficulty {
+                return Err(format!("stored block {} has an invalid proof of work", hash));
+            }
+            if MerkleTree::new(&block.content.data).root() != block.header.merkle_root {
+                return Err(format!("stored block {} has a wrong merkle root", hash));
+            }
+            blockchain.insert(block);
+        }
+        let genesis = blockchain.get_genesis_block_hash();
+        for (hash, state) in self.states.iter() {
+            let valid = match blockchain.blocks.get(hash) {
+                Some(_) if *hash == genesis => states.get(hash) == Some(state),
+                Some(block) => state.root() == block.header.state_root,
+                None => false,
+            };
+            if !valid {
+                return Err(format!("stored state of block {} does not match its header", hash));
+            }
+        }
+        states.extend(self.states);
+        for tx in self.mempool {
+            mempool.insert(tx.hash(), tx);
+        }
+        Ok(())
+    }
+}
+
+/// Write the chain to `path` every `FLUSH_INTERVAL` if its tip moved, so that a crash loses at
+/// most that much, and once more when `stop` receives or is dropped.
+pub fn flush_periodically(chain: ChainState, path: PathBuf, stop: Receiver<()>) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("store".to_string())
+        .spawn(move || {
+            let mut flushed_tip = None;
+            let mut stored = Stored::default();
+            loop {
+                let stopping = !matches!(stop.recv_timeout(FLUSH_INTERVAL), Err(RecvTimeoutError::Timeout));
+                let tip = chain.blockchain.lock().unwrap().tip();
+                if stopping || flushed_tip != Some(tip) {
+                    stored.refresh(&chain);
+                    match stored.save(&path) {
+                        Ok(()) if stopping => info!("Stored {} blocks in {}", stored.blocks.len(), path.display()),
+                        Ok(()) => {
+                            debug!("Stored {} blocks in {}", stored.blocks.len(), path.display());
+                            flushed_tip = Some(tip);
+                        }
+                        Err(e) => error!("Error storing the chain: {}", e),
+                    }
+                }
+                if stopping {
+                    return;
+                }
+            }
+        })
+        .unwrap()
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::generate_random_transaction;
+    use std::sync::{Arc, Mutex};
+
+    /// A chain whose genesis every hash meets, as on regtest.
+    fn easy_chain() -> Blockchain {
+        Blockchain::with_genesis([255; 32].into(), 2)
+    }
+
+    /// A valid child of `parent`, committing to `state`.
+    fn child(parent: &H256, state: &State) -> Block {
+        let mut block = generate_random_block(parent);
+        block.header.difficulty = [255; 32].into();
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        block.header.state_root = state.root();
+        block
+    }
+
+    fn save_and_load(stored: Stored) -> Stored {
+        let path = std::env::temp_dir().join(format!("bitcoin-store-{}.bin", rand::random::<u32>()));
+        assert!(Stored::load(&path).unwrap().is_none());
+        stored.save(&path).unwrap();
+        let loaded = Stored::load(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let mut blockchain = easy_chain();
+        let genesis = blockchain.tip();
+        let state: State = [(Address::from_bytes(&[1; 20]), (1, 50))].iter().cloned().collect();
+        let b1 = child(&genesis, &State::new());
+        let b2 = child(&b1.hash(), &state);
+        let fork = child(&b1.hash(), &State::new());
+        for b in [&b1, &b2, &fork].iter() {
+            blockchain.insert(b);
+        }
+        let mut states = HashMap::new();
+        states.insert(b2.hash(), state);
+        let tx = SignedTransaction::new(generate_random_transaction(), vec![], vec![]);
+        let mempool: HashMap<_, _> = [(tx.hash(), tx.clone())].iter().cloned().collect();
+
+        let stored = save_and_load(Stored::new(&blockchain, &states, &mempool));
+        let (mut restored, mut restored_states, mut restored_mempool) = (easy_chain(), HashMap::new(), HashMap::new());
+        stored.restore(&mut restored, &mut restored_states, &mut restored_mempool).unwrap();
+        assert_eq!(restored.tip(), blockchain.tip());
+        assert_eq!(restored.blocks.len(), 4);
+        assert_eq!(restored_states, states);
+        assert!(restored_mempool.contains_key(&tx.hash()));
+
+        let other = Stored::new(&blockchain, &states, &mempool);
+        let mut other_chain = Blockchain::new();
+        assert!(other.restore(&mut other_chain, &mut HashMap::new(), &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn refresh_copies_what_changed() {
+        let chain = ChainState {
+            blockchain: Arc::new(Mutex::new(easy_chain())),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            orphans: Arc::new(Mutex::new(HashMap::new())),
+            mempool: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let genesis = chain.blockchain.lock().unwrap().tip();
+        let a1 = child(&genesis, &State::new());
+        chain.blockchain.lock().unwrap().insert(&a1);
+        let mut stored = Stored::default();
+        stored.refresh(&chain);
+        assert_eq!(stored.blocks.len(), 2);
+
+        // a fork overtaking the copied block must come first at its height
+        let b1 = child(&genesis, &State::new());
+        let b2 = child(&b1.hash(), &State::new());
+        for b in [&b1, &b2].iter() {
+            chain.blockchain.lock().unwrap().insert(b);
+            chain.states.lock().unwrap().insert(b.hash(), State::new());
+        }
+        stored.refresh(&chain);
+        assert_eq!(stored.blocks.len(), 4);
+        assert_eq!(stored.states.len(), 2);
+        assert_eq!(stored.blocks[1].hash(), b1.hash());
+
+        let mut restored = easy_chain();
+        stored.restore(&mut restored, &mut HashMap::new(), &mut HashMap::new()).unwrap();
+        assert_eq!(restored.tip(), b2.hash());
+    }
+
+    #[test]
+    fn reject_tampered_store() {
+        let mut blockchain = easy_chain();
+        let genesis = blockchain.tip();
+        let state: State = [(Address::from_bytes(&[1; 20]), (0, 50))].iter().cloned().collect();
+        let b1 = child(&genesis, &state);
+        blockchain.insert(&b1);
+        let states: HashMap<H256, State> = [(b1.hash(), state)].iter().cloned().collect();
+        let restore = |stored: Stored| stored.restore(&mut easy_chain(), &mut HashMap::new(), &mut HashMap::new());
+
+        let mut stored = Stored::new(&blockchain, &states, &HashMap::new());
+        stored.states.get_mut(&b1.hash()).unwrap().insert(Address::from_bytes(&[2; 20]), (0, 1));
+        assert!(restore(stored).is_err());
+
+        let mut stored = Stored::new(&blockchain, &states, &HashMap::new());
+        stored.blocks[1].header.difficulty = [0; 32].into();
+        assert!(restore(stored).is_err());
+
+        let mut stored = Stored::new(&blockchain, &states, &HashMap::new());
+        stored.blocks.push(child(&generate_random_block(&genesis).hash(), &State::new()));
+        assert!(restore(stored).is_err());
+
+        let mut stored = Stored::new(&blockchain, &states, &HashMap::new());
+        stored.blocks[1].content.data.push(SignedTransaction::new(generate_random_transaction(), vec![], vec![]));
+        assert!(restore(stored).is_err());
+
+        assert!(restore(Stored::new(&blockchain, &states, &HashMap::new())).is_ok());
+    }
+
+    #[test]
+    fn genesis_state_must_match_the_configured_one() {
+        let blockchain = easy_chain();
+        let genesis = blockchain.tip();
+        let allocation: State = [(Address::from_bytes(&[1; 20]), (0, 50))].iter().cloned().collect();
+        let states: HashMap<H256, State> = [(genesis, allocation.clone())].iter().cloned().collect();
+        let restore = |configured: &State| {
+            let mut restored_states: HashMap<H256, State> = [(genesis, configured.clone())].iter().cloned().collect();
+            Stored::new(&blockchain, &states, &HashMap::new())
+                .restore(&mut easy_chain(), &mut restored_states, &mut HashMap::new())
+                .map(|()| restored_states[&genesis].clone())
+        };
+        assert_eq!(restore(&allocation), Ok(allocation));
+        let other: State = [(Address::from_bytes(&[2; 20]), (0, 50))].iter().cloned().collect();
+        assert!(restore(&other).is_err());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,136 @@
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use std::collections::HashMap;
+
+/// Where the transactions of the longest chain are, by hash and by address. Blocks are added
+/// as they extend the longest chain, and removed from the old tip down on a reorganization.
+#[derive(Debug, Default, Clone)]
+pub struct TxIndex {
+    /// The block of each transaction and its position in the block
+    transactions: HashMap<H256, (H256, usize)>,
+    /// The transactions sent or received by each address, from the oldest
+    addresses: HashMap<Address, Vec<H256>>,
+}
+
+impl TxIndex {
+    pub fn add_block(&mut self, block: &Block) {
+        let block_hash = block.hash();
+        for (position, tx) in block.content.data.iter().enumerate() {
+            let tx_hash = tx.hash();
+            self.transactions.insert(tx_hash, (block_hash, position));
+            self.addresses.entry(tx.t.sender).or_default().push(tx_hash);
+            if tx.t.receiver != tx.t.sender {
+                self.addresses.entry(tx.t.receiver).or_default().push(tx_hash);
+            }
+        }
+    }
+
+    /// Undo `add_block`. Blocks must be removed in the reverse order they were added.
+    pub fn remove_block(&mut self, block: &Block) {
+        for tx in block.content.data.iter().rev() {
+            let tx_hash = tx.hash();
+            self.transactions.remove(&tx_hash);
+            for address in [tx.t.sender, tx.t.receiver].iter() {
+                if let Some(txs) = self.addresses.get_mut(address) {
+                    if let Some(pos) = txs.iter().rposition(|h| *h == tx_hash) {
+                        txs.remove(pos);
+                    }
+                    if txs.is_empty() {
+                        self.addresses.remove(address);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The block of a transaction of the longest chain, and its position in the block.
+    pub fn transaction(&self, tx_hash: &H256) -> Option<(H256, usize)> {
+        self.transactions.get(tx_hash).copied()
+    }
+
+    /// The transactions of the longest chain sent or received by an address, from the oldest.
+    pub fn address(&self, address: &Address) -> &[H256] {
+        self.addresses.get(address).map_or(&[], |v| v.as_slice())
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::Blockchain;
+    use crate::types::block::{generate_random_block, Block};
+    use crate::types::hash::{H256, Hashable};
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+
+    fn block_with_transactions(parent: &H256, n: usize) -> Block {
+        let mut block = generate_random_block(parent);
+        block.content.data = (0..n)
+            .map(|_| SignedTransaction::new(generate_random_transaction(), vec![], vec![]))
+            .collect();
+        block
+    }
+
+    #[test]
+    fn follow_reorganizations() {
+        let mut blockchain = Blockchain::new();
+        blockchain.enable_tx_index();
+        let genesis = blockchain.tip();
+        let a1 = block_with_transactions(&genesis, 3);
+        let b1 = block_with_transactions(&genesis, 2);
+        let b2 = block_with_transactions(&b1.hash(), 1);
+        blockchain.insert(&a1);
+
+        let tx = &a1.content.data[1];
+        assert_eq!(blockchain.find_transaction(&tx.hash()).map(|(b, i)| (b.hash(), i)), Some((a1.hash(), 1)));
+        assert_eq!(blockchain.address_transactions(&tx.t.sender), Some(vec![tx.hash()]));
+        assert_eq!(blockchain.confirmations(&a1.hash()), Some(1));
+
+        // the fork becomes the longest chain
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        assert!(blockchain.find_transaction(&tx.hash()).is_none());
+        assert_eq!(blockchain.address_transactions(&tx.t.sender), Some(vec![]));
+        assert_eq!(blockchain.confirmations(&a1.hash()), None);
+        let tx = &b1.content.data[0];
+        assert_eq!(blockchain.find_transaction(&tx.hash()).map(|(b, i)| (b.hash(), i)), Some((b1.hash(), 0)));
+        assert_eq!(blockchain.confirmations(&b1.hash()), Some(2));
+    }
+
+    #[test]
+    fn index_matches_scan() {
+        let mut indexed = Blockchain::new();
+        let mut blocks = vec![block_with_transactions(&indexed.tip(), 4)];
+        blocks.push(block_with_transactions(&blocks[0].hash(), 5));
+        for block in blocks.iter() {
+            indexed.insert(block);
+        }
+        let scanned = indexed.clone();
+        // enabling the index later covers the blocks already in the chain
+        indexed.enable_tx_index();
+        for block in blocks.iter() {
+            for tx in block.content.data.iter() {
+                let expected = scanned.find_transaction(&tx.hash()).map(|(b, i)| (b.hash(), i));
+                assert_eq!(indexed.find_transaction(&tx.hash()).map(|(b, i)| (b.hash(), i)), expected);
+                assert!(expected.is_some());
+            }
+        }
+        let mut hashes: Vec<H256> = blocks.iter().rev().flat_map(|b| b.content.data.iter().map(|tx| tx.hash())).collect();
+        hashes.insert(3, H256::default());
+        let found = |chain: &Blockchain| -> Vec<(H256, usize)> {
+            chain.find_transactions(&hashes).iter().map(|(b, i)| (b.hash(), *i)).collect()
+        };
+        let expected: Vec<(H256, usize)> = hashes
+            .iter()
+            .filter_map(|h| scanned.find_transaction(h))
+            .map(|(b, i)| (b.hash(), i))
+            .collect();
+        assert_eq!(expected.len(), 9);
+        assert_eq!(found(&scanned), expected);
+        assert_eq!(found(&indexed), expected);
+        assert_eq!(scanned.address_transactions(&blocks[0].content.data[0].t.sender), None);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
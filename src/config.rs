@@ -0,0 +1,463 @@
+//! The configuration of a node. It is read from a TOML file, by default `bitcoin.toml` in the
+//! data directory, and the command line flags override it. Every section and field is
+//! optional:
+//!
+//! ```toml
+//! network = "regtest"
+//! log_level = "info"
+//!
+//! [p2p]
+//! addr = "127.0.0.1:6000"
+//! connect = ["127.0.0.1:6001"]
+//! max_inbound = 32
+//!
+//! [api]
+//! addr = "127.0.0.1:7000"
+//!
+//! [chain]
+//! genesis = "genesis.toml"
+//!
+//! [mining]
+//! block_tx_limit = 30
+//!
+//! [mempool]
+//! max_transactions = 10000
+//! ```
+
+use crate::blockchain::params::{Network, Params};
+use crate::blockchain::Blockchain;
+use crate::network::secure::NodeId;
+use crate::network::server;
+use crate::types::address::Address;
+use crate::types::hash::H256;
+use crate::types::state::State;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Name of the configuration file in the data directory
+pub const CONFIG_FILE: &str = "bitcoin.toml";
+/// Name of the node key in the data directory
+pub const NODE_KEY_FILE: &str = "node.key";
+/// Name of the key of our address in the data directory
+pub const WALLET_KEY_FILE: &str = "wallet.key";
+/// Name of the file in the data directory the chain is kept in between runs
+pub const CHAIN_FILE: &str = "chain.bin";
+/// The levels of `log_level`, from the least verbose
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Decides the genesis, the magic of the frames and the default ports
+    pub network: Network,
+    /// One of `LOG_LEVELS`
+    pub log_level: String,
+    /// Only follow the header chain, see `network::light`
+    pub light: bool,
+    pub p2p: P2pConfig,
+    pub api: ApiConfig,
+    pub chain: ChainConfig,
+    pub mining: MiningConfig,
+    pub mempool: MempoolConfig,
+    /// Set by `load`, the chain is only kept between runs with one
+    #[serde(skip)]
+    pub datadir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct P2pConfig {
+    /// Defaults to the port of the network on 127.0.0.1
+    pub addr: Option<SocketAddr>,
+    /// Peers to connect to at start
+    pub connect: Vec<String>,
+    pub workers: usize,
+    pub max_frame_size: u32,
+    /// In seconds
+    pub ping_interval: u64,
+    /// In seconds
+    pub pong_timeout: u64,
+    /// In seconds
+    pub idle_timeout: u64,
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    pub max_per_host: usize,
+    pub encrypt: bool,
+    /// Created if missing. Defaults to `node.key` in the data directory, if there is one
+    pub node_key: Option<PathBuf>,
+    /// Only accept encrypted peers with these node ids
+    #[serde(deserialize_with = "node_ids")]
+    pub allow_nodes: Option<Vec<NodeId>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Defaults to the port of the network on 127.0.0.1
+    pub addr: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    pub read_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainConfig {
+    /// A TOML file describing the genesis block and its allocations, see `Genesis`
+    pub genesis: Option<PathBuf>,
+    /// Coins our own address gets in the genesis state, when the genesis allocates nothing.
+    /// Defaults to the amount of the network.
+    pub ico_amount: Option<u64>,
+    /// Index the transactions of the longest chain by hash and by address
+    pub tx_index: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiningConfig {
+    /// Most transactions in a mined block
+    pub block_tx_limit: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MempoolConfig {
+    /// Transactions beyond this are refused until blocks make room
+    pub max_transactions: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: Network::default(),
+            log_level: "error".to_string(),
+            light: false,
+            p2p: P2pConfig::default(),
+            api: ApiConfig::default(),
+            chain: ChainConfig::default(),
+            mining: MiningConfig::default(),
+            mempool: MempoolConfig::default(),
+            datadir: None,
+        }
+    }
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        let server = server::Config::default();
+        Self {
+            addr: None,
+            connect: Vec::new(),
+            workers: 4,
+            max_frame_size: server.max_frame_size,
+            ping_interval: server.ping_interval.as_secs(),
+            pong_timeout: server.pong_timeout.as_secs(),
+            idle_timeout: server.idle_timeout.as_secs(),
+            max_inbound: server.max_inbound,
+            max_outbound: server.max_outbound,
+            max_per_host: server.max_per_host,
+            encrypt: false,
+            node_key: None,
+            allow_nodes: None,
+        }
+    }
+}
+
+impl Default for MiningConfig {
+    fn default() -> Self {
+        Self { block_tx_limit: 30 }
+    }
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self { max_transactions: 10_000 }
+    }
+}
+
+impl Config {
+    /// Read the configuration file: `file` if given, `bitcoin.toml` in the data directory if
+    /// it exists, the defaults otherwise. The relative paths in a file are resolved against
+    /// the data directory, or against the directory of the file without one.
+    pub fn load(datadir: Option<&Path>, file: Option<&Path>) -> Result<Self, String> {
+        let file = match (file, datadir) {
+            (Some(f), _) => Some(f.to_path_buf()),
+            (None, Some(d)) if d.join(CONFIG_FILE).exists() => Some(d.join(CONFIG_FILE)),
+            (None, _) => None,
+        };
+        let mut config = match &file {
+            Some(f) => {
+                let text = std::fs::read_to_string(f).map_err(|e| format!("error reading {}: {}", f.display(), e))?;
+                toml::from_str(&text).map_err(|e| format!("error parsing {}: {}", f.display(), e))?
+            }
+            None => Config::default(),
+        };
+        let base = datadir.or_else(|| file.as_ref().and_then(|f| f.parent()));
+        if let Some(base) = base {
+            config.p2p.node_key = config.p2p.node_key.map(|p| base.join(p));
+            config.chain.genesis = config.chain.genesis.map(|p| base.join(p));
+        }
+        if let Some(datadir) = datadir {
+            config.p2p.node_key.get_or_insert_with(|| datadir.join(NODE_KEY_FILE));
+            config.datadir = Some(datadir.to_path_buf());
+        }
+        Ok(config)
+    }
+
+    /// Check the values that would otherwise fail, or be ignored, deep in a subsystem.
+    pub fn validate(&self) -> Result<(), String> {
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return Err(format!("log_level must be one of {}", LOG_LEVELS.join(", ")));
+        }
+        if self.p2p.workers == 0 {
+            return Err("p2p.workers must be at least 1".to_string());
+        }
+        if self.p2p.max_frame_size == 0 {
+            return Err("p2p.max_frame_size must be at least 1".to_string());
+        }
+        if self.p2p.ping_interval == 0 || self.p2p.pong_timeout == 0 {
+            return Err("p2p.ping_interval and p2p.pong_timeout must be at least 1".to_string());
+        }
+        if self.p2p.idle_timeout <= self.p2p.ping_interval {
+            return Err("p2p.idle_timeout must be longer than p2p.ping_interval".to_string());
+        }
+        if self.p2p.allow_nodes.is_some() && !self.p2p.encrypt {
+            return Err("p2p.allow_nodes requires p2p.encrypt".to_string());
+        }
+        if self.p2p_addr() == self.api_addr() {
+            return Err("p2p.addr and api.addr must differ".to_string());
+        }
+        if self.mining.block_tx_limit == 0 {
+            return Err("mining.block_tx_limit must be at least 1".to_string());
+        }
+        if self.mempool.max_transactions == 0 {
+            return Err("mempool.max_transactions must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn params(&self) -> &'static Params {
+        self.network.params()
+    }
+
+    pub fn p2p_addr(&self) -> SocketAddr {
+        self.p2p.addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], self.params().p2p_port)))
+    }
+
+    pub fn api_addr(&self) -> SocketAddr {
+        self.api.addr.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], self.params().api_port)))
+    }
+
+    pub fn ico_amount(&self) -> u64 {
+        self.chain.ico_amount.unwrap_or(self.params().ico_amount)
+    }
+
+    /// Where the blocks, the states and the mempool are kept between runs, see
+    /// `blockchain::store`.
+    pub fn chain_file(&self) -> Option<PathBuf> {
+        self.datadir.as_ref().map(|d| d.join(CHAIN_FILE))
+    }
+
+    /// Where the key of our address is kept between runs, so that the stored states still hold
+    /// our funds.
+    pub fn wallet_key_file(&self) -> Option<PathBuf> {
+        self.datadir.as_ref().map(|d| d.join(WALLET_KEY_FILE))
+    }
+
+    /// The verbosity of `stderrlog` for `log_level`.
+    pub fn verbosity(&self) -> usize {
+        LOG_LEVELS.iter().position(|l| *l == self.log_level).unwrap_or(0)
+    }
+
+    /// The genesis of `chain.genesis`, or the one of the network.
+    pub fn genesis(&self) -> Result<Genesis, String> {
+        match &self.chain.genesis {
+            Some(path) => Genesis::load(path, self.params()),
+            None => Ok(Genesis::of(self.params())),
+        }
+    }
+}
+
+/// The genesis block every node of a network must agree on, and the accounts it starts with.
+/// A genesis file leaves out what it takes from the network:
+///
+/// ```toml
+/// difficulty = "0000ffff01010000000000010000000001000000000000000000000000000000"
+/// timestamp = 0
+///
+/// [[allocations]]
+/// address = "2c98e9071d862e41f2da07835c67a793e3b761da"
+/// balance = 50
+/// ```
+///
+/// Addresses may also start with the prefix of the network, as the node prints them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Genesis {
+    /// Of the genesis block, which every block inherits
+    pub difficulty: H256,
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    /// Accounts of the genesis state. Without any, each node gives itself `chain.ico_amount`.
+    pub allocations: Vec<Allocation>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GenesisFile {
+    #[serde(default, deserialize_with = "hash")]
+    difficulty: Option<H256>,
+    timestamp: Option<u64>,
+    #[serde(default)]
+    allocations: Vec<AllocationFile>,
+}
+
+/// An allocation as written, the address is parsed with the prefix of the network.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AllocationFile {
+    address: String,
+    balance: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub address: Address,
+    pub balance: u64,
+}
+
+impl Genesis {
+    /// The genesis of a network, without allocations.
+    pub fn of(params: &Params) -> Self {
+        Self { difficulty: params.difficulty.into(), timestamp: params.genesis_timestamp, allocations: Vec::new() }
+    }
+
+    pub fn load(path: &Path, params: &Params) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+        let file: GenesisFile = toml::from_str(&text).map_err(|e| format!("error parsing {}: {}", path.display(), e))?;
+        let allocations = file
+            .allocations
+            .into_iter()
+            .map(|a| match params.parse_address(&a.address) {
+                Ok(address) => Ok(Allocation { address, balance: a.balance }),
+                Err(e) => Err(format!("error parsing {}: {}", path.display(), e)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            difficulty: file.difficulty.unwrap_or_else(|| params.difficulty.into()),
+            timestamp: file.timestamp.unwrap_or(params.genesis_timestamp),
+            allocations,
+        })
+    }
+
+    pub fn blockchain(&self) -> Blockchain {
+        Blockchain::with_genesis(self.difficulty, self.timestamp as u128)
+    }
+
+    /// The state after the genesis block, where `ours` gets `ico_amount` if nothing is allocated.
+    pub fn state(&self, ours: Address, ico_amount: u64) -> State {
+        if self.allocations.is_empty() {
+            return vec![(ours, (0, ico_amount))].into_iter().collect();
+        }
+        self.allocations.iter().map(|a| (a.address, (0, a.balance))).collect()
+    }
+}
+
+fn hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<H256>, D::Error> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(String::deserialize(deserializer)?, &mut bytes)
+        .map_err(|_| D::Error::custom("expected 32 hex encoded bytes"))?;
+    Ok(Some(bytes.into()))
+}
+
+fn node_ids<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<NodeId>>, D::Error> {
+    let ids: Option<Vec<String>> = Option::deserialize(deserializer)?;
+    ids.map(|ids| {
+        ids.iter()
+            .map(|id| id.parse().map_err(|e| D::Error::custom(format!("bad node id {}: {}", id, e))))
+            .collect()
+    })
+    .transpose()
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::params::{MAIN, REGTEST};
+
+    #[test]
+    fn parse_and_validate() {
+        let config: Config = toml::from_str(
+            r#"
+            log_level = "info"
+            [p2p]
+            addr = "127.0.0.1:6001"
+            connect = ["127.0.0.1:6000"]
+            [mining]
+            block_tx_limit = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.p2p_addr(), "127.0.0.1:6001".parse().unwrap());
+        assert_eq!(config.api_addr(), "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.p2p.connect, vec!["127.0.0.1:6000".to_string()]);
+        assert_eq!(config.p2p.max_inbound, 32);
+        assert_eq!(config.mining.block_tx_limit, 10);
+        assert_eq!(config.mempool, MempoolConfig::default());
+        assert_eq!(config.verbosity(), 2);
+        assert_eq!(config.validate(), Ok(()));
+
+        assert!(toml::from_str::<Config>("[p2p]\nworker = 2").is_err());
+        let mut bad = config;
+        bad.api.addr = Some(bad.p2p_addr());
+        assert!(bad.validate().is_err());
+        bad = Config::default();
+        bad.p2p.idle_timeout = bad.p2p.ping_interval;
+        assert!(bad.validate().is_err());
+        bad = Config::default();
+        bad.p2p.pong_timeout = 0;
+        assert!(bad.validate().is_err());
+        let regtest: Config = toml::from_str("network = \"regtest\"").unwrap();
+        assert_eq!(regtest.p2p_addr().port(), REGTEST.p2p_port);
+        assert_eq!(regtest.ico_amount(), REGTEST.ico_amount);
+        assert_eq!(regtest.genesis(), Ok(Genesis::of(&REGTEST)));
+    }
+
+    #[test]
+    fn datadir_and_genesis() {
+        let datadir = std::env::temp_dir().join(format!("bitcoin-config-{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&datadir).unwrap();
+        let defaults = Config::load(Some(&datadir), None).unwrap();
+        assert_eq!(defaults.p2p.node_key, Some(datadir.join(NODE_KEY_FILE)));
+        assert_eq!(defaults.chain_file(), Some(datadir.join(CHAIN_FILE)));
+        assert_eq!(defaults.wallet_key_file(), Some(datadir.join(WALLET_KEY_FILE)));
+        assert_eq!(Config::default().chain_file(), None);
+
+        std::fs::write(datadir.join(CONFIG_FILE), "[chain]\ngenesis = \"genesis.toml\"").unwrap();
+        std::fs::write(
+            datadir.join("genesis.toml"),
+            format!(
+                "difficulty = \"{}\"\ntimestamp = 5\n[[allocations]]\naddress = \"{}\"\nbalance = 7\n[[allocations]]\naddress = \"{}\"\nbalance = 8",
+                "ff".repeat(32),
+                "01".repeat(20),
+                MAIN.format_address(&Address::from_bytes(&[3u8; 20]))
+            ),
+        )
+        .unwrap();
+        let config = Config::load(Some(&datadir), None).unwrap();
+        let genesis = config.genesis().unwrap();
+        assert_eq!(genesis.difficulty, [255u8; 32].into());
+        let blockchain = genesis.blockchain();
+        assert_eq!(blockchain.blocks[&blockchain.tip()].header.timestamp, 5);
+        let state = genesis.state(Address::from_bytes(&[2u8; 20]), 50);
+        assert_eq!(state.get(&Address::from_bytes(&[1u8; 20])), Some(&(0, 7)));
+        assert_eq!(state.get(&Address::from_bytes(&[3u8; 20])), Some(&(0, 8)));
+        assert_eq!(state.len(), 2);
+        assert_eq!(Genesis::of(&MAIN).state(Address::from_bytes(&[2u8; 20]), 50).len(), 1);
+        std::fs::remove_dir_all(&datadir).unwrap();
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
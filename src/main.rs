@@ -4,17 +4,23 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod config;
 pub mod types;
 pub mod miner;
 pub mod network;
 
 use blockchain::Blockchain;
-use clap::clap_app;
+use blockchain::store::{self, Stored};
+use clap::{clap_app, ArgMatches};
+use config::{Config, LOG_LEVELS};
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use std::net;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
@@ -22,9 +28,11 @@ use std::collections::HashMap;
 use crate::types::block::Block;
 use crate::types::hash::{H256, Hashable};
 use crate::types::transaction::{SignedTransaction};
+use crate::types::state::State;
 use crate::types::transaction_generator::{TransactionGenerator};
 use crate::types::address::Address;
 use crate::network::message::Message;
+use crate::network::light::LightClient;
 
 use ring::signature::{self, Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use ring::{digest};
@@ -32,6 +40,8 @@ use crate::types::key_pair;
 
 use std::time::{SystemTime};
 
+/// How long peers get, at shutdown, to receive what was queued for them
+const CLOSE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
 
 
 fn main() {
@@ -39,18 +49,55 @@ fn main() {
     let matches = clap_app!(Bitcoin =>
      (version: "0.1")
      (about: "Bitcoin client")
-     (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg verbose: -v ... "Increases the verbosity of logging, instead of log_level")
+     (@arg datadir: --datadir [DIR] "Sets the data directory, holding bitcoin.toml and the node key. It is created if missing")
+     (@arg config: --config [FILE] "Sets the configuration file, instead of bitcoin.toml in the data directory. The flags below override it")
+     (@arg network: --network [NETWORK] possible_values(&["main", "test", "regtest"]) "Sets the network to join, main by default. Each has its own genesis, magic and default ports")
+     (@arg peer_addr: --p2p [ADDR] "Sets the IP address and the port of the P2P server, the port of the network on 127.0.0.1 by default")
+     (@arg api_addr: --api [ADDR] "Sets the IP address and the port of the API server, the port of the network on 127.0.0.1 by default")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
-     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg p2p_workers: --("p2p-workers") [INT] "Sets the number of worker threads for P2P server")
+     (@arg max_frame_size: --("max-frame-size") [BYTES] "Sets the maximum size of a P2P message, larger frames disconnect the peer")
+     (@arg ping_interval: --("ping-interval") [SECS] "Sets how often peers are pinged")
+     (@arg pong_timeout: --("pong-timeout") [SECS] "Sets how long a peer has to answer a ping before disconnecting it")
+     (@arg idle_timeout: --("idle-timeout") [SECS] "Sets how long a silent peer is kept before disconnecting it")
+     (@arg max_inbound: --("max-inbound") [INT] "Sets the maximum number of incoming peers")
+     (@arg max_outbound: --("max-outbound") [INT] "Sets the maximum number of outgoing peers")
+     (@arg max_per_host: --("max-per-host") [INT] "Sets the maximum number of connections with one non-loopback IP address")
+     (@arg encrypt: --encrypt "Encrypts P2P traffic and authenticates peers by their node key")
+     (@arg node_key: --("node-key") [FILE] "Sets the file holding the node key, it is created if missing. A random key is used without it or a data directory")
+     (@arg allow_node: --("allow-node") ... [NODE_ID] "Only accepts encrypted peers with these node ids")
+     (@arg genesis: --genesis [FILE] "Sets the file describing the genesis block and its allocations")
+     (@arg block_tx_limit: --("block-tx-limit") [INT] "Sets the maximum number of transactions in a mined block")
+     (@arg max_mempool: --("max-mempool") [INT] "Sets the maximum number of transactions in the mempool")
+     (@arg tx_index: --("tx-index") "Indexes the transactions of the longest chain by hash and by address")
+     (@arg light: --light "Runs a light client that only follows the header chain and checks the proofs of the transactions it watches")
+     (@arg api_admin_token: --("api-admin-token") [TOKEN] env("BITCOIN_API_ADMIN_TOKEN") "Requires this bearer token for the API endpoints that change the node. The API is open otherwise")
+     (@arg api_read_token: --("api-read-token") [TOKEN] env("BITCOIN_API_READ_TOKEN") "Requires this bearer token, or the admin one, for the read endpoints of the API. They are open otherwise")
     )
     .get_matches();
 
+    // read the configuration file, and override it with the flags
+    let config = load_config(&matches).unwrap_or_else(|e| {
+        eprintln!("Error in the configuration: {}", e);
+        process::exit(1);
+    });
+    let genesis = config.genesis().unwrap_or_else(|e| {
+        eprintln!("Error loading the genesis: {}", e);
+        process::exit(1);
+    });
+
     // The time our node comes online
     // let node_start_time: SystemTime = SystemTime::now();
-    // Create our own address
-    let key = Arc::new(Mutex::new(key_pair::random()));
+    // Create our own address, or load it from the data directory
+    let key = match config.wallet_key_file() {
+        Some(path) => key_pair::load_or_generate(&path).unwrap_or_else(|e| {
+            eprintln!("Error loading the wallet key {}: {}", path.display(), e);
+            process::exit(1);
+        }),
+        None => key_pair::random(),
+    };
+    let key = Arc::new(Mutex::new(key));
     let pub_key_guard = key.lock().unwrap();
     let pub_key = pub_key_guard.public_key();
     let h = digest::digest(&digest::SHA256, pub_key.as_ref());
@@ -59,147 +106,315 @@ fn main() {
     let hex_h = hex::encode(h).into_bytes();
     let public_addr = Address::from_public_key_bytes(&hex_h);
 
-    println!("Our address {:?}", public_addr);
+    println!("Our address {}", config.params().format_address(&public_addr));
 
     // init logger
-    let verbosity = matches.occurrences_of("verbose") as usize;
-    stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
-    // parse p2p server address
-    let p2p_addr = matches
-        .value_of("peer_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P server address: {}", e);
-            process::exit(1);
-        });
+    stderrlog::new().verbosity(config.verbosity()).init().unwrap();
 
-    // parse api server address
-    let api_addr = matches
-        .value_of("api_addr")
-        .unwrap()
-        .parse::<net::SocketAddr>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing API server address: {}", e);
+    // SIGINT and SIGTERM shut the node down gracefully, a second one exits at once
+    let (stop_tx, stop_rx) = crossbeam::channel::bounded(1);
+    let stopping = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if stopping.swap(true, Ordering::SeqCst) {
             process::exit(1);
-        });
+        }
+        let _ = stop_tx.send(());
+    })
+    .unwrap_or_else(|e| {
+        error!("Error handling signals: {}", e);
+        process::exit(1);
+    });
+    let p2p_addr = config.p2p_addr();
+    let api_addr = config.api_addr();
+    info!("Joining the {} network", config.network);
+
+    // the tokens of the API roles
+    let api_auth = api::auth::Auth::new(
+        config.api.admin_token.clone(),
+        config.api.read_token.clone(),
+    )
+    .unwrap_or_else(|e| {
+        error!("Error configuring API authentication: {}", e);
+        process::exit(1);
+    });
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
+    // load the node identity used to authenticate encrypted connections
+    let secure = if config.p2p.encrypt {
+        let node_key = match &config.p2p.node_key {
+            Some(path) => key_pair::load_or_generate(path).unwrap_or_else(|e| {
+                error!("Error loading node key {}: {}", path.display(), e);
+                process::exit(1);
+            }),
+            None => key_pair::random(),
+        };
+        let allowed = config.p2p.allow_nodes.clone().map(|ids| ids.into_iter().collect());
+        info!("Our node id {}", network::secure::NodeId::of(&node_key));
+        Some(network::secure::SecureConfig { key: Arc::new(node_key), allowed })
+    } else {
+        None
+    };
+    let server_config = network::server::Config {
+        max_frame_size: config.p2p.max_frame_size,
+        ping_interval: time::Duration::from_secs(config.p2p.ping_interval),
+        pong_timeout: time::Duration::from_secs(config.p2p.pong_timeout),
+        idle_timeout: time::Duration::from_secs(config.p2p.idle_timeout),
+        max_inbound: config.p2p.max_inbound,
+        max_outbound: config.p2p.max_outbound,
+        max_per_host: config.p2p.max_per_host,
+        secure,
+        magic: config.params().magic,
+        ..Default::default()
+    };
+
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx, server_config).unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
-    let p2p_workers = matches
-        .value_of("p2p_workers")
-        .unwrap()
-        .parse::<usize>()
-        .unwrap_or_else(|e| {
-            error!("Error parsing P2P workers: {}", e);
-            process::exit(1);
+    let p2p_workers = config.p2p.workers;
+    let known_peers = config.p2p.connect.clone();
+
+    if config.light {
+        let genesis = genesis.blockchain();
+        let mut light_client = LightClient::new(&genesis.blocks[&genesis.tip()].header);
+        light_client.watch_address(public_addr);
+        let client = Arc::new(Mutex::new(light_client));
+        let workers = network::light::Worker::new(p2p_workers, msg_rx, &client).start();
+        // ask each full node for the headers we are missing, and to only relay what is ours
+        let hello_client = Arc::clone(&client);
+        connect_to_known_peers(known_peers, &server, move || {
+            let client = hello_client.lock().unwrap();
+            vec![Message::FilterLoad(client.filter()), Message::GetHeaders(client.headers().locator())]
         });
+        let api = ApiServer::start_light(api_addr, &server, &client, config.params(), &api_auth);
+
+        let _ = stop_rx.recv();
+        info!("Shutting down");
+        api.stop();
+        server.stop_receiving();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        if !server.close_peers(CLOSE_TIMEOUT) {
+            warn!("Some peers were still connected after {:?}", CLOSE_TIMEOUT);
+        }
+        return;
+    }
+
     // new blockchain
-    let b_chain: Blockchain = Blockchain::new();
+    let mut b_chain: Blockchain = genesis.blockchain();
+    if config.chain.tx_index {
+        b_chain.enable_tx_index();
+    }
     let blockchain = Arc::new(Mutex::new(b_chain));
     let orphans_map:Arc<Mutex<HashMap<H256, Vec<Block>>>> = Arc::new(Mutex::new(HashMap::new()));
     let mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>> = Arc::new(Mutex::new(HashMap::new()));
-    let states: Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let states: Arc<Mutex<HashMap<H256, State>>> = Arc::new(Mutex::new(HashMap::new()));
     let peers: Arc<Mutex<Vec<Address>>> = Arc::new(Mutex::new(Vec::new()));
 
     // Add it to our own state map
-    let blockchain_guard = blockchain.lock().unwrap();
+    let mut blockchain_guard = blockchain.lock().unwrap();
     let mut states_guard = states.lock().unwrap();
     
-    let mut ico_state: HashMap<Address, (u64, u64)> = HashMap::new();
-    ico_state.insert(public_addr, (0, 50));
+    let ico_state = genesis.state(public_addr, config.ico_amount());
     states_guard.insert(blockchain_guard.tip(), ico_state);
 
+    // pick up where the last run stopped
+    if let Some(path) = config.chain_file() {
+        let restored = Stored::load(&path).and_then(|stored| match stored {
+            Some(stored) => stored.restore(&mut blockchain_guard, &mut states_guard, &mut mempool.lock().unwrap()),
+            None => Ok(()),
+        });
+        if let Err(e) = restored {
+            error!("Error restoring the chain from {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        let height = blockchain_guard.blocks[&blockchain_guard.tip()].height;
+        info!("Starting at height {} with {} transactions in the mempool", height, mempool.lock().unwrap().len());
+    }
+
     std::mem::drop(blockchain_guard);
     std::mem::drop(states_guard);
     
     
+    let chain = blockchain::ChainState {
+        blockchain: Arc::clone(&blockchain),
+        states: Arc::clone(&states),
+        orphans: Arc::clone(&orphans_map),
+        mempool: Arc::clone(&mempool),
+    };
     let worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
-        &blockchain,
-        &orphans_map,
-        &mempool,
-        &states,
+        &chain,
+        &config.mempool,
         &peers, 
         // &node_start_time, 
         &public_addr
     );
-    worker_ctx.start();
+    let network_workers = worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &states); // Added &blockchain, &mempool, and &states
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &states, &config.mining); // Added &blockchain, &mempool, and &states
     let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain); // Added &blockchain
-    miner_ctx.start();
-    miner_worker_ctx.start();
-
-    // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        let our_pub_addr = public_addr.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            
-                            // Request for state 
-                            println!("Request state from {:?}", addr);
-                            server.send(addr, Message::RequestState(our_pub_addr));
-
-                            // Write to peer requesting for state - overwrite your state with peers state
-                            // let msg = "LOL".to_string();
-                            // server.send(addr, Message::Ping(msg));
-                            // println!("{:?}", peer);
-
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
-                    }
-                }
-            }
-        });
-    }
-    // let (tx_ctx, tx_handler) = TransactionGenerator::new(&server);
-    let tx_generator: TransactionGenerator = TransactionGenerator::new(&server, &mempool, &key, &states, &blockchain, &public_addr, /*&node_start_time,*/ &peers); 
+    let miner_thread = miner_ctx.start();
+    let miner_worker_thread = miner_worker_ctx.start();
+
+    // connect to known peers, and ask for a recent state snapshot, only the first valid one is used
+    let our_pub_addr = public_addr;
+    connect_to_known_peers(known_peers, &server, move || vec![Message::GetSnapshot(our_pub_addr)]);
+    let (tx_generator_ctx, tx_generator) = TransactionGenerator::new(&server, &chain, &config.mempool, &key, &public_addr, /*&node_start_time,*/ &peers); 
+    let tx_generator_thread = tx_generator_ctx.start();
 
 
     // start the API server
-    ApiServer::start(
+    let api = ApiServer::start(
         api_addr,
-        &miner,
-        &server,
-        &blockchain,
-        &tx_generator,
-        &states
+        &api::Context {
+            miner: &miner,
+            network: &server,
+            tx_generator: &tx_generator,
+            chain: &chain,
+            mempool_config: &config.mempool,
+            params: config.params(),
+            auth: &api_auth,
+        },
     );
 
-    loop {
-        std::thread::park();
+    // write the chain as it grows, and a last time once everything stopped
+    let flusher = config.chain_file().map(|path| {
+        let (flush_stop, flush_stop_rx) = crossbeam::channel::bounded(1);
+        (flush_stop, store::flush_periodically(chain.clone(), path, flush_stop_rx))
+    });
+
+    let _ = stop_rx.recv();
+    info!("Shutting down");
+    // no request changes the node from now on
+    api.stop();
+    // the blocks mined so far are inserted and relayed
+    miner.exit();
+    tx_generator.exit();
+    let _ = miner_thread.join();
+    let _ = miner_worker_thread.join();
+    let _ = tx_generator_thread.join();
+    // the messages already read are handled, and the replies written before the peers are closed
+    server.stop_receiving();
+    for worker in network_workers {
+        let _ = worker.join();
+    }
+    if !server.close_peers(CLOSE_TIMEOUT) {
+        warn!("Some peers were still connected after {:?}", CLOSE_TIMEOUT);
+    }
+    if let Some((flush_stop, flusher)) = flusher {
+        let _ = flush_stop.send(());
+        let _ = flusher.join();
+    }
+    info!("Shut down");
+}
+
+/// Read the configuration file, from the data directory or `--config`, and apply the flags
+/// over it.
+fn load_config(matches: &ArgMatches) -> Result<Config, String> {
+    let datadir = matches.value_of("datadir").map(PathBuf::from);
+    if let Some(datadir) = &datadir {
+        std::fs::create_dir_all(datadir).map_err(|e| format!("error creating {}: {}", datadir.display(), e))?;
+    }
+    let mut config = Config::load(datadir.as_deref(), matches.value_of("config").map(Path::new))?;
+
+    fn flag<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        matches
+            .value_of(name)
+            .map(|v| v.parse::<T>().map_err(|e| format!("error parsing {}: {}", name, e)))
+            .transpose()
+    }
+    if matches.occurrences_of("verbose") > 0 {
+        let verbosity = std::cmp::min(matches.occurrences_of("verbose") as usize, LOG_LEVELS.len() - 1);
+        config.log_level = LOG_LEVELS[verbosity].to_string();
+    }
+    config.network = flag(matches, "network")?.unwrap_or(config.network);
+    let p2p = &mut config.p2p;
+    p2p.addr = flag(matches, "peer_addr")?.or(p2p.addr);
+    if let Some(peers) = matches.values_of("known_peer") {
+        p2p.connect = peers.map(String::from).collect();
+    }
+    p2p.workers = flag(matches, "p2p_workers")?.unwrap_or(p2p.workers);
+    p2p.max_frame_size = flag(matches, "max_frame_size")?.unwrap_or(p2p.max_frame_size);
+    p2p.ping_interval = flag(matches, "ping_interval")?.unwrap_or(p2p.ping_interval);
+    p2p.pong_timeout = flag(matches, "pong_timeout")?.unwrap_or(p2p.pong_timeout);
+    p2p.idle_timeout = flag(matches, "idle_timeout")?.unwrap_or(p2p.idle_timeout);
+    p2p.max_inbound = flag(matches, "max_inbound")?.unwrap_or(p2p.max_inbound);
+    p2p.max_outbound = flag(matches, "max_outbound")?.unwrap_or(p2p.max_outbound);
+    p2p.max_per_host = flag(matches, "max_per_host")?.unwrap_or(p2p.max_per_host);
+    p2p.encrypt |= matches.is_present("encrypt");
+    if let Some(path) = matches.value_of("node_key") {
+        p2p.node_key = Some(PathBuf::from(path));
     }
+    if let Some(ids) = matches.values_of("allow_node") {
+        let ids: Result<Vec<_>, _> = ids
+            .map(|id| id.parse().map_err(|e| format!("error parsing node id {}: {}", id, e)))
+            .collect();
+        p2p.allow_nodes = Some(ids?);
+    }
+    config.api.addr = flag(matches, "api_addr")?.or(config.api.addr);
+    if let Some(token) = matches.value_of("api_admin_token") {
+        config.api.admin_token = Some(token.to_string());
+    }
+    if let Some(token) = matches.value_of("api_read_token") {
+        config.api.read_token = Some(token.to_string());
+    }
+    if let Some(path) = matches.value_of("genesis") {
+        config.chain.genesis = Some(PathBuf::from(path));
+    }
+    config.chain.tx_index |= matches.is_present("tx_index");
+    config.light |= matches.is_present("light");
+    config.mining.block_tx_limit = flag(matches, "block_tx_limit")?.unwrap_or(config.mining.block_tx_limit);
+    config.mempool.max_transactions = flag(matches, "max_mempool")?.unwrap_or(config.mempool.max_transactions);
+    config.validate()?;
+    Ok(config)
+}
+
+/// Connect to each peer in turn, retrying every second until it succeeds, and send it the
+/// messages made by `hello`.
+fn connect_to_known_peers<F>(known_peers: Vec<String>, server: &network::server::Handle, hello: F)
+where
+    F: Fn() -> Vec<Message> + Send + 'static,
+{
+    let server = server.clone();
+    thread::spawn(move || {
+        for peer in known_peers {
+            loop {
+                let addr = match peer.parse::<net::SocketAddr>() {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("Error parsing peer address {}: {}", &peer, e);
+                        break;
+                    }
+                };
+                match server.connect(addr) {
+                    Ok(_) => {
+                        info!("Connected to outgoing peer {}", &addr);
+                        for msg in hello() {
+                            server.send(addr, msg);
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error connecting to peer {}, retrying in one second: {}",
+                            addr, e
+                        );
+                        thread::sleep(time::Duration::from_millis(1000));
+                        continue;
+                    }
+                }
+            }
+        }
+    });
 }
@@ -3,7 +3,7 @@ pub mod worker;
 use log::info;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use rand::Rng;
@@ -13,13 +13,19 @@ use crate::types::block::{Header, Content, Block};
 use crate::types::transaction::{SignedTransaction};
 use crate::types::hash::{H256, Hashable};
 use crate::blockchain::Blockchain;
+use crate::config::MiningConfig;
 use crate::types::merkle::MerkleTree;
+use crate::types::state;
 use std::collections::HashMap;
 use crate::types::address::Address;
+use serde::Serialize;
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Pause,
+    Resume, // start again with the lambda of the last start
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Generate(u64, Sender<H256>), // mine this many blocks without waiting, even while paused, and send their hashes
     Exit,
 }
 
@@ -29,6 +35,50 @@ enum OperatingState {
     ShutDown,
 }
 
+/// What a worker thread is doing, as reported by the API
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Paused,
+    Running,
+    /// The thread exited, it cannot be started again
+    Stopped,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub state: State,
+    /// The lambda of the last start, kept while paused
+    pub lambda: Option<u64>,
+    pub blocks_mined: u64,
+    /// Nonces tried since the start of the node
+    pub hashes: u64,
+    /// Nonces tried per second, over the last second of mining
+    pub hash_rate: f64,
+}
+
+/// How often the hash rate is measured
+const HASH_RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a block template is mined on when neither the tip nor the size of the mempool
+/// changes, so that transactions replacing others of the mempool are picked up
+const TEMPLATE_REFRESH: Duration = Duration::from_secs(1);
+
+/// The block being mined, except for its timestamp and nonce, built once rather than for each
+/// nonce since computing its state is costly
+struct Template {
+    parent: H256,
+    /// The size of the mempool when the transactions were picked
+    mempool_len: usize,
+    built: Instant,
+    difficulty: H256,
+    merkle_root: H256,
+    state_root: H256,
+    txs: Vec<SignedTransaction>,
+    /// The state after the transactions
+    state: state::State,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -36,18 +86,27 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
-    states: Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>>
+    states: Arc<Mutex<HashMap<H256, state::State>>>,
+    status: Arc<Mutex<Status>>,
+    /// Most transactions in a block
+    block_tx_limit: usize,
+    /// Blocks left to generate, and where to send their hashes
+    generating: Option<(u64, Sender<H256>)>,
+    /// Nonces tried since the hash rate was last measured, not yet in the status
+    uncounted_hashes: u64,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>, states: &Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>, states: &Arc<Mutex<HashMap<H256, state::State>>>, config: &MiningConfig) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let status = Arc::new(Mutex::new(Status { state: State::Paused, lambda: None, blocks_mined: 0, hashes: 0, hash_rate: 0.0 }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
@@ -55,11 +114,16 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
         mempool: Arc::clone(mempool),
-        states: Arc::clone(states)
+        states: Arc::clone(states),
+        status: Arc::clone(&status),
+        block_tx_limit: config.block_tx_limit,
+        generating: None,
+        uncounted_hashes: 0,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        status,
     };
 
     (ctx, handle, finished_block_receiver)
@@ -72,35 +136,169 @@ fn test_new() -> (Context, Handle, Receiver<Block>) { // CORRECT????
     let blockchain = Arc::new(Mutex::new(b_chain));
     
     let mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>> = Arc::new(Mutex::new(HashMap::new())); 
-    let states: Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>> = Arc::new(Mutex::new(HashMap::new()));
-    new(&blockchain, &mempool, &states)
+    let states: Arc<Mutex<HashMap<H256, state::State>>> = Arc::new(Mutex::new(HashMap::new()));
+    new(&blockchain, &mempool, &states, &MiningConfig::default())
 }
 
+// Sending fails once the miner thread exited, and then there is nothing left to control.
 impl Handle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Exit);
     }
 
+    /// Start mining, or change the lambda if the miner is already running.
     pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+        let _ = self.control_chan.send(ControlSignal::Start(lambda));
+    }
+
+    pub fn pause(&self) {
+        let _ = self.control_chan.send(ControlSignal::Pause);
+    }
+
+    /// Start again with the lambda of the last start. Does nothing if the miner was never started.
+    pub fn resume(&self) {
+        let _ = self.control_chan.send(ControlSignal::Resume);
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Update);
+    }
+
+    /// Mine `n` blocks without waiting between them, paused or not, and return their hashes.
+    /// Only quick where every hash meets the difficulty, as on regtest.
+    pub fn generate(&self, n: u64) -> Vec<H256> {
+        let (sender, receiver) = unbounded();
+        if n == 0 || self.control_chan.send(ControlSignal::Generate(n, sender)).is_err() {
+            return Vec::new();
+        }
+        // ends early if the miner exits
+        receiver.iter().collect()
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
     }
 }
 
 impl Context {
-    pub fn start(mut self) {
-        thread::Builder::new()
+    /// Start the miner thread, which exits on `Handle::exit`.
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let thread = thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
                 self.miner_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        thread
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal, parent: &mut H256) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Miner shutting down");
+                self.set_state(OperatingState::ShutDown);
+            }
+            ControlSignal::Start(i) => {
+                info!("Miner starting in continuous mode with lambda {}", i);
+                self.set_state(OperatingState::Run(i));
+            }
+            ControlSignal::Pause => {
+                if let OperatingState::Run(_) = self.operating_state {
+                    info!("Miner paused");
+                    self.set_state(OperatingState::Paused);
+                }
+            }
+            ControlSignal::Resume => {
+                let lambda = self.status.lock().unwrap().lambda;
+                if let (OperatingState::Paused, Some(i)) = (&self.operating_state, lambda) {
+                    info!("Miner resuming with lambda {}", i);
+                    self.set_state(OperatingState::Run(i));
+                }
+            }
+            ControlSignal::Update => {
+                // also while paused, so that mining resumes on the current tip
+                *parent = self.blockchain.lock().unwrap().tip();
+            }
+            ControlSignal::Generate(n, sender) => {
+                info!("Miner generating {} blocks", n);
+                *parent = self.blockchain.lock().unwrap().tip();
+                self.generating = Some((n, sender));
+            }
+        }
+    }
+
+    fn set_state(&mut self, operating_state: OperatingState) {
+        let mut status = self.status.lock().unwrap();
+        match operating_state {
+            OperatingState::Paused => status.state = State::Paused,
+            OperatingState::Run(i) => {
+                status.state = State::Running;
+                status.lambda = Some(i);
+            }
+            OperatingState::ShutDown => status.state = State::Stopped,
+        }
+        if status.state != State::Running {
+            status.hashes += self.uncounted_hashes;
+            self.uncounted_hashes = 0;
+            status.hash_rate = 0.0;
+        }
+        self.operating_state = operating_state;
+    }
+
+    /// Pick the transactions of the mempool that apply on the state after `parent`, and compute
+    /// the roots committing to them and to the resulting state.
+    fn build_template(&self, parent: H256) -> Template {
+        // every block has the difficulty of the genesis
+        let difficulty: H256 = self.blockchain.lock().unwrap().blocks[&parent].header.difficulty;
+
+        let mempool_guard = self.mempool.lock().unwrap();
+        let mempool_len = mempool_guard.len();
+        let num_transactions = std::cmp::min(mempool_guard.len(), self.block_tx_limit);
+        let mut txs_data: Vec<SignedTransaction> = Vec::new(); 
+        let mut idx = 0; 
+        let mut curr_state = self.states.lock().unwrap()[&parent].clone();
+
+        for k in mempool_guard.keys() {
+            let elem = mempool_guard[k].clone();
+            if idx < num_transactions {
+                if curr_state.contains_key(&elem.t.sender) {
+                    let (s_nonce, bal) = curr_state[&elem.t.sender];
+                    if s_nonce + 1 == elem.t.acc_nonce && bal >= elem.t.value {
+                        curr_state.insert(
+                            elem.t.sender, (s_nonce + 1, bal - elem.t.value)
+                        );
+
+                        if curr_state.contains_key(&elem.t.receiver) {
+                            let (r_nonce, bal) = curr_state[&elem.t.receiver];
+                            curr_state.insert(elem.t.receiver, (r_nonce + 1, bal + elem.t.value));
+                        } else {
+                            curr_state.insert(
+                                elem.t.receiver, (1, elem.t.value)
+                            );
+                        }
+                        txs_data.push(elem);
+                        idx += 1;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+        std::mem::drop(mempool_guard);
+
+        // the header commits to the transactions, so peers can rebuild compact blocks,
+        // and to the resulting state, so that it can be served as a verifiable snapshot
+        Template {
+            parent,
+            mempool_len,
+            built: Instant::now(),
+            difficulty,
+            merkle_root: MerkleTree::new(&txs_data).root(),
+            state_root: curr_state.root(),
+            txs: txs_data,
+            state: curr_state,
+        }
     }
 
     fn miner_loop(&mut self) {
@@ -114,25 +312,20 @@ impl Context {
             parent = self.blockchain.lock().unwrap().tip();
         }
         println!("Initial Parent: {:?}", parent);
+        let mut hashes_since = Instant::now();
+        let mut template: Option<Template> = None;
         
         loop {
             // check and react to control signals
             match self.operating_state {
-                OperatingState::Paused => {
+                OperatingState::Paused if self.generating.is_none() => {
+                    // the nonces tried while generating are counted before waiting
+                    self.status.lock().unwrap().hashes += self.uncounted_hashes;
+                    self.uncounted_hashes = 0;
                     let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
+                    self.handle_signal(signal, &mut parent);
+                    // the time spent paused does not count in the hash rate
+                    hashes_since = Instant::now();
                     continue;
                 }
                 OperatingState::ShutDown => {
@@ -140,21 +333,7 @@ impl Context {
                 }
                 _ => match self.control_chan.try_recv() {
                     Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                let blockchain_guard = self.blockchain.lock().unwrap();
-                                parent = blockchain_guard.tip();
-                                std::mem::drop(blockchain_guard);
-                            }
-                        };
+                        self.handle_signal(signal, &mut parent);
                     }
                     Err(TryRecvError::Empty) => {}
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
@@ -170,77 +349,60 @@ impl Context {
             // Rand Nonce
             let mut rng = rand::thread_rng();
             let rand_nonce: u32 = rng.gen();   // Generate random value for nonce
-            
-            let tx_vec: Vec<SignedTransaction> = Vec::new();
-            let merkle_tree = MerkleTree::new(&tx_vec);
-            let root = merkle_tree.root();
-
-            // let blockchain_guard = self.blockchain.lock().unwrap();
-            //println!("Parent: {:?} BLOCKS ACQUIRED VIA LOCK: {:?}", parent, blockchain_guard.blocks);
-            // let difficulty: H256 = blockchain_guard.blocks[&parent].header.difficulty;
-            let difficulty:H256 = [0u8,0u8,255u8,255u8,1u8,1u8,0u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8,0u8].into();
-            // std::mem::drop(blockchain_guard);
 
+            let mempool_len = self.mempool.lock().unwrap().len();
+            let stale = match &template {
+                Some(t) => t.parent != parent || t.mempool_len != mempool_len || t.built.elapsed() >= TEMPLATE_REFRESH,
+                None => true,
+            };
+            if stale {
+                template = Some(self.build_template(parent));
+            }
+            let t = template.as_ref().unwrap();
             let header = Header {
-                parent: parent,
-                timestamp: timestamp,
-                difficulty: difficulty,
-                merkle_root: root,
+                parent,
+                timestamp,
+                difficulty: t.difficulty,
+                merkle_root: t.merkle_root,
+                state_root: t.state_root,
                 nonce: rand_nonce                
             };
-            let mempool_guard = self.mempool.lock().unwrap();
-            let num_transactions = std::cmp::min(mempool_guard.len(), 30);
-            //println!("MEMPOOL LEN: {:?}", mempool_guard.len());
-            let mut txs_data: Vec<SignedTransaction> = Vec::new(); 
-            let mut idx = 0; 
-            let mut curr_state = self.states.lock().unwrap()[&parent].clone();
-
-            for k in mempool_guard.keys() {
-                let elem = mempool_guard[k].clone();
-                if idx < num_transactions {
-                    if curr_state.contains_key(&elem.t.sender) {
-                        let (s_nonce, bal) = curr_state[&elem.t.sender];
-                        //println!("ACC_NONCE: {:?} & VAL: {:?}", elem.t.acc_nonce, elem.t.value);
-                        if s_nonce + 1 == elem.t.acc_nonce && bal >= elem.t.value {
-                            //println!("s_nonce + 1 == elem.t.acc_nonce && bal >= elem.t.value ");
-                            curr_state.insert(
-                                elem.t.sender, (s_nonce + 1, bal - elem.t.value)
-                            );
-
-                            if curr_state.contains_key(&elem.t.receiver) {
-                                let (r_nonce, bal) = curr_state[&elem.t.receiver];
-                                curr_state.insert(elem.t.receiver, (r_nonce + 1, bal + elem.t.value));
-                            } else {
-                                curr_state.insert(
-                                    elem.t.receiver, (1, elem.t.value)
-                                );
-                            }
-                            txs_data.push(elem);
-                            idx += 1;
-                        }
-                    } else {
-                        // Do nothing
-                    }
-                } else {
-                    break;
-                }
-            }
-            //println!("TXS DATA: {:?}", txs_data);
-            std::mem::drop(mempool_guard);
+            let difficulty = t.difficulty;
             
-            let block = Block{header: header, content: Content {data: txs_data}, height: 0}; // Content should be the transactions in the mempool.
+            let block = Block{header, content: Content {data: t.txs.clone()}, height: 0}; // Content should be the transactions in the mempool.
             
             
+            self.uncounted_hashes += 1;
+            if hashes_since.elapsed() >= HASH_RATE_INTERVAL {
+                let mut status = self.status.lock().unwrap();
+                status.hashes += self.uncounted_hashes;
+                status.hash_rate = self.uncounted_hashes as f64 / hashes_since.elapsed().as_secs_f64();
+                self.uncounted_hashes = 0;
+                hashes_since = Instant::now();
+            }
+
             // TODO for student: if block mining finished, you can have something like: self.finished_block_chan.send(block.clone()).expect("Send finished block error");
             if block.hash() <= difficulty {
                 //println!("PRINT STATE UPDATE\n");
                 let mut blockchain_guard = self.blockchain.lock().unwrap();
+                let curr_state = template.take().unwrap().state;
+                // the mined transactions leave the mempool
+                blockchain_guard.insert_with_mempool(&block, &curr_state, &mut self.mempool.lock().unwrap());
                 self.states.lock().unwrap().insert(block.hash(), curr_state);
-                blockchain_guard.insert(&block);
                 self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+                self.status.lock().unwrap().blocks_mined += 1;
                 println!("Parent: {:?}. B-Hash: {:?}, TX-Data Len: {:?}", parent, block.hash(), block.content.data.len()); // PRINT PARENT
                 parent = blockchain_guard.tip();
                 std::mem::drop(blockchain_guard);
+                if let Some((n, sender)) = self.generating.take() {
+                    let _ = sender.send(block.hash());
+                    if n > 1 {
+                        self.generating = Some((n - 1, sender));
+                    }
+                }
+            }
+            if self.generating.is_some() {
+                continue;
             }
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
@@ -258,6 +420,10 @@ impl Context {
 mod test {
     use ntest::timeout;
     use crate::types::hash::Hashable;
+    use super::State;
+    use crate::blockchain::Blockchain;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     #[timeout(60000)]
@@ -272,6 +438,69 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_lifecycle() {
+        let blockchain = Blockchain::new();
+        let mut states = HashMap::new();
+        states.insert(blockchain.tip(), Default::default());
+        let (miner_ctx, miner_handle, _finished_block_chan) = super::new(
+            &Arc::new(Mutex::new(blockchain)),
+            &Arc::new(Mutex::new(HashMap::new())),
+            &Arc::new(Mutex::new(states)),
+            &crate::config::MiningConfig::default(),
+        );
+        miner_ctx.start();
+        let wait_for = |state: State| {
+            while miner_handle.status().state != state {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+        // nothing to resume before the first start
+        miner_handle.resume();
+        miner_handle.pause();
+        assert_eq!(miner_handle.status().lambda, None);
+        miner_handle.start(1000);
+        wait_for(State::Running);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        miner_handle.pause();
+        wait_for(State::Paused);
+        // the nonces tried since the last measure are counted on pausing
+        assert!(miner_handle.status().hashes > 0);
+        miner_handle.resume();
+        wait_for(State::Running);
+        assert_eq!(miner_handle.status().lambda, Some(1000));
+        miner_handle.exit();
+        wait_for(State::Stopped);
+        // the thread is gone, the handle does not panic
+        miner_handle.start(0);
+        assert_eq!(miner_handle.status().state, State::Stopped);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn generate_while_paused() {
+        let regtest = &crate::blockchain::params::REGTEST;
+        let blockchain = Blockchain::with_genesis(regtest.difficulty.into(), regtest.genesis_timestamp as u128);
+        let genesis = blockchain.tip();
+        let mut states = HashMap::new();
+        states.insert(genesis, Default::default());
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (miner_ctx, miner_handle, _finished_block_chan) = super::new(
+            &blockchain,
+            &Arc::new(Mutex::new(HashMap::new())),
+            &Arc::new(Mutex::new(states)),
+            &crate::config::MiningConfig::default(),
+        );
+        miner_ctx.start();
+        let hashes = miner_handle.generate(3);
+        assert_eq!(hashes.len(), 3);
+        let blockchain = blockchain.lock().unwrap();
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis, hashes[0], hashes[1], hashes[2]]);
+        assert_eq!(miner_handle.status().state, State::Paused);
+        assert_eq!(miner_handle.generate(0), vec![]);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -6,7 +6,6 @@ use crate::network::server::Handle as ServerHandle;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use crate::types::hash::{H256, Hashable};
-use crate::network::message::Message;
 
 #[derive(Clone)]
 pub struct Worker {
@@ -28,23 +27,25 @@ impl Worker {
         }
     }
 
-    pub fn start(self) {
-        thread::Builder::new()
+    /// Start the worker thread. It exits after the miner, once it relayed every mined block.
+    pub fn start(self) -> thread::JoinHandle<()> {
+        let thread = thread::Builder::new()
             .name("miner-worker".to_string())
             .spawn(move || {
                 self.worker_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
+        thread
     }
 
     fn worker_loop(&self) {
-        loop {
-            let _block = self.finished_block_chan.recv().expect("Receive finished block error");
+        // ends when the miner exited and its blocks are all handled
+        while let Ok(_block) = self.finished_block_chan.recv() {
             // TODO for student: insert this finished block to blockchain, and broadcast this block hash
             self.blockchain.lock().unwrap().insert(&_block);
 
-            self.server.broadcast(Message::NewBlockHashes(vec![_block.hash()]));
+            self.server.relay_blocks(vec![_block]);
             //let ll: Vec<H256> = self.blockchain.lock().unwrap().blocks.keys().cloned().collect::<Vec<H256>>();
             //println!("{:?} BROADCAST NEW BLOCKS", ll);
             //self.server.broadcast(Message::NewBlockHashes(ll));
@@ -0,0 +1,114 @@
+use serde::{Serialize, Deserialize};
+use crate::types::address::Address;
+use crate::types::transaction::SignedTransaction;
+use ring::digest;
+
+/// Filters with more bytes are refused, so that a peer cannot make us hash without bound.
+pub const MAX_FILTER_BYTES: usize = 36000;
+/// Filters with more hash functions are refused.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// A Bloom filter over addresses, loaded by a light client so that we only relay the
+/// transactions that pay or are paid by one of its addresses. It matches every inserted
+/// address, and other addresses with a small probability, which hides the exact set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+    /// Mixed into the hashes, so that the false positives differ between filters
+    tweak: u32,
+}
+
+impl BloomFilter {
+    /// An empty filter sized so that, with `elements` addresses inserted, another address
+    /// matches with probability `fp_rate`.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let elements = elements.max(1) as f64;
+        let num_bits = -elements * fp_rate.ln() / (ln2 * ln2);
+        let num_bytes = ((num_bits / 8.0).ceil() as usize).clamp(1, MAX_FILTER_BYTES);
+        let num_hashes = ((num_bytes * 8) as f64 / elements * ln2).round() as u32;
+        Self {
+            bits: vec![0; num_bytes],
+            num_hashes: num_hashes.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+        }
+    }
+
+    /// Whether the filter is within the limits we accept from a peer.
+    pub fn is_valid(&self) -> bool {
+        !self.bits.is_empty()
+            && self.bits.len() <= MAX_FILTER_BYTES
+            && self.num_hashes >= 1
+            && self.num_hashes <= MAX_HASH_FUNCS
+    }
+
+    pub fn insert(&mut self, data: &[u8]) {
+        for i in 0..self.num_hashes {
+            let bit = self.bit_index(i, data);
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn contains(&self, data: &[u8]) -> bool {
+        (0..self.num_hashes).all(|i| {
+            let bit = self.bit_index(i, data);
+            self.bits[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    pub fn insert_address(&mut self, address: &Address) {
+        self.insert(address.as_ref());
+    }
+
+    /// Whether the sender or the receiver of the transaction is in the filter.
+    pub fn matches(&self, tx: &SignedTransaction) -> bool {
+        self.contains(tx.t.sender.as_ref()) || self.contains(tx.t.receiver.as_ref())
+    }
+
+    fn bit_index(&self, i: u32, data: &[u8]) -> usize {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&self.tweak.to_le_bytes());
+        ctx.update(&i.to_le_bytes());
+        ctx.update(data);
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&ctx.finish().as_ref()[0..8]);
+        (u64::from_le_bytes(prefix) % (self.bits.len() as u64 * 8)) as usize
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn random_address() -> Address {
+        rand::random::<[u8; 20]>().into()
+    }
+
+    #[test]
+    fn matches_inserted_addresses() {
+        let addresses: Vec<Address> = (0..20).map(|_| random_address()).collect();
+        let mut filter = BloomFilter::new(addresses.len(), 0.001, 7);
+        for a in addresses.iter() {
+            filter.insert_address(a);
+        }
+        assert!(filter.is_valid());
+        assert!(addresses.iter().all(|a| filter.contains(a.as_ref())));
+        let false_positives = (0..10000).filter(|_| filter.contains(random_address().as_ref())).count();
+        assert!(false_positives < 50);
+    }
+
+    #[test]
+    fn limits() {
+        let huge = BloomFilter::new(1_000_000, 0.000001, 0);
+        assert_eq!(huge.bits.len(), MAX_FILTER_BYTES);
+        assert!(huge.is_valid());
+        let mut bad = BloomFilter::new(10, 0.01, 0);
+        bad.num_hashes = MAX_HASH_FUNCS + 1;
+        assert!(!bad.is_valid());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// How many inbound peers with the best score are never evicted.
+const PROTECT_BY_SCORE: usize = 4;
+/// How many inbound peers with the lowest round trip time are never evicted.
+const PROTECT_BY_LATENCY: usize = 4;
+
+/// What we know about an inbound peer when deciding whom to evict.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub connected: Duration,
+    pub rtt: Option<Duration>,
+    pub score: i64,
+}
+
+/// The network a peer belongs to: the /16 of an IPv4 address or the /32 of an IPv6 address.
+/// Many peers in one group are likely controlled by the same party.
+pub fn address_group(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v4) => vec![4, v4.octets()[0], v4.octets()[1]],
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => vec![4, v4.octets()[0], v4.octets()[1]],
+            None => {
+                let mut group = vec![6];
+                group.extend_from_slice(&v6.octets()[0..4]);
+                group
+            }
+        },
+    }
+}
+
+/// Pick the inbound peer to drop so that a new one can connect. The best scoring and fastest
+/// peers are protected, then we evict the most recent connection of the address group holding
+/// the most peers. Returns `None` if every peer is protected.
+pub fn select_victim(candidates: &[Candidate]) -> Option<SocketAddr> {
+    let mut remaining: Vec<Candidate> = candidates.to_vec();
+
+    remaining.sort_by_key(|c| std::cmp::Reverse(c.score));
+    remaining.drain(0..PROTECT_BY_SCORE.min(remaining.len()));
+
+    // peers that never answered a ping sort last
+    remaining.sort_by_key(|c| c.rtt.unwrap_or(Duration::MAX));
+    remaining.drain(0..PROTECT_BY_LATENCY.min(remaining.len()));
+
+    let mut groups: HashMap<Vec<u8>, Vec<Candidate>> = HashMap::new();
+    for c in remaining {
+        groups.entry(address_group(&c.addr.ip())).or_default().push(c);
+    }
+    let largest = groups.into_values().max_by(|a, b| {
+        // on a tie, prefer the group with the most recent connection
+        let youngest = |g: &Vec<Candidate>| g.iter().map(|c| c.connected).min();
+        a.len().cmp(&b.len()).then_with(|| youngest(b).cmp(&youngest(a)))
+    })?;
+    largest.into_iter().min_by_key(|c| c.connected).map(|c| c.addr)
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn candidate(addr: &str, connected: u64, rtt: Option<u64>, score: i64) -> Candidate {
+        Candidate {
+            addr: addr.parse().unwrap(),
+            connected: Duration::from_secs(connected),
+            rtt: rtt.map(Duration::from_millis),
+            score,
+        }
+    }
+
+    #[test]
+    fn protect_everyone_when_few() {
+        let candidates = vec![
+            candidate("10.0.0.1:6000", 10, Some(5), 0),
+            candidate("10.0.0.2:6000", 10, Some(5), 0),
+        ];
+        assert_eq!(select_victim(&candidates), None);
+    }
+
+    #[test]
+    fn evict_youngest_of_largest_group() {
+        let mut candidates = Vec::new();
+        // well behaved and fast peers, all protected
+        for i in 0..8 {
+            candidates.push(candidate(&format!("20.{}.0.1:6000", i), 100, Some(1), 10));
+        }
+        candidates.push(candidate("30.1.0.1:6000", 50, None, 0));
+        candidates.push(candidate("30.1.0.2:6000", 5, None, 0));
+        candidates.push(candidate("30.1.0.3:6000", 60, None, 0));
+        candidates.push(candidate("40.1.0.1:6000", 1, None, 0));
+        assert_eq!(select_victim(&candidates), Some("30.1.0.2:6000".parse().unwrap()));
+    }
+
+    #[test]
+    fn group_ipv4_by_slash_16() {
+        assert_eq!(
+            address_group(&"1.2.3.4".parse().unwrap()),
+            address_group(&"1.2.200.7".parse().unwrap())
+        );
+        assert_ne!(
+            address_group(&"1.2.3.4".parse().unwrap()),
+            address_group(&"1.3.3.4".parse().unwrap())
+        );
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
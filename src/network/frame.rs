@@ -0,0 +1,204 @@
+use super::message::Message;
+use futures::io::{AsyncRead, AsyncReadExt};
+use ring::digest;
+use std::convert::TryInto;
+
+/// Magic bytes at the start of every frame on the main network. Each network has its own, see
+/// `blockchain::params`.
+pub const MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+/// Size of the frame header: magic (4) | command (1) | length (4) | checksum (4).
+pub const HEADER_SIZE: usize = 13;
+
+/// Default upper bound on the payload size of a single frame.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 32 * 1024 * 1024;
+
+/// The parsed header of a frame, read before the payload is allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub command: u8,
+    pub length: u32,
+    pub checksum: [u8; 4],
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    BadMagic([u8; 4]),
+    UnknownCommand(u8),
+    Oversized { length: u32, max: u32 },
+    Truncated,
+    BadChecksum,
+    CommandMismatch { header: u8, payload: u8 },
+    Decode(bincode::Error),
+    Decrypt,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrameError::BadMagic(m) => write!(f, "bad network magic {}", hex::encode(m)),
+            FrameError::UnknownCommand(c) => write!(f, "unknown command byte {}", c),
+            FrameError::Oversized { length, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {} bytes", length, max)
+            }
+            FrameError::Truncated => write!(f, "frame length does not match its header"),
+            FrameError::BadChecksum => write!(f, "payload checksum mismatch"),
+            FrameError::CommandMismatch { header, payload } => write!(
+                f,
+                "header announces command {} but payload decodes to {}",
+                header, payload
+            ),
+            FrameError::Decode(e) => write!(f, "error decoding payload: {}", e),
+            FrameError::Decrypt => write!(f, "frame failed authentication"),
+            FrameError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// First four bytes of the double SHA256 of the payload.
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let h = digest::digest(&digest::SHA256, digest::digest(&digest::SHA256, payload).as_ref());
+    h.as_ref()[0..4].try_into().unwrap()
+}
+
+/// Serialize a message into a complete frame, header included.
+pub fn encode(msg: &Message, magic: [u8; 4]) -> Vec<u8> {
+    let payload = bincode::serialize(msg).unwrap();
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&magic);
+    frame.push(msg.command());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Parse and validate a frame header. The length is checked against `max_size` here so that
+/// the reader never allocates a buffer for an oversized frame.
+pub fn decode_header(buf: &[u8; HEADER_SIZE], magic: [u8; 4], max_size: u32) -> Result<FrameHeader, FrameError> {
+    let found: [u8; 4] = buf[0..4].try_into().unwrap();
+    if found != magic {
+        return Err(FrameError::BadMagic(found));
+    }
+    let command = buf[4];
+    if command >= Message::NUM_COMMANDS {
+        return Err(FrameError::UnknownCommand(command));
+    }
+    let length = u32::from_be_bytes(buf[5..9].try_into().unwrap());
+    if length > max_size {
+        return Err(FrameError::Oversized { length, max: max_size });
+    }
+    let checksum = buf[9..13].try_into().unwrap();
+    Ok(FrameHeader { command, length, checksum })
+}
+
+/// Verify the payload against its header and decode the message.
+pub fn decode_payload(header: &FrameHeader, payload: &[u8]) -> Result<Message, FrameError> {
+    if checksum(payload) != header.checksum {
+        return Err(FrameError::BadChecksum);
+    }
+    // same encoding as `bincode::serialize`, but bounded by the announced length
+    let msg: Message = bincode::config()
+        .limit(header.length as u64)
+        .deserialize(payload)
+        .map_err(FrameError::Decode)?;
+    if msg.command() != header.command {
+        return Err(FrameError::CommandMismatch { header: header.command, payload: msg.command() });
+    }
+    Ok(msg)
+}
+
+/// Decode a complete frame held in memory.
+pub fn decode(frame: &[u8], magic: [u8; 4], max_size: u32) -> Result<Message, FrameError> {
+    if frame.len() < HEADER_SIZE {
+        return Err(FrameError::Truncated);
+    }
+    let header = decode_header(frame[0..HEADER_SIZE].try_into().unwrap(), magic, max_size)?;
+    let payload = &frame[HEADER_SIZE..];
+    if payload.len() != header.length as usize {
+        return Err(FrameError::Truncated);
+    }
+    decode_payload(&header, payload)
+}
+
+/// Read one frame from the stream, returning its message and its size. `buffer` is reused
+/// across calls to hold the payload.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    magic: [u8; 4],
+    max_size: u32,
+) -> Result<(Message, usize), FrameError> {
+    let mut header_buffer = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header_buffer).await.map_err(FrameError::Io)?;
+    // validate the header before allocating
+    let header = decode_header(&header_buffer, magic, max_size)?;
+    let msg_size = header.length as usize;
+    if buffer.len() < msg_size {
+        buffer.resize(msg_size, 0);
+    }
+    reader.read_exact(&mut buffer[0..msg_size]).await.map_err(FrameError::Io)?;
+    let msg = decode_payload(&header, &buffer[0..msg_size])?;
+    Ok((msg, HEADER_SIZE + msg_size))
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn roundtrip() {
+        let msg = Message::NewBlockHashes(vec![generate_random_hash(), generate_random_hash()]);
+        let frame = encode(&msg, MAGIC);
+        match decode(&frame, MAGIC, DEFAULT_MAX_FRAME_SIZE).unwrap() {
+            Message::NewBlockHashes(v) => assert_eq!(v.len(), 2),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let mut frame = encode(&Message::Ping("hi".to_string()), MAGIC);
+        frame[0] ^= 0xff;
+        assert!(matches!(decode(&frame, MAGIC, DEFAULT_MAX_FRAME_SIZE), Err(FrameError::BadMagic(_))));
+    }
+
+    #[test]
+    fn reject_other_network() {
+        let frame = encode(&Message::Ping("hi".to_string()), [1, 2, 3, 4]);
+        assert!(decode(&frame, [1, 2, 3, 4], DEFAULT_MAX_FRAME_SIZE).is_ok());
+        assert!(matches!(decode(&frame, MAGIC, DEFAULT_MAX_FRAME_SIZE), Err(FrameError::BadMagic(_))));
+    }
+
+    #[test]
+    fn reject_oversized() {
+        let frame = encode(&Message::GetBlocks(vec![generate_random_hash(); 10]), MAGIC);
+        assert!(matches!(decode(&frame, MAGIC, 16), Err(FrameError::Oversized { .. })));
+    }
+
+    #[test]
+    fn reject_corrupted_payload() {
+        let mut frame = encode(&Message::Pong("hello".to_string()), MAGIC);
+        let last = frame.len() - 1;
+        frame[last] ^= 0x01;
+        assert!(matches!(decode(&frame, MAGIC, DEFAULT_MAX_FRAME_SIZE), Err(FrameError::BadChecksum)));
+    }
+
+    #[test]
+    fn reject_command_mismatch() {
+        let mut frame = encode(&Message::Ping("hi".to_string()), MAGIC);
+        frame[4] = Message::Pong(String::new()).command();
+        assert!(matches!(
+            decode(&frame, MAGIC, DEFAULT_MAX_FRAME_SIZE),
+            Err(FrameError::CommandMismatch { .. })
+        ));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,238 @@
+use crate::types::compact_block::PartialBlock;
+use crate::types::hash::H256;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How many block hashes we remember per peer.
+pub const MAX_KNOWN_BLOCKS: usize = 1024;
+/// How many transaction hashes we remember per peer.
+pub const MAX_KNOWN_TRANSACTIONS: usize = 8192;
+/// How long we wait for a peer to answer a `GetBlocks`/`GetTransactions` before asking another.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many compact blocks may wait for their missing transactions at once.
+pub const MAX_PENDING_BLOCKS: usize = 64;
+
+/// A set of hashes bounded in size. When full, the least recently inserted or touched hash is
+/// forgotten first.
+#[derive(Debug)]
+pub struct KnownSet {
+    capacity: usize,
+    /// Each known hash with the generation of its last insert
+    set: HashMap<H256, u64>,
+    /// Hashes in insertion order, an entry whose generation is not the one in `set` is stale
+    order: VecDeque<(H256, u64)>,
+    generation: u64,
+}
+
+impl KnownSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            set: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    /// Record a hash, returns true if it was not known before.
+    pub fn insert(&mut self, hash: H256) -> bool {
+        self.generation += 1;
+        let generation = self.generation;
+        // a touched hash is pushed again, its older queue entry becomes stale
+        let new = self.set.insert(hash, generation).is_none();
+        self.order.push_back((hash, generation));
+        if new && self.set.len() > self.capacity {
+            while let Some((old, g)) = self.order.pop_front() {
+                if self.set.get(&old) == Some(&g) {
+                    self.set.remove(&old);
+                    break;
+                }
+            }
+        }
+        if self.order.len() > 2 * self.capacity {
+            let set = &self.set;
+            self.order.retain(|(h, g)| set.get(h) == Some(g));
+        }
+        new
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.set.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+}
+
+/// Everything one peer is known to have, either because it told us or because we sent it.
+#[derive(Debug)]
+pub struct PeerInventory {
+    pub blocks: KnownSet,
+    pub transactions: KnownSet,
+}
+
+impl Default for PeerInventory {
+    fn default() -> Self {
+        Self {
+            blocks: KnownSet::new(MAX_KNOWN_BLOCKS),
+            transactions: KnownSet::new(MAX_KNOWN_TRANSACTIONS),
+        }
+    }
+}
+
+impl PeerInventory {
+    /// Keep only the hashes this peer does not know yet, and mark them as known.
+    pub fn filter_unknown_blocks(&mut self, hashes: &[H256]) -> Vec<H256> {
+        hashes.iter().filter(|h| self.blocks.insert(**h)).cloned().collect()
+    }
+
+    /// Keep only the hashes this peer does not know yet, and mark them as known.
+    pub fn filter_unknown_transactions(&mut self, hashes: &[H256]) -> Vec<H256> {
+        hashes.iter().filter(|h| self.transactions.insert(**h)).cloned().collect()
+    }
+}
+
+/// Hashes we already asked some peer for, so that several workers do not request the same
+/// block or transaction from several peers at once.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    requested: HashMap<H256, Instant>,
+}
+
+impl InFlight {
+    /// Returns the hashes that are not already being fetched, and marks them as in flight.
+    pub fn claim(&mut self, hashes: Vec<H256>) -> Vec<H256> {
+        let now = Instant::now();
+        self.requested.retain(|_, t| now.duration_since(*t) < REQUEST_TIMEOUT);
+        let mut claimed = Vec::new();
+        for h in hashes {
+            if let Entry::Vacant(e) = self.requested.entry(h) {
+                e.insert(now);
+                claimed.push(h);
+            }
+        }
+        claimed
+    }
+
+    /// The data for this hash arrived, it may be requested again if needed.
+    pub fn complete(&mut self, hash: &H256) {
+        self.requested.remove(hash);
+    }
+}
+
+/// Compact blocks waiting for the transactions we asked with `GetBlockTxn`. A peer that never
+/// answers cannot grow it: blocks are forgotten after `REQUEST_TIMEOUT`, and the oldest one when
+/// `MAX_PENDING_BLOCKS` are waiting.
+#[derive(Debug, Default)]
+pub struct PendingBlocks {
+    pending: HashMap<H256, (PartialBlock, Instant)>,
+    /// The pending hashes, oldest first
+    order: VecDeque<H256>,
+}
+
+impl PendingBlocks {
+    pub fn insert(&mut self, hash: H256, partial: PartialBlock) {
+        let now = Instant::now();
+        self.take(&hash);
+        while let Some(oldest) = self.order.front() {
+            let expired = now.duration_since(self.pending[oldest].1) >= REQUEST_TIMEOUT;
+            if !expired && self.order.len() < MAX_PENDING_BLOCKS {
+                break;
+            }
+            self.pending.remove(oldest);
+            self.order.pop_front();
+        }
+        self.pending.insert(hash, (partial, now));
+        self.order.push_back(hash);
+    }
+
+    /// The block waiting for these transactions, if it did not expire.
+    pub fn take(&mut self, hash: &H256) -> Option<PartialBlock> {
+        let (partial, inserted) = self.pending.remove(hash)?;
+        self.order.retain(|h| h != hash);
+        if inserted.elapsed() < REQUEST_TIMEOUT {
+            Some(partial)
+        } else {
+            None
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn known_set_evicts_oldest() {
+        let mut set = KnownSet::new(2);
+        let (a, b, c) = (generate_random_hash(), generate_random_hash(), generate_random_hash());
+        assert!(set.insert(a));
+        assert!(set.insert(b));
+        assert!(!set.insert(a));
+        assert!(set.insert(c));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&a));
+        assert!(!set.contains(&b));
+    }
+
+    #[test]
+    fn known_set_touch_stays_bounded() {
+        let mut set = KnownSet::new(2);
+        let (a, b, c) = (generate_random_hash(), generate_random_hash(), generate_random_hash());
+        assert!(set.insert(a));
+        assert!(set.insert(b));
+        for _ in 0..10 {
+            assert!(!set.insert(a));
+        }
+        assert!(set.order.len() <= 4);
+        assert!(set.insert(c));
+        assert!(set.contains(&a) && set.contains(&c) && !set.contains(&b));
+        assert!(!set.insert(c));
+        assert!(set.insert(b));
+        assert!(!set.contains(&a));
+    }
+
+    #[test]
+    fn filter_announced_once() {
+        let mut inv = PeerInventory::default();
+        let h = generate_random_hash();
+        assert_eq!(inv.filter_unknown_blocks(&[h]), vec![h]);
+        assert!(inv.filter_unknown_blocks(&[h]).is_empty());
+    }
+
+    #[test]
+    fn in_flight_claimed_once() {
+        let mut in_flight = InFlight::default();
+        let h = generate_random_hash();
+        assert_eq!(in_flight.claim(vec![h]), vec![h]);
+        assert!(in_flight.claim(vec![h]).is_empty());
+        in_flight.complete(&h);
+        assert_eq!(in_flight.claim(vec![h]), vec![h]);
+    }
+
+    #[test]
+    fn pending_blocks_bounded() {
+        let mut pending = PendingBlocks::default();
+        let partial = |hash: &H256| PartialBlock { header: generate_random_block(hash).header, txs: vec![None] };
+        let hashes: Vec<H256> = (0..=MAX_PENDING_BLOCKS).map(|_| generate_random_hash()).collect();
+        for h in hashes.iter() {
+            pending.insert(*h, partial(h));
+        }
+        assert!(pending.take(&hashes[0]).is_none());
+        assert!(pending.take(&hashes[1]).is_some());
+        assert!(pending.take(&hashes[1]).is_none());
+        assert_eq!(pending.order.len(), MAX_PENDING_BLOCKS - 1);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
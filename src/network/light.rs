@@ -0,0 +1,486 @@
+use serde::{Serialize, Deserialize};
+use super::bloom::BloomFilter;
+use super::message::Message;
+use super::peer;
+use crate::blockchain::header_chain::{HeaderChain, HeaderError};
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::{self, MerkleTree};
+use crate::types::address::Address;
+use crate::types::transaction::{SignedTransaction, st_verify};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, info, warn};
+
+/// Most headers sent in one `Headers` message. A full batch means the peer has more.
+pub const MAX_HEADERS: usize = 2000;
+/// Probability that the Bloom filter of a light client matches an address it does not watch
+const FILTER_FP_RATE: f64 = 0.001;
+
+/// Proof that a transaction is in the block with the given hash, checked against the Merkle
+/// root of the block header.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxProof {
+    pub block: H256,
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Number of transactions in the block
+    pub leaf_size: usize,
+    pub transaction: SignedTransaction,
+    pub proof: Vec<H256>,
+}
+
+impl TxProof {
+    /// Prove the transaction at `index` of a block, or `None` if there is no such transaction.
+    pub fn build(block: &Block, index: usize) -> Option<Self> {
+        let transaction = block.content.data.get(index)?.clone();
+        let proof = MerkleTree::new(&block.content.data).proof(index);
+        Some(Self {
+            block: block.hash(),
+            index,
+            leaf_size: block.content.data.len(),
+            transaction,
+            proof,
+        })
+    }
+
+    pub fn verify(&self, header: &Header) -> bool {
+        header.hash() == self.block
+            && merkle::verify(&header.merkle_root, &self.transaction.hash(), &self.proof, self.index, self.leaf_size)
+    }
+}
+
+/// A block relayed to a peer that loaded a Bloom filter: the header, the transactions that
+/// match the filter, and one Merkle multiproof of all of them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilteredBlock {
+    pub header: Header,
+    /// Number of transactions in the block
+    pub leaf_size: usize,
+    /// The matching transactions with their position in the block
+    pub transactions: Vec<(usize, SignedTransaction)>,
+    pub proof: Vec<H256>,
+}
+
+impl FilteredBlock {
+    pub fn new(block: &Block, filter: &BloomFilter) -> Self {
+        let transactions: Vec<(usize, SignedTransaction)> = block
+            .content
+            .data
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| filter.matches(tx))
+            .map(|(i, tx)| (i, tx.clone()))
+            .collect();
+        let proof = if transactions.is_empty() {
+            Vec::new()
+        } else {
+            let indexes: Vec<usize> = transactions.iter().map(|(i, _)| *i).collect();
+            MerkleTree::new(&block.content.data).multi_proof(&indexes)
+        };
+        Self {
+            header: block.header,
+            leaf_size: block.content.data.len(),
+            transactions,
+            proof,
+        }
+    }
+
+    /// Check the transactions against the Merkle root of the header.
+    pub fn verify(&self) -> bool {
+        if self.transactions.is_empty() {
+            return self.proof.is_empty();
+        }
+        let data: Vec<(usize, H256)> = self.transactions.iter().map(|(i, tx)| (*i, tx.hash())).collect();
+        merkle::verify_multi(&self.header.merkle_root, &data, &self.proof, self.leaf_size)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProofError {
+    /// The block of the proof is not in our header chain, yet
+    UnknownBlock,
+    /// The proof does not match the Merkle root of the block
+    Invalid,
+}
+
+/// What a light client knows about a watched transaction.
+#[derive(Serialize, Debug, Clone)]
+pub struct TxStatus {
+    pub hash: String,
+    /// The block of the longest chain that includes the transaction, if any
+    pub block: Option<String>,
+    /// Number of headers from that block to the tip, both included
+    pub confirmations: u32,
+}
+
+/// The state of a light client: the header chain, and the transactions it waits for.
+pub struct LightClient {
+    headers: HeaderChain,
+    /// Watched transactions, with the block in which their inclusion was last proven
+    watched: HashMap<H256, Option<H256>>,
+    /// Addresses whose transactions are watched as full nodes relay them
+    addresses: HashSet<Address>,
+    filter_tweak: u32,
+}
+
+impl LightClient {
+    pub fn new(genesis: &Header) -> Self {
+        Self {
+            headers: HeaderChain::new(genesis),
+            watched: HashMap::new(),
+            addresses: HashSet::new(),
+            filter_tweak: rand::random(),
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    /// Insert headers in order. Returns how many were new, the headers before an invalid one
+    /// are kept.
+    pub fn add_headers(&mut self, headers: &[Header]) -> Result<usize, HeaderError> {
+        let mut added = 0;
+        for header in headers {
+            if self.headers.insert(header)? {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    pub fn watch(&mut self, tx_hash: H256) {
+        self.watched.entry(tx_hash).or_insert(None);
+    }
+
+    pub fn watch_address(&mut self, address: Address) {
+        self.addresses.insert(address);
+    }
+
+    /// The Bloom filter of the watched addresses, to load into full nodes.
+    pub fn filter(&self) -> BloomFilter {
+        let mut filter = BloomFilter::new(self.addresses.len(), FILTER_FP_RATE, self.filter_tweak);
+        for address in self.addresses.iter() {
+            filter.insert_address(address);
+        }
+        filter
+    }
+
+    /// Whether the transaction pays or is paid by a watched address. Unlike the filter, this
+    /// has no false positives.
+    fn is_ours(&self, tx: &SignedTransaction) -> bool {
+        self.addresses.contains(&tx.t.sender) || self.addresses.contains(&tx.t.receiver)
+    }
+
+    /// Watch the relayed transactions of our addresses, until their inclusion is proven.
+    pub fn add_transactions(&mut self, txs: &[SignedTransaction]) {
+        for tx in txs {
+            if self.is_ours(tx) && st_verify(tx) {
+                self.watch(tx.hash());
+            }
+        }
+    }
+
+    /// The watched transactions not included in the longest chain as far as we know.
+    pub fn unconfirmed(&self) -> Vec<H256> {
+        self.watched
+            .iter()
+            .filter(|(_, block)| self.confirmations(block).is_none())
+            .map(|(hash, _)| *hash)
+            .collect()
+    }
+
+    /// Check a proof against our headers, and keep it if it is for a watched transaction.
+    pub fn add_proof(&mut self, proof: TxProof) -> Result<(), ProofError> {
+        let header = match self.headers.get(&proof.block) {
+            Some(h) => h,
+            None => return Err(ProofError::UnknownBlock),
+        };
+        if !proof.verify(header) {
+            return Err(ProofError::Invalid);
+        }
+        if self.watched.contains_key(&proof.transaction.hash()) {
+            self.included(proof.transaction.hash(), proof.block);
+        }
+        Ok(())
+    }
+
+    /// Check a filtered block whose header is in our chain, and record the inclusion of the
+    /// transactions of our addresses. Other matches are false positives of the filter.
+    pub fn add_filtered_block(&mut self, block: &FilteredBlock) -> Result<(), ProofError> {
+        let block_hash = block.header.hash();
+        if !self.headers.contains(&block_hash) {
+            return Err(ProofError::UnknownBlock);
+        }
+        if !block.verify() {
+            return Err(ProofError::Invalid);
+        }
+        for (_, tx) in block.transactions.iter() {
+            if self.is_ours(tx) || self.watched.contains_key(&tx.hash()) {
+                self.included(tx.hash(), block_hash);
+            }
+        }
+        Ok(())
+    }
+
+    fn included(&mut self, tx_hash: H256, block_hash: H256) {
+        // an inclusion in the longest chain is not replaced by one on a fork
+        let keep = match self.watched.get(&tx_hash) {
+            Some(Some(old)) => self.headers.confirmations(old).is_some(),
+            _ => false,
+        };
+        if !keep {
+            self.watched.insert(tx_hash, Some(block_hash));
+        }
+    }
+
+    pub fn status(&self) -> Vec<TxStatus> {
+        self.watched
+            .iter()
+            .map(|(hash, block)| {
+                let confirmations = self.confirmations(block);
+                TxStatus {
+                    hash: hash.to_string(),
+                    block: confirmations.and(block.as_ref()).map(|b| b.to_string()),
+                    confirmations: confirmations.unwrap_or(0),
+                }
+            })
+            .collect()
+    }
+
+    fn confirmations(&self, block: &Option<H256>) -> Option<u32> {
+        block.as_ref().and_then(|b| self.headers.confirmations(b))
+    }
+}
+
+/// The network worker of a light client. It follows the headers announced by full nodes, and
+/// asks them for the proofs of the watched transactions.
+#[derive(Clone)]
+pub struct Worker {
+    msg_chan: smol::channel::Receiver<(Message, peer::Handle)>,
+    num_worker: usize,
+    client: Arc<Mutex<LightClient>>,
+}
+
+impl Worker {
+    pub fn new(
+        num_worker: usize,
+        msg_src: smol::channel::Receiver<(Message, peer::Handle)>,
+        client: &Arc<Mutex<LightClient>>,
+    ) -> Self {
+        Self {
+            msg_chan: msg_src,
+            num_worker,
+            client: Arc::clone(client),
+        }
+    }
+
+    /// Start the worker threads. They exit once the message channel is closed and drained.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
+        (0..self.num_worker)
+            .map(|i| {
+                let cloned = self.clone();
+                thread::spawn(move || {
+                    cloned.worker_loop();
+                    info!("Light worker thread {} exited", i);
+                })
+            })
+            .collect()
+    }
+
+    fn worker_loop(&self) {
+        loop {
+            let (msg, mut peer) = match smol::block_on(self.msg_chan.recv()) {
+                Ok(m) => m,
+                // the server stopped receiving, and every message was handled
+                Err(_) => break,
+            };
+            match msg {
+                Message::Ping(nonce) => {
+                    peer.write(Message::Pong(nonce));
+                }
+                Message::Pong(nonce) => {
+                    peer.record_pong(&nonce);
+                }
+                Message::NewBlockHashes(hashes) => {
+                    peer.mark_known_blocks(&hashes);
+                    let client = self.client.lock().unwrap();
+                    if hashes.iter().any(|h| !client.headers().contains(h)) {
+                        let locator = client.headers().locator();
+                        std::mem::drop(client);
+                        peer.write(Message::GetHeaders(locator));
+                    }
+                }
+                Message::CompactBlock(compact) => {
+                    peer.mark_known_blocks(&[compact.hash()]);
+                    self.process_headers(&[compact.header], &mut peer);
+                }
+                Message::Headers(headers) => {
+                    let full = headers.len() >= MAX_HEADERS;
+                    self.process_headers(&headers, &mut peer);
+                    if full {
+                        let locator = self.client.lock().unwrap().headers().locator();
+                        peer.write(Message::GetHeaders(locator));
+                    }
+                }
+                Message::MerkleBlock(block) => {
+                    peer.mark_known_blocks(&[block.header.hash()]);
+                    self.process_headers(&[block.header], &mut peer);
+                    match self.client.lock().unwrap().add_filtered_block(&block) {
+                        Ok(()) | Err(ProofError::UnknownBlock) => {}
+                        Err(ProofError::Invalid) => {
+                            warn!("Invalid filtered block from {}", peer.addr());
+                            peer.adjust_score(-20);
+                        }
+                    }
+                }
+                // the transactions of our addresses, relayed through our filter
+                Message::Transactions(txs) => {
+                    self.client.lock().unwrap().add_transactions(&txs);
+                }
+                Message::TxProofs(proofs) => {
+                    let mut client = self.client.lock().unwrap();
+                    for proof in proofs {
+                        match client.add_proof(proof) {
+                            Ok(()) | Err(ProofError::UnknownBlock) => {}
+                            Err(ProofError::Invalid) => {
+                                warn!("Invalid transaction proof from {}", peer.addr());
+                                peer.adjust_score(-20);
+                            }
+                        }
+                    }
+                }
+                // a light client keeps neither blocks nor transactions
+                other => {
+                    debug!("Light client ignores {} from {}", Message::command_name(other.command()), peer.addr());
+                }
+            }
+        }
+    }
+
+    /// Extend the header chain, and ask the peer for the proofs of the watched transactions
+    /// that the new headers may include.
+    fn process_headers(&self, headers: &[Header], peer: &mut peer::Handle) {
+        let mut client = self.client.lock().unwrap();
+        match client.add_headers(headers) {
+            Ok(0) => {}
+            Ok(added) => {
+                debug!("{} new headers from {}, tip at height {}", added, peer.addr(), client.headers().height());
+                let unconfirmed = client.unconfirmed();
+                std::mem::drop(client);
+                if !unconfirmed.is_empty() {
+                    peer.write(Message::GetTxProofs(unconfirmed));
+                }
+            }
+            Err(HeaderError::UnknownParent) => {
+                let locator = client.headers().locator();
+                std::mem::drop(client);
+                peer.write(Message::GetHeaders(locator));
+            }
+            Err(e) => {
+                warn!("Invalid header from {}: {}", peer.addr(), e);
+                peer.adjust_score(-20);
+            }
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, sign};
+    use ring::signature::KeyPair;
+
+    /// A block on top of `parent` with `n` transactions, valid under an easy difficulty.
+    fn block_with_transactions(parent: &Header, n: usize) -> Block {
+        let key = key_pair::random();
+        let mut block = generate_random_block(&parent.hash());
+        block.header.difficulty = parent.difficulty;
+        block.content.data = (0..n)
+            .map(|_| {
+                let t = generate_random_transaction();
+                let sig = sign(&t, &key).as_ref().to_vec();
+                SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec())
+            })
+            .collect();
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        block
+    }
+
+    fn easy_genesis() -> Header {
+        let mut header = generate_random_block(&H256::default()).header;
+        header.difficulty = [255u8; 32].into();
+        header
+    }
+
+    #[test]
+    fn confirm_watched_transaction() {
+        let genesis = easy_genesis();
+        let mut client = LightClient::new(&genesis);
+        let block = block_with_transactions(&genesis, 5);
+        let tx_hash = block.content.data[3].hash();
+        client.watch(tx_hash);
+        assert_eq!(client.unconfirmed(), vec![tx_hash]);
+
+        let proof = TxProof::build(&block, 3).unwrap();
+        assert_eq!(client.add_proof(proof.clone()), Err(ProofError::UnknownBlock));
+        assert_eq!(client.add_headers(&[block.header]), Ok(1));
+        client.add_proof(proof).unwrap();
+        assert!(client.unconfirmed().is_empty());
+
+        let child = block_with_transactions(&block.header, 0);
+        client.add_headers(&[child.header]).unwrap();
+        let status = client.status();
+        assert_eq!(status[0].confirmations, 2);
+        assert_eq!(status[0].block, Some(block.hash().to_string()));
+    }
+
+    #[test]
+    fn filtered_block_proves_matches() {
+        let genesis = easy_genesis();
+        let mut client = LightClient::new(&genesis);
+        let block = block_with_transactions(&genesis, 9);
+        let ours = [block.content.data[2].t.receiver, block.content.data[7].t.sender];
+        for address in ours.iter() {
+            client.watch_address(*address);
+        }
+        let filtered = FilteredBlock::new(&block, &client.filter());
+        let matched: Vec<usize> = filtered.transactions.iter().map(|(i, _)| *i).collect();
+        assert!(matched.contains(&2) && matched.contains(&7));
+        assert!(filtered.verify());
+
+        assert_eq!(client.add_filtered_block(&filtered), Err(ProofError::UnknownBlock));
+        client.add_headers(&[block.header]).unwrap();
+        client.add_filtered_block(&filtered).unwrap();
+        let confirmed: Vec<String> = client.status().into_iter().filter(|s| s.confirmations == 1).map(|s| s.hash).collect();
+        assert_eq!(confirmed.len(), 2);
+        assert!(confirmed.contains(&block.content.data[2].hash().to_string()));
+
+        let mut forged = filtered.clone();
+        forged.transactions[0].1.t.value += 1;
+        assert_eq!(client.add_filtered_block(&forged), Err(ProofError::Invalid));
+    }
+
+    #[test]
+    fn reject_forged_proof() {
+        let genesis = easy_genesis();
+        let mut client = LightClient::new(&genesis);
+        let block = block_with_transactions(&genesis, 4);
+        client.add_headers(&[block.header]).unwrap();
+
+        let mut proof = TxProof::build(&block, 1).unwrap();
+        proof.transaction = block.content.data[2].clone();
+        client.watch(proof.transaction.hash());
+        assert_eq!(client.add_proof(proof), Err(ProofError::Invalid));
+        assert_eq!(client.unconfirmed().len(), 1);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
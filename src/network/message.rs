@@ -1,9 +1,14 @@
 use serde::{Serialize, Deserialize};
 
 use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::compact_block::CompactBlock;
+use crate::types::state::Account;
+use super::state_sync::SnapshotOffer;
+use super::bloom::BloomFilter;
+use super::light::{FilteredBlock, TxProof};
+use crate::types::block::Header;
 // use std::time::{SystemTime};
 use crate::types::address::Address;
-use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -15,7 +20,93 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
-    RequestState(Address),
-    RespondState(HashMap<H256, HashMap<Address, (u64, u64)>>, Address),
+    /// Ask a peer for a recent state snapshot, sent by a joining node with its own address
+    GetSnapshot(Address),
+    Snapshot(SnapshotOffer),
+    /// Request a chunk of the state after the given block
+    GetStateChunk(H256, u32),
+    StateChunk(H256, u32, Vec<Account>),
+    /// Ask the peer to relay new blocks to us as compact blocks
+    SendCompact(bool),
+    CompactBlock(CompactBlock),
+    /// Request the transactions at these indexes of a compact block
+    GetBlockTxn(H256, Vec<u32>),
+    BlockTxn(H256, Vec<SignedTransaction>),
+    /// Ask for the headers of the longest chain after the first of these hashes that the peer
+    /// has, see `HeaderChain::locator`
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// Ask for the Merkle proofs of the transactions with these hashes in the longest chain
+    GetTxProofs(Vec<H256>),
+    TxProofs(Vec<TxProof>),
+    /// Only relay to us the transactions and blocks that match this filter
+    FilterLoad(BloomFilter),
+    FilterClear,
+    MerkleBlock(FilteredBlock),
     // InitialState(SystemTime, Address)
 }
+
+impl Message {
+    /// Number of distinct command bytes, i.e. the number of `Message` variants.
+    pub const NUM_COMMANDS: u8 = 23;
+
+    /// Human readable names of the command bytes, indexed by `command()`.
+    const COMMAND_NAMES: [&'static str; Message::NUM_COMMANDS as usize] = [
+        "ping",
+        "pong",
+        "new_block_hashes",
+        "get_blocks",
+        "blocks",
+        "new_transaction_hashes",
+        "get_transactions",
+        "transactions",
+        "get_snapshot",
+        "snapshot",
+        "get_state_chunk",
+        "state_chunk",
+        "send_compact",
+        "compact_block",
+        "get_block_txn",
+        "block_txn",
+        "get_headers",
+        "headers",
+        "get_tx_proofs",
+        "tx_proofs",
+        "filter_load",
+        "filter_clear",
+        "merkle_block",
+    ];
+
+    /// The command byte carried in the frame header for this message.
+    pub fn command(&self) -> u8 {
+        match self {
+            Message::Ping(_) => 0,
+            Message::Pong(_) => 1,
+            Message::NewBlockHashes(_) => 2,
+            Message::GetBlocks(_) => 3,
+            Message::Blocks(_) => 4,
+            Message::NewTransactionHashes(_) => 5,
+            Message::GetTransactions(_) => 6,
+            Message::Transactions(_) => 7,
+            Message::GetSnapshot(_) => 8,
+            Message::Snapshot(_) => 9,
+            Message::GetStateChunk(_, _) => 10,
+            Message::StateChunk(_, _, _) => 11,
+            Message::SendCompact(_) => 12,
+            Message::CompactBlock(_) => 13,
+            Message::GetBlockTxn(_, _) => 14,
+            Message::BlockTxn(_, _) => 15,
+            Message::GetHeaders(_) => 16,
+            Message::Headers(_) => 17,
+            Message::GetTxProofs(_) => 18,
+            Message::TxProofs(_) => 19,
+            Message::FilterLoad(_) => 20,
+            Message::FilterClear => 21,
+            Message::MerkleBlock(_) => 22,
+        }
+    }
+
+    pub fn command_name(command: u8) -> &'static str {
+        Message::COMMAND_NAMES.get(command as usize).copied().unwrap_or("unknown")
+    }
+}
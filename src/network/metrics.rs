@@ -0,0 +1,90 @@
+use super::message::Message;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Counters of P2P traffic, shared by the server, every peer and the workers.
+#[derive(Debug)]
+pub struct Metrics {
+    sent: Vec<AtomicU64>,
+    received: Vec<AtomicU64>,
+    /// Bytes of the frames sent and received, before encryption
+    bytes_sent: Vec<AtomicU64>,
+    bytes_received: Vec<AtomicU64>,
+    /// Hashes left out of an announcement because the peer already knew them.
+    suppressed_announcements: AtomicU64,
+    /// Hashes left out of a `GetBlocks`/`GetTransactions` because they were already requested.
+    suppressed_requests: AtomicU64,
+    /// Blocks received from peers and not added to the chain, by reason
+    rejected_blocks: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Serialize)]
+pub struct MetricsSnapshot {
+    pub sent: BTreeMap<&'static str, u64>,
+    pub received: BTreeMap<&'static str, u64>,
+    pub bytes_sent: BTreeMap<&'static str, u64>,
+    pub bytes_received: BTreeMap<&'static str, u64>,
+    pub suppressed_announcements: u64,
+    pub suppressed_requests: u64,
+    pub rejected_blocks: BTreeMap<&'static str, u64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let counters = || (0..Message::NUM_COMMANDS).map(|_| AtomicU64::new(0)).collect();
+        Self {
+            sent: counters(),
+            received: counters(),
+            bytes_sent: counters(),
+            bytes_received: counters(),
+            suppressed_announcements: AtomicU64::new(0),
+            suppressed_requests: AtomicU64::new(0),
+            rejected_blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_sent(&self, command: u8, bytes: usize) {
+        self.sent[command as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent[command as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, command: u8, bytes: usize) {
+        self.received[command as usize].fetch_add(1, Ordering::Relaxed);
+        self.bytes_received[command as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_block(&self, reason: &'static str) {
+        *self.rejected_blocks.lock().unwrap().entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn record_suppressed_announcements(&self, n: usize) {
+        self.suppressed_announcements.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_suppressed_requests(&self, n: usize) {
+        self.suppressed_requests.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let collect = |counters: &Vec<AtomicU64>| {
+            counters
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (Message::command_name(i as u8), c.load(Ordering::Relaxed)))
+                .collect()
+        };
+        MetricsSnapshot {
+            sent: collect(&self.sent),
+            received: collect(&self.received),
+            bytes_sent: collect(&self.bytes_sent),
+            bytes_received: collect(&self.bytes_received),
+            suppressed_announcements: self.suppressed_announcements.load(Ordering::Relaxed),
+            suppressed_requests: self.suppressed_requests.load(Ordering::Relaxed),
+            rejected_blocks: self.rejected_blocks.lock().unwrap().clone(),
+        }
+    }
+}
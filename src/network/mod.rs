@@ -1,4 +1,12 @@
+pub mod bloom;
+pub mod eviction;
+pub mod frame;
+pub mod inventory;
+pub mod light;
 pub mod message;
+pub mod metrics;
 pub mod peer;
+pub mod secure;
 pub mod server;
+pub mod state_sync;
 pub mod worker;
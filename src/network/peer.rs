@@ -1,30 +1,112 @@
+use super::bloom::BloomFilter;
+use super::frame;
+use super::eviction::Candidate;
+use super::inventory::PeerInventory;
+use super::light::FilteredBlock;
 use super::message::Message;
+use super::metrics::Metrics;
+use super::secure::NodeId;
+use crate::types::block::Block;
+use crate::types::compact_block::CompactBlock;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::SignedTransaction;
 use futures::{channel::mpsc, sink::SinkExt};
 use log::trace;
+use rand::Rng;
+use serde::Serialize;
 use smol::Async;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
+    direction: Direction,
+    node_id: Option<NodeId>,
+    magic: [u8; 4],
+    metrics: &Arc<Metrics>,
 ) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
     let (write_sender, write_receiver) = mpsc::unbounded();
     let addr = stream.get_ref().peer_addr()?;
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        direction,
+        node_id,
+        magic,
+        socket: Some(Arc::new(stream.get_ref().try_clone()?)),
+        inventory: Arc::new(Mutex::new(PeerInventory::default())),
+        compact: Arc::new(AtomicBool::new(false)),
+        filter: Arc::new(Mutex::new(None)),
+        liveness: Arc::new(Mutex::new(Liveness::new())),
+        score: Arc::new(AtomicI64::new(0)),
+        metrics: Arc::clone(metrics),
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum Direction {
     Incoming,
     Outgoing,
 }
 
+/// When we last heard from a peer, and how fast it answers our pings.
+#[derive(Debug)]
+struct Liveness {
+    connected_at: Instant,
+    last_seen: Instant,
+    /// The nonce of the ping we are waiting an answer for, and when we sent it
+    pending_ping: Option<(String, Instant)>,
+    rtt: Option<Duration>,
+}
+
+impl Liveness {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            connected_at: now,
+            last_seen: now,
+            pending_ping: None,
+            rtt: None,
+        }
+    }
+}
+
+/// What the API reports about a connected peer.
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub direction: Direction,
+    /// The key the peer authenticated with, on an encrypted connection
+    pub node_id: Option<String>,
+    pub connected_secs: u64,
+    pub idle_ms: u128,
+    pub rtt_ms: Option<u128>,
+    pub awaiting_pong: bool,
+    pub score: i64,
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
+    direction: Direction,
+    node_id: Option<NodeId>,
+    /// Of the network, at the start of the frames we write
+    magic: [u8; 4],
+    /// A clone of the socket, only used to shut the connection down
+    socket: Option<Arc<std::net::TcpStream>>,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    /// Blocks and transactions this peer already has, so we do not announce them again.
+    inventory: Arc<Mutex<PeerInventory>>,
+    /// Whether this peer asked for new blocks as compact blocks
+    compact: Arc<AtomicBool>,
+    /// The filter a light client loaded, only matching transactions are relayed to it
+    filter: Arc<Mutex<Option<BloomFilter>>>,
+    liveness: Arc<Mutex<Liveness>>,
+    /// Grows when the peer sends us useful data, shrinks when it sends invalid data
+    score: Arc<AtomicI64>,
+    metrics: Arc<Metrics>,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -34,7 +116,8 @@ pub struct TestReceiver {
 
 impl Handle {
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
+        let buffer = frame::encode(&msg, self.magic);
+        self.metrics.record_sent(msg.command(), buffer.len());
         smol::block_on(async move {
             if self.write_queue.send(buffer).await.is_err() {
                 trace!("Trying to send to disconnected peer");
@@ -46,12 +129,225 @@ impl Handle {
         &self.addr
     }
 
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Close the connection. The reader notices and reports the peer as dropped.
+    pub fn disconnect(&self) {
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// Close the connection once the frames already queued are written. The writer notices and
+    /// reports the peer as dropped.
+    pub fn close(&self) {
+        self.write_queue.close_channel();
+    }
+
+    /// Record that something arrived from this peer.
+    pub fn touch(&self) {
+        self.liveness.lock().unwrap().last_seen = Instant::now();
+    }
+
+    /// Send a ping with a random nonce, unless we are still waiting for the previous pong.
+    pub fn send_ping(&mut self) {
+        let nonce = {
+            let mut liveness = self.liveness.lock().unwrap();
+            if liveness.pending_ping.is_some() {
+                return;
+            }
+            let nonce = rand::thread_rng().gen::<u64>().to_string();
+            liveness.pending_ping = Some((nonce.clone(), Instant::now()));
+            nonce
+        };
+        self.write(Message::Ping(nonce));
+    }
+
+    /// Match a pong against our outstanding ping, and measure the round trip time.
+    pub fn record_pong(&self, nonce: &str) {
+        let mut liveness = self.liveness.lock().unwrap();
+        if let Some((expected, sent_at)) = liveness.pending_ping.take() {
+            if expected == nonce {
+                liveness.rtt = Some(sent_at.elapsed());
+            } else {
+                // e.g. the answer to a ping from the debug endpoint
+                liveness.pending_ping = Some((expected, sent_at));
+            }
+        }
+    }
+
+    /// The reason to evict this peer, if it did not answer our ping in time or has been silent
+    /// for too long.
+    pub fn check_alive(&self, pong_timeout: Duration, idle_timeout: Duration) -> Result<(), &'static str> {
+        let liveness = self.liveness.lock().unwrap();
+        if let Some((_, sent_at)) = &liveness.pending_ping {
+            if sent_at.elapsed() > pong_timeout {
+                return Err("missed pong");
+            }
+        }
+        if liveness.last_seen.elapsed() > idle_timeout {
+            return Err("idle");
+        }
+        Ok(())
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.liveness.lock().unwrap().rtt
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        let liveness = self.liveness.lock().unwrap();
+        PeerInfo {
+            addr: self.addr.to_string(),
+            direction: self.direction,
+            node_id: self.node_id.as_ref().map(|id| id.to_string()),
+            connected_secs: liveness.connected_at.elapsed().as_secs(),
+            idle_ms: liveness.last_seen.elapsed().as_millis(),
+            rtt_ms: liveness.rtt.map(|d| d.as_millis()),
+            awaiting_pong: liveness.pending_ping.is_some(),
+            score: self.score(),
+        }
+    }
+
+    pub fn adjust_score(&self, delta: i64) {
+        self.score.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    pub fn score(&self) -> i64 {
+        self.score.load(Ordering::Relaxed)
+    }
+
+    pub fn eviction_candidate(&self) -> Candidate {
+        let liveness = self.liveness.lock().unwrap();
+        Candidate {
+            addr: self.addr,
+            connected: liveness.connected_at.elapsed(),
+            rtt: liveness.rtt,
+            score: self.score(),
+        }
+    }
+
+    /// Remember that this peer has these blocks, e.g. because it announced or sent them.
+    pub fn mark_known_blocks(&self, hashes: &[H256]) {
+        let mut inventory = self.inventory.lock().unwrap();
+        for h in hashes {
+            inventory.blocks.insert(*h);
+        }
+    }
+
+    /// Remember that this peer has these transactions.
+    pub fn mark_known_transactions(&self, hashes: &[H256]) {
+        let mut inventory = self.inventory.lock().unwrap();
+        for h in hashes {
+            inventory.transactions.insert(*h);
+        }
+    }
+
+    /// Send an announcement, leaving out the hashes this peer already knows. Nothing is sent
+    /// if the peer knows all of them.
+    pub fn announce(&mut self, msg: &Message) {
+        let filtered = {
+            let mut inventory = self.inventory.lock().unwrap();
+            match msg {
+                Message::NewBlockHashes(hashes) => {
+                    let unknown = inventory.filter_unknown_blocks(hashes);
+                    self.metrics.record_suppressed_announcements(hashes.len() - unknown.len());
+                    Message::NewBlockHashes(unknown)
+                }
+                Message::NewTransactionHashes(hashes) => {
+                    let unknown = inventory.filter_unknown_transactions(hashes);
+                    self.metrics.record_suppressed_announcements(hashes.len() - unknown.len());
+                    Message::NewTransactionHashes(unknown)
+                }
+                other => other.clone(),
+            }
+        };
+        match &filtered {
+            Message::NewBlockHashes(v) | Message::NewTransactionHashes(v) if v.is_empty() => {}
+            _ => self.write(filtered),
+        }
+    }
+
+    pub fn set_compact(&self, compact: bool) {
+        self.compact.store(compact, Ordering::Relaxed);
+    }
+
+    pub fn set_filter(&self, filter: Option<BloomFilter>) {
+        *self.filter.lock().unwrap() = filter;
+    }
+
+    /// Relay new transactions this peer does not have yet. A peer with a filter gets the
+    /// matching transactions themselves, other peers get a hash announcement.
+    pub fn relay_transactions(&mut self, txs: &[SignedTransaction]) {
+        let filter = self.filter.lock().unwrap().clone();
+        let filter = match filter {
+            Some(f) => f,
+            None => {
+                let hashes: Vec<H256> = txs.iter().map(|t| t.hash()).collect();
+                self.announce(&Message::NewTransactionHashes(hashes));
+                return;
+            }
+        };
+        let matching: Vec<SignedTransaction> = {
+            let mut inventory = self.inventory.lock().unwrap();
+            txs.iter()
+                .filter(|t| filter.matches(t) && inventory.transactions.insert(t.hash()))
+                .cloned()
+                .collect()
+        };
+        if !matching.is_empty() {
+            self.write(Message::Transactions(matching));
+        }
+    }
+
+    /// Relay new blocks this peer does not have yet: filtered to the matching transactions if
+    /// it loaded a filter, as compact blocks if it asked for them, otherwise as a hash
+    /// announcement.
+    pub fn relay_blocks(&mut self, blocks: &[Block]) {
+        let filter = self.filter.lock().unwrap().clone();
+        if let Some(filter) = filter {
+            let unknown: Vec<&Block> = {
+                let mut inventory = self.inventory.lock().unwrap();
+                blocks.iter().filter(|b| inventory.blocks.insert(b.hash())).collect()
+            };
+            for block in unknown {
+                self.write(Message::MerkleBlock(FilteredBlock::new(block, &filter)));
+            }
+            return;
+        }
+        if !self.compact.load(Ordering::Relaxed) {
+            let hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+            self.announce(&Message::NewBlockHashes(hashes));
+            return;
+        }
+        let unknown: Vec<&Block> = {
+            let mut inventory = self.inventory.lock().unwrap();
+            blocks.iter().filter(|b| inventory.blocks.insert(b.hash())).collect()
+        };
+        self.metrics.record_suppressed_announcements(blocks.len() - unknown.len());
+        for block in unknown {
+            self.write(Message::CompactBlock(CompactBlock::from_block(block)));
+        }
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
+            direction: Direction::Incoming,
+            node_id: None,
+            magic: frame::MAGIC,
+            socket: None,
             write_queue: s,
+            inventory: Arc::new(Mutex::new(PeerInventory::default())),
+            compact: Arc::new(AtomicBool::new(false)),
+            filter: Arc::new(Mutex::new(None)),
+            liveness: Arc::new(Mutex::new(Liveness::new())),
+            score: Arc::new(AtomicI64::new(0)),
+            metrics: Arc::new(Metrics::default()),
         },
         TestReceiver {
             r
@@ -63,7 +359,6 @@ impl Handle {
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
         let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
-        let msg: Message = bincode::deserialize(&bytes).unwrap();
-        msg
+        frame::decode(&bytes, frame::MAGIC, frame::DEFAULT_MAX_FRAME_SIZE).unwrap()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,331 @@
+use super::frame::{self, FrameError};
+use super::message::Message;
+use super::peer::Direction;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use ring::{aead, agreement, digest, hkdf, rand};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A handshake that does not finish in time drops the connection.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PROTOCOL_NAME: &[u8] = b"bitcoin-p2p-x25519-chachapoly-ed25519";
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+const AUTH_LEN: usize = KEY_LEN + SIGNATURE_LEN;
+
+/// The long-term identity of this node and the peers it accepts.
+#[derive(Debug, Clone)]
+pub struct SecureConfig {
+    pub key: Arc<Ed25519KeyPair>,
+    /// If set, only peers whose node id is in the list may connect.
+    pub allowed: Option<HashSet<NodeId>>,
+}
+
+/// The Ed25519 public key a peer authenticated with.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct NodeId(pub [u8; KEY_LEN]);
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl std::str::FromStr for NodeId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut id = [0u8; KEY_LEN];
+        hex::decode_to_slice(s, &mut id)?;
+        Ok(NodeId(id))
+    }
+}
+
+impl NodeId {
+    pub fn of(key: &Ed25519KeyPair) -> Self {
+        NodeId(key.public_key().as_ref().try_into().unwrap())
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    KeyAgreement,
+    BadAuthentication,
+    NotAllowed(NodeId),
+    Timeout,
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "{}", e),
+            HandshakeError::KeyAgreement => write!(f, "key agreement failed"),
+            HandshakeError::BadAuthentication => write!(f, "peer failed to authenticate"),
+            HandshakeError::NotAllowed(id) => write!(f, "node {} is not allowed", id),
+            HandshakeError::Timeout => write!(f, "handshake timed out"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+/// One direction of an encrypted connection: a key and a message counter used as nonce.
+pub struct CipherState {
+    key: aead::LessSafeKey,
+    counter: u64,
+}
+
+impl CipherState {
+    fn new(key: aead::UnboundKey) -> Self {
+        Self { key: aead::LessSafeKey::new(key), counter: 0 }
+    }
+
+    fn next_nonce(&mut self) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypt a message, the authentication tag is appended.
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        let mut buf = plaintext.to_vec();
+        self.key.seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut buf).unwrap();
+        buf
+    }
+
+    /// Decrypt and authenticate a message in place, returns the plaintext.
+    pub fn open<'a>(&mut self, ciphertext: &'a mut [u8]) -> Result<&'a [u8], FrameError> {
+        let nonce = self.next_nonce();
+        self.key
+            .open_in_place(nonce, aead::Aad::empty(), ciphertext)
+            .map(|p| &*p)
+            .map_err(|_| FrameError::Decrypt)
+    }
+}
+
+/// The outcome of a successful handshake.
+pub struct Session {
+    pub send: CipherState,
+    pub recv: CipherState,
+    pub remote_id: NodeId,
+}
+
+/// Run the handshake. Both sides send an ephemeral X25519 key, derive one key per direction
+/// from the shared secret, then prove their long-term Ed25519 identity by signing the
+/// transcript, encrypted under the new keys.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    direction: Direction,
+    config: &SecureConfig,
+) -> Result<Session, HandshakeError> {
+    let rng = rand::SystemRandom::new();
+    let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+        .map_err(|_| HandshakeError::KeyAgreement)?;
+    let local_e = ephemeral.compute_public_key().map_err(|_| HandshakeError::KeyAgreement)?;
+    stream.write_all(local_e.as_ref()).await?;
+    stream.flush().await?;
+    let mut remote_e = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote_e).await?;
+
+    // the transcript binds the keys to this exact exchange, ordered by role
+    let (initiator_e, responder_e) = match direction {
+        Direction::Outgoing => (local_e.as_ref(), &remote_e[..]),
+        Direction::Incoming => (&remote_e[..], local_e.as_ref()),
+    };
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(PROTOCOL_NAME);
+    ctx.update(initiator_e);
+    ctx.update(responder_e);
+    let transcript = ctx.finish();
+
+    let (initiator_key, responder_key) = agreement::agree_ephemeral(
+        ephemeral,
+        &agreement::UnparsedPublicKey::new(&agreement::X25519, remote_e),
+        HandshakeError::KeyAgreement,
+        |shared| {
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, PROTOCOL_NAME).extract(shared);
+            let derive = |label: &[u8]| -> Result<aead::UnboundKey, HandshakeError> {
+                let info = [label, transcript.as_ref()];
+                let okm = prk
+                    .expand(&info, &aead::CHACHA20_POLY1305)
+                    .map_err(|_| HandshakeError::KeyAgreement)?;
+                Ok(aead::UnboundKey::from(okm))
+            };
+            Ok((derive(b"initiator")?, derive(b"responder")?))
+        },
+    )?;
+    let (mut send, mut recv) = match direction {
+        Direction::Outgoing => (CipherState::new(initiator_key), CipherState::new(responder_key)),
+        Direction::Incoming => (CipherState::new(responder_key), CipherState::new(initiator_key)),
+    };
+
+    // prove our identity, the role is signed so that a signature cannot be reflected back
+    let role = |d: Direction| if d == Direction::Outgoing { 0u8 } else { 1u8 };
+    let signed = |d: Direction| [transcript.as_ref(), &[role(d)]].concat();
+    let mut auth = config.key.public_key().as_ref().to_vec();
+    auth.extend_from_slice(config.key.sign(&signed(direction)).as_ref());
+    stream.write_all(&send.seal(&auth)).await?;
+    stream.flush().await?;
+
+    let mut remote_auth = vec![0u8; AUTH_LEN + aead::CHACHA20_POLY1305.tag_len()];
+    stream.read_exact(&mut remote_auth).await?;
+    let remote_auth = recv.open(&mut remote_auth).map_err(|_| HandshakeError::BadAuthentication)?;
+    let (remote_key, remote_sig) = remote_auth.split_at(KEY_LEN);
+    let remote_direction = match direction {
+        Direction::Outgoing => Direction::Incoming,
+        Direction::Incoming => Direction::Outgoing,
+    };
+    signature::UnparsedPublicKey::new(&signature::ED25519, remote_key)
+        .verify(&signed(remote_direction), remote_sig)
+        .map_err(|_| HandshakeError::BadAuthentication)?;
+
+    let remote_id = NodeId(remote_key.try_into().unwrap());
+    if let Some(allowed) = &config.allowed {
+        if !allowed.contains(&remote_id) {
+            return Err(HandshakeError::NotAllowed(remote_id));
+        }
+    }
+    Ok(Session { send, recv, remote_id })
+}
+
+/// Run the handshake, giving up after `HANDSHAKE_TIMEOUT`.
+pub async fn handshake_with_timeout<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    direction: Direction,
+    config: &SecureConfig,
+) -> Result<Session, HandshakeError> {
+    smol::future::or(handshake(stream, direction, config), async {
+        smol::Timer::after(HANDSHAKE_TIMEOUT).await;
+        Err(HandshakeError::Timeout)
+    })
+    .await
+}
+
+/// Encrypt a frame, prefixed with the length of the ciphertext.
+pub fn seal_frame(cipher: &mut CipherState, frame: &[u8]) -> Vec<u8> {
+    let sealed = cipher.seal(frame);
+    let mut out = (sealed.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(&sealed);
+    out
+}
+
+/// Read and decrypt one frame, returning its message and the size of the decrypted frame.
+/// `buffer` is reused across calls to hold the ciphertext.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    cipher: &mut CipherState,
+    buffer: &mut Vec<u8>,
+    magic: [u8; 4],
+    max_size: u32,
+) -> Result<(Message, usize), FrameError> {
+    let mut size_buffer = [0u8; 4];
+    reader.read_exact(&mut size_buffer).await.map_err(FrameError::Io)?;
+    let length = u32::from_be_bytes(size_buffer);
+    let overhead = (frame::HEADER_SIZE + aead::CHACHA20_POLY1305.tag_len()) as u32;
+    if length > max_size.saturating_add(overhead) {
+        return Err(FrameError::Oversized { length, max: max_size });
+    }
+    let length = length as usize;
+    if buffer.len() < length {
+        buffer.resize(length, 0);
+    }
+    reader.read_exact(&mut buffer[0..length]).await.map_err(FrameError::Io)?;
+    let plaintext = cipher.open(&mut buffer[0..length])?;
+    let msg = frame::decode(plaintext, magic, max_size)?;
+    Ok((msg, plaintext.len()))
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::key_pair;
+    use ntest::timeout;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    fn config(allowed: Option<HashSet<NodeId>>) -> SecureConfig {
+        SecureConfig { key: Arc::new(key_pair::random()), allowed }
+    }
+
+    /// Connect two sockets and run the handshake on both ends.
+    fn run(
+        client: SecureConfig,
+        server: SecureConfig,
+    ) -> (Result<Session, HandshakeError>, Result<Session, HandshakeError>) {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        smol::block_on(async {
+            let accept = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = stream;
+                handshake(&mut stream, Direction::Incoming, &server).await
+            };
+            let connect = async {
+                let mut stream = Async::<TcpStream>::connect(addr).await.unwrap();
+                handshake(&mut stream, Direction::Outgoing, &client).await
+            };
+            futures::join!(connect, accept)
+        })
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn handshake_and_exchange() {
+        let client = config(None);
+        let server = config(None);
+        let (client_id, server_id) = (NodeId::of(&client.key), NodeId::of(&server.key));
+        let (c, s) = run(client, server);
+        let (mut c, mut s) = (c.unwrap(), s.unwrap());
+        assert!(c.remote_id == server_id);
+        assert!(s.remote_id == client_id);
+
+        let frame = frame::encode(&Message::Ping("secret".to_string()), frame::MAGIC);
+        let mut sealed = c.send.seal(&frame);
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(s.recv.open(&mut sealed).unwrap(), &frame[..]);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reject_unknown_node() {
+        let allowed: HashSet<NodeId> = vec![NodeId::of(&key_pair::random())].into_iter().collect();
+        let (c, s) = run(config(None), config(Some(allowed)));
+        assert!(matches!(s, Err(HandshakeError::NotAllowed(_))));
+        // the client finished its side, but the server drops the connection
+        assert!(c.is_ok());
+    }
+
+    #[test]
+    fn reject_tampered_ciphertext() {
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &[7u8; 32]).unwrap();
+        let mut sender = CipherState::new(key);
+        let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &[7u8; 32]).unwrap();
+        let mut receiver = CipherState::new(key);
+        let mut sealed = sender.seal(b"hello");
+        sealed[0] ^= 1;
+        assert!(receiver.open(&mut sealed).is_err());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,30 +1,86 @@
 use crate::types::address::Address;
 use super::peer;
 use super::message;
+use super::eviction;
+use super::frame::{self, FrameError};
+use super::secure;
+use super::metrics::Metrics;
+use crate::types::block::Block;
+use crate::types::transaction::SignedTransaction;
+#[cfg(any(test,test_utilities))]
+use crate::types::hash::Hashable;
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::AsyncWriteExt;
 use futures::io::{BufReader, BufWriter};
 use futures::{channel::oneshot, stream::StreamExt};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use std::net;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 // use std::sync::{Arc, Mutex};
 // use crate::types::hash::{H256, Hashable};
 // use std::collections::HashMap;
 
 
+/// Tunables of the P2P server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Frames with a larger payload disconnect the peer
+    pub max_frame_size: u32,
+    /// Of the network, frames with another magic disconnect the peer
+    pub magic: [u8; 4],
+    /// How often we ping every peer
+    pub ping_interval: Duration,
+    /// A peer that does not answer a ping within this time is disconnected
+    pub pong_timeout: Duration,
+    /// A peer we have not heard from for this long is disconnected
+    pub idle_timeout: Duration,
+    /// Maximum number of peers that connected to us
+    pub max_inbound: usize,
+    /// Maximum number of peers we connected to
+    pub max_outbound: usize,
+    /// Maximum number of connections, in either direction, with one IP address. Loopback
+    /// addresses are exempt so that several local nodes can form a test network.
+    pub max_per_host: usize,
+    /// If set, every connection is encrypted and peers must authenticate with their node key
+    pub secure: Option<secure::SecureConfig>,
+}
+
+/// How often peers are checked for a missed pong or a long silence, so that the timeouts are not
+/// rounded up to the next ping
+const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_frame_size: frame::DEFAULT_MAX_FRAME_SIZE,
+            magic: frame::MAGIC,
+            ping_interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(90),
+            max_inbound: 32,
+            max_outbound: 8,
+            max_per_host: 4,
+            secure: None,
+        }
+    }
+}
+
 pub fn new(
     addr: std::net::SocketAddr,
-    msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    msg_sink: smol::channel::Sender<(message::Message, peer::Handle)>,
+    config: Config,
     // states: Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>>
 
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
+    let metrics = Arc::new(Metrics::default());
     let handle = Handle {
         control_chan: control_signal_sender.clone(),
+        metrics: Arc::clone(&metrics),
     };
     let ctx = Context {
         peers: std::collections::HashMap::new(),
@@ -32,6 +88,11 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        config,
+        metrics,
+        closing: false,
+        on_closed: None,
+        handshaking: std::collections::HashMap::new(),
         // states: states
     };
     Ok((ctx, handle))
@@ -42,7 +103,15 @@ pub struct Context {
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
-    new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    new_msg_chan: smol::channel::Sender<(message::Message, peer::Handle)>,
+    config: Config,
+    metrics: Arc<Metrics>,
+    /// Set when the node shuts down, no peer is accepted and no message is read after it
+    closing: bool,
+    /// Answered once the last peer is gone, after `ClosePeers`
+    on_closed: Option<oneshot::Sender<()>>,
+    /// Incoming connections still in the secure handshake, by host
+    handshaking: std::collections::HashMap<net::IpAddr, usize>,
     // states: Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>>
 }
 
@@ -53,6 +122,8 @@ impl Context {
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
         let control_chan = self.control_sender.clone();
+        let keepalive_chan = self.control_sender.clone();
+        let ping_interval = self.config.ping_interval;
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -64,6 +135,10 @@ impl Context {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
             .detach();
+        ex.spawn(async move {
+            Self::keepalive_loop(ping_interval, keepalive_chan).await;
+        })
+            .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
         return Ok(());
     }
@@ -76,12 +151,27 @@ impl Context {
     ) -> std::io::Result<()> {
         loop {
             let (stream, addr) = listener.accept().await?;
+            info!("Incoming peer from {}", addr);
             control_chan
-                .send(ControlSignal::GetNewPeer(stream))
+                .send(ControlSignal::Accepted(stream, addr))
                 .await
                 .unwrap();
-            info!("Incoming peer from {}", addr);
-            
+        }
+    }
+
+    /// the loop that periodically triggers the eviction of dead peers, and pings every
+    /// `ping_interval`
+    async fn keepalive_loop(ping_interval: Duration, control_chan: smol::channel::Sender<ControlSignal>) {
+        let mut last_ping = Instant::now();
+        loop {
+            smol::Timer::after(LIVENESS_CHECK_INTERVAL.min(ping_interval)).await;
+            let ping = last_ping.elapsed() >= ping_interval;
+            if ping {
+                last_ping = Instant::now();
+            }
+            if control_chan.send(ControlSignal::Keepalive(ping)).await.is_err() {
+                return;
+            }
         }
     }
 
@@ -91,24 +181,148 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
-                    let handle = self.connect(&addr, ex.clone()).await;
-                    result_chan.send(handle).unwrap();
+                    debug!("Establishing connection to peer {}", addr);
+                    if let Err(e) = self.check_outbound(&addr) {
+                        let _ = result_chan.send(Err(e));
+                        continue;
+                    }
+                    // the connection and the handshake run in their own task, so that a slow
+                    // peer does not hold up the other control signals
+                    let secure = self.config.secure.clone();
+                    let control_chan = self.control_sender.clone();
+                    ex.spawn(async move {
+                        match Self::dial(addr, secure).await {
+                            Ok((stream, session)) => {
+                                let _ = control_chan
+                                    .send(ControlSignal::Dialed(addr, stream, session, result_chan))
+                                    .await;
+                            }
+                            Err(e) => {
+                                let _ = result_chan.send(Err(e));
+                            }
+                        }
+                    })
+                        .detach();
+                }
+                ControlSignal::Dialed(addr, stream, session, result_chan) => {
+                    trace!("Processing Dialed command");
+                    // the limits may have been reached while we were connecting
+                    let handle = match self.check_outbound(&addr) {
+                        Ok(()) => self.register(stream, session, peer::Direction::Outgoing, ex.clone()).await,
+                        Err(e) => Err(e),
+                    };
+                    let _ = result_chan.send(handle);
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
+                    // announcements are filtered per peer, so nobody hears about what it has
                     for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                        hd.announce(&msg);
+                    }
+                }
+                ControlSignal::RelayBlocks(blocks) => {
+                    trace!("Processing RelayBlocks command");
+                    for (_, hd) in self.peers.iter_mut() {
+                        hd.relay_blocks(&blocks);
+                    }
+                }
+                ControlSignal::RelayTransactions(txs) => {
+                    trace!("Processing RelayTransactions command");
+                    for (_, hd) in self.peers.iter_mut() {
+                        hd.relay_transactions(&txs);
+                    }
+                }
+                ControlSignal::Accepted(stream, addr) => {
+                    trace!("Processing Accepted command");
+                    let secure = match &self.config.secure {
+                        Some(config) => config.clone(),
+                        None => {
+                            self.accept(stream, None, ex.clone()).await?;
+                            continue;
+                        }
+                    };
+                    // a handshake holds a socket and a task until it times out, so the limits
+                    // are checked before starting it
+                    if let Err(reason) = self.check_handshake(&addr) {
+                        info!("Rejecting incoming peer {}: {}", addr, reason);
+                        continue;
                     }
+                    *self.handshaking.entry(addr.ip()).or_insert(0) += 1;
+                    // the handshake runs in its own task, so that a slow peer does not hold up
+                    // the others
+                    let control_chan = self.control_sender.clone();
+                    ex.spawn(async move {
+                        let mut stream = stream;
+                        let result = secure::handshake_with_timeout(&mut stream, peer::Direction::Incoming, &secure)
+                            .await
+                            .map(|session| (stream, session));
+                        let _ = control_chan.send(ControlSignal::Handshaken(addr, result)).await;
+                    })
+                        .detach();
                 }
-                ControlSignal::GetNewPeer(stream) => {
-                    trace!("Processing GetNewPeer command");
-                    // println!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                ControlSignal::Handshaken(addr, result) => {
+                    trace!("Processing Handshaken command");
+                    if let Some(count) = self.handshaking.get_mut(&addr.ip()) {
+                        *count -= 1;
+                        if *count == 0 {
+                            self.handshaking.remove(&addr.ip());
+                        }
+                    }
+                    match result {
+                        Ok((stream, session)) => self.accept(stream, Some(session), ex.clone()).await?,
+                        Err(e) => info!("Rejecting incoming peer {}: {}", addr, e),
+                    }
+                }
+                ControlSignal::Keepalive(ping) => {
+                    trace!("Processing Keepalive command");
+                    if self.closing {
+                        continue;
+                    }
+                    for (addr, hd) in self.peers.iter_mut() {
+                        match hd.check_alive(self.config.pong_timeout, self.config.idle_timeout) {
+                            Ok(()) if ping => hd.send_ping(),
+                            Ok(()) => {}
+                            Err(reason) => {
+                                info!("Disconnecting peer {}: {}", addr, reason);
+                                hd.disconnect();
+                            }
+                        }
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    let infos = self.peers.values().map(|hd| hd.info()).collect();
+                    let _ = result_chan.send(infos);
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    // both the reader and the writer report the drop, only log it once
+                    if self.peers.remove(&addr).is_some() {
+                        info!("Peer {} disconnected", addr);
+                    }
+                    if self.peers.is_empty() {
+                        if let Some(result_chan) = self.on_closed.take() {
+                            let _ = result_chan.send(());
+                        }
+                    }
+                }
+                ControlSignal::StopReceiving => {
+                    trace!("Processing StopReceiving command");
+                    self.closing = true;
+                    // the workers still get the messages already read, then see the channel closed
+                    self.new_msg_chan.close();
+                }
+                ControlSignal::ClosePeers(result_chan) => {
+                    trace!("Processing ClosePeers command");
+                    self.closing = true;
+                    self.new_msg_chan.close();
+                    for hd in self.peers.values() {
+                        hd.close();
+                    }
+                    if self.peers.is_empty() {
+                        let _ = result_chan.send(());
+                    } else {
+                        self.on_closed = Some(result_chan);
+                    }
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     for (addr, hd) in self.peers.iter_mut() {
@@ -117,111 +331,186 @@ impl Context {
                         }
                     }
                 }
-                ControlSignal::SendToEveryoneBut((handle_where_came_from, msg)) => {
-                    for (addr, hd) in self.peers.iter_mut() {
-                        if handle_where_came_from.addr() != hd.addr() && addr != &self.addr {
-                            hd.write(msg.clone());
-                        }
-                    }
-                }
             }
         }
         return Ok(());
     }
 
-    /// Connect to a peer, and register this peer
-    async fn connect(
-        &mut self,
-        addr: &std::net::SocketAddr,
-        ex: Arc<Executor<'_>>,
-    ) -> std::io::Result<peer::Handle> {
-        debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+    /// Whether we may have one more outgoing peer at this address.
+    fn check_outbound(&self, addr: &std::net::SocketAddr) -> std::io::Result<()> {
+        if self.closing {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "the node is shutting down"));
+        }
+        if self.count_direction(peer::Direction::Outgoing) >= self.config.max_outbound {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "outbound connection limit reached"));
+        }
+        if self.host_is_full(&addr.ip()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "too many connections to this host"));
+        }
+        Ok(())
+    }
 
-        // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+    /// Whether we may start one more incoming handshake with this address. At most
+    /// `max_inbound` handshakes run at once, and a host's handshakes count towards its
+    /// `max_per_host` connections.
+    fn check_handshake(&self, addr: &std::net::SocketAddr) -> Result<(), &'static str> {
+        if self.closing {
+            return Err("the node is shutting down");
+        }
+        if self.handshaking.values().sum::<usize>() >= self.config.max_inbound {
+            return Err("too many handshakes in progress");
+        }
+        if self.host_is_full(&addr.ip()) {
+            return Err("too many connections from this host");
+        }
+        Ok(())
+    }
+
+    /// Connect to a peer, and authenticate it if the connections are encrypted.
+    async fn dial(
+        addr: std::net::SocketAddr,
+        secure: Option<secure::SecureConfig>,
+    ) -> std::io::Result<(Async<net::TcpStream>, Option<secure::Session>)> {
+        let mut stream = Async::<std::net::TcpStream>::connect(addr).await?;
+        let session = match &secure {
+            Some(config) => Some(
+                secure::handshake_with_timeout(&mut stream, peer::Direction::Outgoing, config)
+                    .await
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok((stream, session))
     }
 
     async fn accept(
         &mut self,
         stream: Async<net::TcpStream>,
+        session: Option<secure::Session>,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
+        let addr = stream.get_ref().peer_addr()?;
+        if self.closing {
+            info!("Rejecting incoming peer {}: the node is shutting down", addr);
+            return Ok(());
+        }
+        if self.host_is_full(&addr.ip()) {
+            info!("Rejecting incoming peer {}: too many connections from this host", addr);
+            return Ok(());
+        }
+        if self.count_direction(peer::Direction::Incoming) >= self.config.max_inbound
+            && !self.evict_inbound()
+        {
+            info!("Rejecting incoming peer {}: no inbound slot available", addr);
+            return Ok(());
+        }
+        self.register(stream, session, peer::Direction::Incoming, ex).await?;
         Ok(())
     }
 
+    fn count_direction(&self, direction: peer::Direction) -> usize {
+        self.peers.values().filter(|hd| hd.direction() == direction).count()
+    }
+
+    fn host_is_full(&self, ip: &net::IpAddr) -> bool {
+        if ip.is_loopback() {
+            return false;
+        }
+        let handshaking = self.handshaking.get(ip).copied().unwrap_or(0);
+        self.peers.keys().filter(|a| a.ip() == *ip).count() + handshaking >= self.config.max_per_host
+    }
+
+    /// Make room for a new inbound peer by dropping the least valuable one. Returns false if
+    /// every inbound peer is protected.
+    fn evict_inbound(&mut self) -> bool {
+        let candidates: Vec<eviction::Candidate> = self
+            .peers
+            .values()
+            .filter(|hd| hd.direction() == peer::Direction::Incoming)
+            .map(|hd| hd.eviction_candidate())
+            .collect();
+        match eviction::select_victim(&candidates) {
+            Some(victim) => {
+                info!("Evicting inbound peer {} to make room", victim);
+                if let Some(hd) = self.peers.remove(&victim) {
+                    hd.disconnect();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        session: Option<secure::Session>,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let node_id = session.as_ref().map(|s| s.remote_id.clone());
+        let (mut write_queue, handle) = peer::new(&stream, direction, node_id, self.config.magic, &self.metrics)?;
+        let (mut send_cipher, mut recv_cipher) = match session {
+            Some(s) => (Some(s.send), Some(s.recv)),
+            None => (None, None),
+        };
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
+        let reader_control_chan = self.control_sender.clone();
+        let max_frame_size = self.config.max_frame_size;
+        let magic = self.config.magic;
+        let metrics = Arc::clone(&self.metrics);
         let addr = stream.get_ref().peer_addr()?;
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
+        let reader_stream = stream.clone();
         let mut reader = BufReader::new(stream.clone());
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
+            // the buffer to store the message content, reused across frames
             let mut msg_buffer: Vec<u8> = vec![];
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
+                let result = match recv_cipher.as_mut() {
+                    Some(cipher) => {
+                        secure::read_frame(&mut reader, cipher, &mut msg_buffer, magic, max_frame_size).await
                     }
+                    None => frame::read_frame(&mut reader, &mut msg_buffer, magic, max_frame_size).await,
                 };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
-                    }
-                    Err(_) => {
+                let (msg, size) = match result {
+                    Ok(m) => m,
+                    // the connection is closed
+                    Err(FrameError::Io(_)) => break,
+                    Err(e) => {
+                        warn!("Disconnecting peer {}: {}", addr, e);
                         break;
                     }
+                };
+                metrics.record_received(msg.command(), size);
+                handle_copy.touch();
+                if new_msg_chan.send((msg, handle_copy.clone())).await.is_err() {
+                    // the node is shutting down, the writer closes the connection once the
+                    // replies to what we already read are written
+                    return;
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, or misbehaved, so close the socket for the writer too
+            let _ = reader_stream.get_ref().shutdown(net::Shutdown::Both);
+            let _ = reader_control_chan.send(ControlSignal::DroppedPeer(addr)).await;
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
-                    }
+            // first, get a message to write from the queue, it is already a complete frame
+            while let Some(mut new_msg) = write_queue.next().await {
+                if let Some(cipher) = send_cipher.as_mut() {
+                    new_msg = secure::seal_frame(cipher, &new_msg);
                 }
+                // second, write the frame
                 match writer.write_all(&new_msg).await {
                     Ok(_) => {}
                     Err(_) => {
@@ -236,13 +525,15 @@ impl Context {
                 }
             }
             // the peer is disconnected
-            control_chan
-                .send(ControlSignal::DroppedPeer(addr))
-                .await
-                .unwrap();
+            let _ = writer.get_ref().get_ref().shutdown(net::Shutdown::Both);
+            let _ = control_chan.send(ControlSignal::DroppedPeer(addr)).await;
         })
             .detach();
 
+        // we always prefer new blocks as compact blocks
+        let mut handle = handle;
+        handle.write(message::Message::SendCompact(true));
+
         // insert the peer handle so that we can broadcast to this guy later
         // println!("Inserted Peer at {:?}", addr);
         self.peers.insert(addr, handle.clone());
@@ -253,6 +544,7 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
+    metrics: Arc<Metrics>,
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -265,6 +557,14 @@ impl TestReceiver {
         match sig {
             // in this test, only return broadcast msg
             ControlSignal::BroadcastMessage(msg) => Some(msg),
+            // a peer that did not ask for compact blocks gets the hashes
+            ControlSignal::RelayBlocks(blocks) => {
+                Some(message::Message::NewBlockHashes(blocks.iter().map(|b| b.hash()).collect()))
+            }
+            // and the hashes of transactions, without a filter
+            ControlSignal::RelayTransactions(txs) => {
+                Some(message::Message::NewTransactionHashes(txs.iter().map(|t| t.hash()).collect()))
+            }
             _ => None,
         }
     }
@@ -281,6 +581,13 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
+    /// Information about every connected peer, including the measured round trip time.
+    pub fn peers(&self) -> Vec<peer::PeerInfo> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap_or_default()
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
@@ -289,14 +596,42 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
-    pub fn sendToEveryoneButMeAndWhereThisMsgCameFrom(&self, wherethiscamefrom: peer::Handle, msg: message::Message){
-        smol::block_on(self.control_chan.send(ControlSignal::SendToEveryoneBut((wherethiscamefrom, msg)))).unwrap();
+    /// Relay newly inserted blocks to every peer that does not have them.
+    pub fn relay_blocks(&self, blocks: Vec<Block>) {
+        smol::block_on(self.control_chan.send(ControlSignal::RelayBlocks(blocks))).unwrap();
+    }
+
+    /// Relay new transactions to every peer that does not have them, or only the matching
+    /// ones to peers that loaded a filter.
+    pub fn relay_transactions(&self, txs: Vec<SignedTransaction>) {
+        smol::block_on(self.control_chan.send(ControlSignal::RelayTransactions(txs))).unwrap();
+    }
+
+    /// Stop accepting peers and reading their messages, when the node shuts down. The messages
+    /// already read stay queued, and the workers exit once they handled them.
+    pub fn stop_receiving(&self) {
+        smol::block_on(self.control_chan.send(ControlSignal::StopReceiving)).unwrap();
+    }
+
+    /// Close every connection once the frames queued for it are written. Returns false if some
+    /// peer is still connected after `timeout`.
+    pub fn close_peers(&self, timeout: Duration) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::ClosePeers(sender))).unwrap();
+        smol::block_on(smol::future::or(async { receiver.await.is_ok() }, async {
+            smol::Timer::after(timeout).await;
+            false
+        }))
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
     }
 
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {control_chan: s, metrics: Arc::new(Metrics::default())};
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -307,9 +642,28 @@ enum ControlSignal {
         std::net::SocketAddr,
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
+    /// An outgoing connection is established and authenticated, it only remains to register it
+    Dialed(
+        std::net::SocketAddr,
+        Async<net::TcpStream>,
+        Option<secure::Session>,
+        oneshot::Sender<std::io::Result<peer::Handle>>,
+    ),
     BroadcastMessage(message::Message),
-    GetNewPeer(Async<net::TcpStream>),
+    RelayBlocks(Vec<Block>),
+    RelayTransactions(Vec<SignedTransaction>),
+    /// A peer connected to us, it still has to authenticate if the connections are encrypted
+    Accepted(Async<net::TcpStream>, std::net::SocketAddr),
+    /// The secure handshake of an incoming peer finished
+    Handshaken(
+        std::net::SocketAddr,
+        Result<(Async<net::TcpStream>, secure::Session), secure::HandshakeError>,
+    ),
     DroppedPeer(std::net::SocketAddr),
+    /// Evict the dead peers, and ping the others if set
+    Keepalive(bool),
+    GetPeers(oneshot::Sender<Vec<peer::PeerInfo>>),
     SendToPeer((std::net::SocketAddr,message::Message)),
-    SendToEveryoneBut((peer::Handle, message::Message)), 
+    StopReceiving,
+    ClosePeers(oneshot::Sender<()>),
 }
@@ -0,0 +1,452 @@
+use serde::{Serialize, Deserialize};
+use crate::blockchain::stats::expected_hashes;
+use crate::types::address::Address;
+use crate::types::block::{Block, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::{self, Account, State};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How many blocks below its tip a node offers its snapshot, so that the snapshot block is
+/// unlikely to be dropped by a reorganization.
+pub const SNAPSHOT_DEPTH: usize = 6;
+/// How long a joining node collects offers after the first one, before downloading the one
+/// with the most work.
+pub const OFFER_WINDOW: Duration = Duration::from_secs(2);
+/// Most block bodies asked in one `GetBlocks`, so that the `Blocks` answer fits in a frame.
+pub const BODY_BATCH: usize = 16;
+/// Offers with more chunks are refused, so that a peer cannot make us allocate without bound.
+const MAX_CHUNKS: u32 = 4096;
+
+/// What a node offers to a joining node: the headers up to a recent block of its longest
+/// chain, and the account state after that block, split into chunks. The bodies of the blocks
+/// are fetched with `GetBlocks` once the offer is chosen.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotOffer {
+    /// The headers of the longest chain after genesis, up to and including the snapshot block,
+    /// which commits to the state
+    pub headers: Vec<Header>,
+    /// Number of chunks the state at the snapshot block is split into
+    pub num_chunks: u32,
+    /// The blocks on top of the snapshot block, fetched as usual once the state is installed
+    pub descendants: Vec<H256>,
+    /// The address of the offering node
+    pub address: Address,
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// A snapshot is already being downloaded, or was installed
+    Busy,
+    /// The offer has more chunks than we are willing to download
+    TooLarge,
+    /// The headers of the offer do not form a valid chain starting at our genesis
+    BrokenChain,
+    /// The offer has no block, so no header commits to its state
+    NoBlock,
+    /// The snapshot block is not above our tip
+    NotAhead,
+    /// A chunk that we did not ask for, or that is not ordered by address
+    UnexpectedChunk,
+    /// A block body that does not match the merkle root of its header
+    BadBody,
+    /// The downloaded state does not match the state root of the snapshot block
+    BadStateRoot,
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SyncError::Busy => write!(f, "state sync already in progress or done"),
+            SyncError::TooLarge => write!(f, "snapshot has too many chunks"),
+            SyncError::BrokenChain => write!(f, "snapshot chain is invalid"),
+            SyncError::NoBlock => write!(f, "snapshot has no block committing to its state"),
+            SyncError::NotAhead => write!(f, "snapshot block is not above our tip"),
+            SyncError::UnexpectedChunk => write!(f, "unexpected or malformed state chunk"),
+            SyncError::BadBody => write!(f, "block body does not match its header"),
+            SyncError::BadStateRoot => write!(f, "snapshot does not match the state root"),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+/// A verified snapshot, ready to be installed.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub blocks: Vec<Block>,
+    pub block: H256,
+    pub state: State,
+    pub descendants: Vec<H256>,
+}
+
+pub enum Progress {
+    /// Ask the peer for these chunks of the state at this block, and for these block bodies
+    Request(SocketAddr, H256, Vec<u32>, Vec<H256>),
+    /// Waiting for more offers, chunks or bodies
+    Pending,
+    Complete(Snapshot),
+}
+
+/// The download of the state snapshot of a joining node. Offers are collected for
+/// `OFFER_WINDOW`, then the one with the most work is downloaded. If it turns out to be
+/// invalid, the next best one is.
+#[derive(Default)]
+pub struct StateSync {
+    /// Offers with a valid chain, with their peer and the work of their chain
+    offers: Vec<(SocketAddr, SnapshotOffer, f64)>,
+    download: Option<Download>,
+    done: bool,
+}
+
+struct Download {
+    peer: SocketAddr,
+    block: H256,
+    /// Of the snapshot block, the downloaded state must match it
+    state_root: H256,
+    offer: SnapshotOffer,
+    chunks: Vec<Option<Vec<Account>>>,
+    /// The bodies of the offered headers, in chain order
+    bodies: Vec<Option<Block>>,
+    positions: HashMap<H256, usize>,
+    /// The bodies before it were requested
+    requested: usize,
+}
+
+impl StateSync {
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Check the headers of an offer against our genesis block and our height, and keep it until
+    /// the offers are compared. Returns true for the first offer, which opens the window.
+    pub fn offer(
+        &mut self,
+        peer: SocketAddr,
+        genesis: &Block,
+        height: u32,
+        offer: SnapshotOffer,
+    ) -> Result<bool, SyncError> {
+        if self.done || self.download.is_some() {
+            return Err(SyncError::Busy);
+        }
+        if offer.num_chunks > MAX_CHUNKS {
+            return Err(SyncError::TooLarge);
+        }
+        let mut parent = genesis.header;
+        for header in offer.headers.iter() {
+            if header.parent != parent.hash()
+                || header.hash() > parent.difficulty
+                || header.difficulty != parent.difficulty
+            {
+                return Err(SyncError::BrokenChain);
+            }
+            parent = *header;
+        }
+        // the allocation of the genesis is not committed in a header, so it cannot be checked
+        if offer.headers.is_empty() {
+            return Err(SyncError::NoBlock);
+        }
+        if offer.headers.len() <= height as usize {
+            return Err(SyncError::NotAhead);
+        }
+        let work = offer.headers.iter().map(|h| expected_hashes(&h.difficulty)).sum();
+        self.offers.retain(|(p, _, _)| *p != peer);
+        self.offers.push((peer, offer, work));
+        Ok(self.offers.len() == 1)
+    }
+
+    /// Start downloading the offer with the most work, once the window is over or the previous
+    /// download failed.
+    pub fn choose(&mut self) -> Progress {
+        if self.done || self.download.is_some() {
+            return Progress::Pending;
+        }
+        let best = self
+            .offers
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.2.partial_cmp(&b.2).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(i, _)| i);
+        let (peer, offer, _) = match best {
+            Some(i) => self.offers.swap_remove(i),
+            None => return Progress::Pending,
+        };
+        let snapshot = offer.headers.last().unwrap();
+        let (block, state_root) = (snapshot.hash(), snapshot.state_root);
+        let positions = offer.headers.iter().enumerate().map(|(i, h)| (h.hash(), i)).collect();
+        let requested = BODY_BATCH.min(offer.headers.len());
+        let bodies: Vec<H256> = offer.headers[..requested].iter().map(|h| h.hash()).collect();
+        self.download = Some(Download {
+            peer,
+            block,
+            state_root,
+            chunks: vec![None; offer.num_chunks as usize],
+            bodies: vec![None; offer.headers.len()],
+            positions,
+            requested,
+            offer,
+        });
+        let download = self.download.as_ref().unwrap();
+        Progress::Request(peer, block, (0..download.offer.num_chunks).collect(), bodies)
+    }
+
+    /// Whether this block body is one we asked `peer` for.
+    pub fn wants(&self, peer: SocketAddr, hash: &H256) -> bool {
+        match &self.download {
+            Some(d) => d.peer == peer && d.positions.get(hash).map_or(false, |i| d.bodies[*i].is_none()),
+            None => false,
+        }
+    }
+
+    /// Store a chunk received from `peer`. Once every chunk is there, the state is checked
+    /// against the state root of the snapshot block. On error the download is abandoned, so
+    /// that the next best offer can be chosen.
+    pub fn add_chunk(
+        &mut self,
+        peer: SocketAddr,
+        block: H256,
+        index: u32,
+        accounts: Vec<Account>,
+    ) -> Result<Progress, SyncError> {
+        let download = match self.download.as_mut() {
+            Some(d) if d.peer == peer && d.block == block => d,
+            _ => return Err(SyncError::UnexpectedChunk),
+        };
+        let sorted = accounts.windows(2).all(|w| w[0].address < w[1].address);
+        match download.chunks.get_mut(index as usize) {
+            Some(slot) if slot.is_none() && sorted && !accounts.is_empty() => *slot = Some(accounts),
+            _ => {
+                self.download = None;
+                return Err(SyncError::UnexpectedChunk);
+            }
+        }
+        self.next()
+    }
+
+    /// Store block bodies received from `peer`, only those `wants` accepts. Once a batch is
+    /// complete, the next one is requested.
+    pub fn add_bodies(&mut self, peer: SocketAddr, blocks: Vec<Block>) -> Result<Progress, SyncError> {
+        let download = match self.download.as_mut() {
+            Some(d) if d.peer == peer => d,
+            _ => return Ok(Progress::Pending),
+        };
+        for block in blocks {
+            // the hash covers the header, so only the transactions remain to be checked
+            if let Some(i) = download.positions.get(&block.hash()) {
+                if MerkleTree::new(&block.content.data).root() != block.header.merkle_root {
+                    self.download = None;
+                    return Err(SyncError::BadBody);
+                }
+                download.bodies[*i] = Some(block);
+            }
+        }
+        self.next()
+    }
+
+    /// Request the next batch of bodies if the previous one arrived, or finish.
+    fn next(&mut self) -> Result<Progress, SyncError> {
+        let download = self.download.as_mut().unwrap();
+        if download.bodies[..download.requested].iter().any(|b| b.is_none()) {
+            return Ok(Progress::Pending);
+        }
+        if download.requested < download.bodies.len() {
+            let end = (download.requested + BODY_BATCH).min(download.bodies.len());
+            let bodies = download.offer.headers[download.requested..end].iter().map(|h| h.hash()).collect();
+            download.requested = end;
+            return Ok(Progress::Request(download.peer, download.block, vec![], bodies));
+        }
+        if download.chunks.iter().any(|c| c.is_none()) {
+            return Ok(Progress::Pending);
+        }
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<Progress, SyncError> {
+        let download = self.download.take().unwrap();
+        let accounts: Vec<Account> = download.chunks.into_iter().flatten().flatten().collect();
+        let state = state::from_accounts(&accounts);
+        // chunks that overlap would collapse to fewer accounts
+        if state.len() != accounts.len() {
+            return Err(SyncError::UnexpectedChunk);
+        }
+        if state.root() != download.state_root {
+            return Err(SyncError::BadStateRoot);
+        }
+        self.done = true;
+        self.offers.clear();
+        Ok(Progress::Complete(Snapshot {
+            blocks: download.bodies.into_iter().flatten().collect(),
+            block: download.block,
+            state,
+            descendants: download.offer.descendants,
+        }))
+    }
+}
+
+/// The accounts of the snapshot we serve, sorted once, so that each chunk request does not sort
+/// the whole state again.
+#[derive(Default)]
+pub struct ServedSnapshot {
+    block: Option<H256>,
+    accounts: Vec<Account>,
+}
+
+impl ServedSnapshot {
+    /// Chunk `index` of the state after `block`. The state is only sorted when `block` is not
+    /// the one served last.
+    pub fn chunk(&mut self, block: H256, index: u32, states: &HashMap<H256, State>) -> Option<Vec<Account>> {
+        if self.block != Some(block) {
+            self.accounts = state::sorted_accounts(states.get(&block)?);
+            self.block = Some(block);
+        }
+        state::chunk(&self.accounts, index)
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::types::block::generate_random_block;
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+
+    /// A valid block on top of `parent`, committing to `state`.
+    fn child(parent: &Block, state: &State) -> Block {
+        let mut block = generate_random_block(&parent.hash());
+        block.header.difficulty = parent.header.difficulty;
+        block.header.merkle_root = MerkleTree::new(&block.content.data).root();
+        block.header.state_root = state.root();
+        block
+    }
+
+    /// A chain of `len` blocks on an easy genesis, the last one committing to a state big
+    /// enough for several chunks.
+    fn snapshot_chain(len: usize) -> (Block, Vec<Block>, State) {
+        let blockchain = Blockchain::with_genesis([255; 32].into(), 0);
+        let genesis = blockchain.blocks[&blockchain.tip()].clone();
+        let state: State = (0..state::CHUNK_SIZE + 10)
+            .map(|i| (rand::random::<[u8; 20]>().into(), (i as u64, 50)))
+            .collect();
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..len {
+            let block = child(blocks.last().unwrap_or(&genesis), &state);
+            blocks.push(block);
+        }
+        (genesis, blocks, state)
+    }
+
+    fn offer_of(blocks: &[Block], state: &State) -> SnapshotOffer {
+        SnapshotOffer {
+            headers: blocks.iter().map(|b| b.header).collect(),
+            num_chunks: state::num_chunks(state.len()),
+            descendants: vec![],
+            address: Address::default(),
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:6000".parse().unwrap()
+    }
+
+    #[test]
+    fn download_and_verify() {
+        let (genesis, blocks, state) = snapshot_chain(BODY_BATCH + 1);
+        let mut sync = StateSync::default();
+        assert!(sync.offer(peer(), &genesis, 0, offer_of(&blocks, &state)).unwrap());
+        let (block, indexes, bodies) = match sync.choose() {
+            Progress::Request(_, block, indexes, bodies) => (block, indexes, bodies),
+            _ => panic!(),
+        };
+        assert_eq!(indexes, vec![0, 1]);
+        assert_eq!(bodies.len(), BODY_BATCH);
+        assert!(sync.wants(peer(), &bodies[0]));
+        let mut served = ServedSnapshot::default();
+        let states: HashMap<H256, State> = [(block, state.clone())].iter().cloned().collect();
+        let chunk = served.chunk(block, 1, &states).unwrap();
+        assert!(matches!(sync.add_chunk(peer(), block, 1, chunk), Ok(Progress::Pending)));
+        // the next batch of bodies is asked once the first one arrived
+        match sync.add_bodies(peer(), blocks[..BODY_BATCH].to_vec()).unwrap() {
+            Progress::Request(_, _, indexes, bodies) => {
+                assert!(indexes.is_empty());
+                assert_eq!(bodies, vec![block]);
+            }
+            _ => panic!(),
+        }
+        assert!(matches!(sync.add_bodies(peer(), blocks[BODY_BATCH..].to_vec()), Ok(Progress::Pending)));
+        let chunk = served.chunk(block, 0, &states).unwrap();
+        match sync.add_chunk(peer(), block, 0, chunk).unwrap() {
+            Progress::Complete(snapshot) => {
+                assert_eq!(snapshot.state, state);
+                assert_eq!(snapshot.blocks.len(), blocks.len());
+            }
+            _ => panic!(),
+        }
+        assert!(sync.is_done());
+    }
+
+    #[test]
+    fn reject_tampered_state() {
+        let (genesis, blocks, state) = snapshot_chain(1);
+        let mut sync = StateSync::default();
+        let block = blocks[0].hash();
+        sync.offer(peer(), &genesis, 0, offer_of(&blocks, &state)).unwrap();
+        sync.choose();
+        sync.add_bodies(peer(), blocks).unwrap();
+        let accounts = state::sorted_accounts(&state);
+        sync.add_chunk(peer(), block, 0, state::chunk(&accounts, 0).unwrap()).unwrap();
+        let mut chunk = state::chunk(&accounts, 1).unwrap();
+        chunk[0].balance += 1000;
+        assert!(matches!(sync.add_chunk(peer(), block, 1, chunk), Err(SyncError::BadStateRoot)));
+        assert!(!sync.is_done());
+    }
+
+    #[test]
+    fn reject_tampered_body() {
+        let (genesis, mut blocks, state) = snapshot_chain(1);
+        let mut sync = StateSync::default();
+        sync.offer(peer(), &genesis, 0, offer_of(&blocks, &state)).unwrap();
+        sync.choose();
+        blocks[0].content.data.push(SignedTransaction::new(generate_random_transaction(), vec![], vec![]));
+        assert!(matches!(sync.add_bodies(peer(), blocks), Err(SyncError::BadBody)));
+    }
+
+    #[test]
+    fn reject_broken_chain() {
+        let (genesis, blocks, state) = snapshot_chain(1);
+        let mut offer = offer_of(&blocks, &state);
+        offer.headers[0].parent = H256::default();
+        let mut sync = StateSync::default();
+        assert!(matches!(sync.offer(peer(), &genesis, 0, offer), Err(SyncError::BrokenChain)));
+    }
+
+    #[test]
+    fn reject_offer_without_block() {
+        let (genesis, _, state) = snapshot_chain(0);
+        let mut sync = StateSync::default();
+        assert!(matches!(sync.offer(peer(), &genesis, 0, offer_of(&[], &state)), Err(SyncError::NoBlock)));
+        assert!(!sync.is_done());
+    }
+
+    #[test]
+    fn choose_the_most_work() {
+        let (genesis, blocks, state) = snapshot_chain(3);
+        let mut sync = StateSync::default();
+        let (short, long): (SocketAddr, SocketAddr) = ("127.0.0.1:6001".parse().unwrap(), peer());
+        assert!(matches!(sync.offer(short, &genesis, 2, offer_of(&blocks[..2], &state)), Err(SyncError::NotAhead)));
+        assert!(sync.offer(short, &genesis, 0, offer_of(&blocks[..2], &state)).unwrap());
+        assert!(!sync.offer(long, &genesis, 0, offer_of(&blocks, &state)).unwrap());
+        assert!(matches!(sync.choose(), Progress::Request(p, b, _, _) if p == long && b == blocks[2].hash()));
+        assert!(matches!(sync.offer(short, &genesis, 0, offer_of(&blocks, &state)), Err(SyncError::Busy)));
+
+        // a failed download falls back to the other offer
+        assert!(sync.add_chunk(long, blocks[2].hash(), 7, vec![]).is_err());
+        assert!(matches!(sync.choose(), Progress::Request(p, b, _, _) if p == short && b == blocks[1].hash()));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,11 +1,18 @@
+use super::inventory::{InFlight, PendingBlocks};
+use super::light::{TxProof, MAX_HEADERS};
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::state_sync::{Progress, ServedSnapshot, SnapshotOffer, StateSync, SyncError, OFFER_WINDOW, SNAPSHOT_DEPTH};
+use crate::types::state;
 use crate::types::hash::{H256, Hashable};
-use crate::types::transaction::{Transaction, SignedTransaction, st_verify};
+use crate::types::transaction::{SignedTransaction, st_verify, check_for_mempool, Rejection};
+use crate::config::MempoolConfig;
+use crate::blockchain::events::Event;
 use std::sync::{Arc, Mutex};
-use crate::blockchain::Blockchain;
-use crate::types::block::Block;
+use crate::blockchain::{Blockchain, ChainState};
+use crate::types::block::{Block, Header};
+use crate::types::merkle::MerkleTree;
 use std::collections::HashMap;
 use crate::types::address::Address;
 extern crate queues;
@@ -14,7 +21,7 @@ use ring::{digest};
 use std::time::{SystemTime, UNIX_EPOCH, Duration};
 
 
-use log::{debug, warn, error};
+use log::{debug, info, warn, error};
 
 use std::thread;
 
@@ -32,28 +39,36 @@ pub struct State {
 
 #[derive(Clone)]
 pub struct Worker {
-    msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
+    msg_chan: smol::channel::Receiver<(Message, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     orphans_map: Arc<Mutex<HashMap<H256, Vec<Block>>>>,
     mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
-    states: Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>>, // given block hash, returns state
+    /// Most transactions in the mempool
+    max_mempool: usize,
+    states: Arc<Mutex<HashMap<H256, state::State>>>, // given block hash, returns state
     peers: Arc<Mutex<Vec<Address>>>, 
     // node_start_time: SystemTime, 
-    public_addr: Address
+    public_addr: Address,
+    /// Blocks and transactions already requested from some peer, shared by all worker threads
+    in_flight: Arc<Mutex<InFlight>>,
+    /// Compact blocks waiting for the transactions we asked with `GetBlockTxn`
+    pending_compact: Arc<Mutex<PendingBlocks>>,
+    /// The download of a state snapshot when joining the network
+    state_sync: Arc<Mutex<StateSync>>,
+    /// The sorted accounts of the snapshot we serve to joining peers
+    served_snapshot: Arc<Mutex<ServedSnapshot>>,
 }
 
 
 impl Worker {
     pub fn new(
         num_worker: usize,
-        msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
+        msg_src: smol::channel::Receiver<(Message, peer::Handle)>,
         server: &ServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>, 
-        orphans_map: &Arc<Mutex<HashMap<H256, Vec<Block>>>>,
-        mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
-        states: &Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>>,
+        chain: &ChainState,
+        mempool_config: &MempoolConfig,
         peers: &Arc<Mutex<Vec<Address>>>, 
         // node_start_time: &SystemTime, 
         public_addr: &Address
@@ -62,37 +77,43 @@ impl Worker {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
-            blockchain: Arc::clone(blockchain),
-            orphans_map: Arc::clone(orphans_map),
-            mempool: Arc::clone(mempool),
-            states: Arc::clone(states),
+            blockchain: Arc::clone(&chain.blockchain),
+            orphans_map: Arc::clone(&chain.orphans),
+            mempool: Arc::clone(&chain.mempool),
+            max_mempool: mempool_config.max_transactions,
+            states: Arc::clone(&chain.states),
             peers: Arc::clone(peers), 
             // node_start_time: node_start_time.clone(), 
-            public_addr: public_addr.clone()
+            public_addr: public_addr.clone(),
+            in_flight: Arc::new(Mutex::new(InFlight::default())),
+            pending_compact: Arc::new(Mutex::new(PendingBlocks::default())),
+            state_sync: Arc::new(Mutex::new(StateSync::default())),
+            served_snapshot: Arc::new(Mutex::new(ServedSnapshot::default())),
         }
     }
 
-    pub fn start(self) {
-        let num_worker = self.num_worker;
-        for i in 0..num_worker {
-            let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
-        }
+    /// Start the worker threads. They exit once the message channel is closed and drained.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
+        (0..self.num_worker)
+            .map(|i| {
+                let cloned = self.clone();
+                thread::spawn(move || {
+                    cloned.worker_loop();
+                    info!("Worker thread {} exited", i);
+                })
+            })
+            .collect()
     }
 
     fn worker_loop(&self) {
         loop {
             let result = smol::block_on(self.msg_chan.recv());
-            if let Err(e) = result {
-                error!("network worker terminated {}", e);
+            if result.is_err() {
+                // the server stopped receiving, and every message was handled
                 break;
             }
             let msg = result.unwrap();
             let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -100,11 +121,13 @@ impl Worker {
                 }
                 Message::Pong(nonce) => {
                     debug!("Pong: {}", nonce);
+                    peer.record_pong(&nonce);
                 }
                 // Check if new hashes are already in our blockchain
                 // If they are not send out a get blocks message with the block hashes needed
                 Message::NewBlockHashes(hashes) => {
                     println!("NEW BLOCK HASH MESSAGE RECEIEVED"); 
+                    peer.mark_known_blocks(&hashes);
                     let mut missing_hashes: Vec<H256> = Vec::new();
                     let blockchain_guard = self.blockchain.lock().unwrap();
                     for hash in hashes {
@@ -116,6 +139,7 @@ impl Worker {
                     }
                     std::mem::drop(blockchain_guard);
                     println!("{:?} missing block hashes, length: {:?}", missing_hashes, missing_hashes.len()); 
+                    let missing_hashes = self.claim_requests(missing_hashes);
                     if missing_hashes.len() > 0 {
                         peer.write(Message::GetBlocks(missing_hashes));
                     }
@@ -143,150 +167,69 @@ impl Worker {
                 // NewBlockHashes message should contain hashes of blocks newly received.
                 Message::Blocks(blocks) => {
                     println!("BLOCKS MESSAGE RECEIEVED"); 
-                    let mut blockchain_guard = self.blockchain.lock().unwrap();
-                    let mut new_block_hashes: Vec<H256> = Vec::new();
-                    let mut missing_parents: Vec<H256> = Vec::new();
-
-                    for block in blocks {
-                        if blockchain_guard.blocks.contains_key(&block.hash()) {
-                            continue;
-                        } else {
-                            if blockchain_guard.blocks.contains_key(&block.header.parent) {
-                                // Any time we insert a block that is in our chain, 
-                                // recurisvely check in our orphans map, 
-                                // if any blocks parents are the block that got inserted
-                                let mut queue: Queue<Block> = queue![block]; 
-                                while queue.size() > 0 {
-                                    let curr_block: Block = queue.remove().unwrap();
-
-                                    if curr_block.hash() <= blockchain_guard.blocks[&curr_block.header.parent].header.difficulty 
-                                    && curr_block.header.difficulty == blockchain_guard.blocks[&curr_block.header.parent].header.difficulty {
-                                        // Check all blocks transactions are not in our mempool 
-                                        let mut failed_block = false;
-                                        let mut valid_txs = Vec::new();
-                                        
-                                        let curr_block_data: Vec<SignedTransaction> = curr_block.content.data.clone(); 
-                                        let mut curr_state = self.states.lock().unwrap()[&curr_block.header.parent].clone();
-
-                                        for tx in curr_block_data {
-                                            if st_verify(&tx) {
-                                                let h = digest::digest(&digest::SHA256, tx.pub_key.as_ref());
-                                                let hex_h = hex::encode(h).into_bytes();
-                                                
-                                                if tx.t.sender == Address::from_public_key_bytes(&hex_h) {
-                                                    // let mut curr_state = self.states.lock().unwrap()[&curr_block.header.parent].clone();
-                                                    if curr_state.contains_key(&tx.t.sender) {
-                                                        let (s_nonce, bal) = curr_state[&tx.t.sender];
-                                                        if (s_nonce+1 == tx.t.acc_nonce) && (bal >= tx.t.value) { // Spending check
-                                                            curr_state.insert(
-                                                                tx.t.sender, (s_nonce + 1, bal - tx.t.value)
-                                                            );
-
-                                                            if curr_state.contains_key(&tx.t.receiver) {
-                                                                let (r_nonce, bal) = curr_state[&tx.t.receiver];
-                                                                curr_state.insert(tx.t.receiver, (r_nonce + 1, bal + tx.t.value));
-                                                            } else {
-
-                                                                curr_state.insert(
-                                                                    tx.t.receiver, (1, tx.t.value)
-                                                                );
-                                                            }
-                                                            valid_txs.push(tx.clone());
-                                                        } else {
-                                                            failed_block = true; 
-                                                            break;
-                                                        }
-                                                        
-                                                    } else {
-                                                        failed_block = true; 
-                                                        break;
-                                                    }
-                                                } else {
-                                                    failed_block = true; 
-                                                    break;
-                                                } 
-                                                
-                                            } else {
-                                                failed_block = true; 
-                                                break;
-                                            }
-                                        }
-
-                                        if !failed_block {
-                                            println!("INSERTING BLOCK {:?}, TXS: {:?}", curr_block.hash(), curr_block.content.data.len());
-                                            blockchain_guard.insert(&curr_block);
-                                            println!("PRINT STATE UPDATING BLOCKS\n");
-                                            self.states.lock().unwrap().insert(curr_block.hash(), curr_state);
-                                             // Remove transactions from our mempool
-                                            for tx in valid_txs {
-                                                let mut mempool_guard = self.mempool.lock().unwrap();
-                                                if mempool_guard.contains_key(&tx.hash()) {
-                                                    mempool_guard.remove(&tx.hash());
-                                                } else {
-                                                    //println!("TRANSACTION HASH NOT IN THE MEMPOOL: {:?}", &tx.hash());
-                                                }
-                                                std::mem::drop(mempool_guard);
-                                            }
-                                        } else {
-                                            continue;
-                                        }
-                                        
-                                        new_block_hashes.push(curr_block.hash());
-                                    
-                                        let mut orphans_map_guard = self.orphans_map.lock().unwrap();
-                                        if orphans_map_guard.contains_key(&curr_block.hash()) {
-                                            let orphans_vec: Vec<Block> = orphans_map_guard[&curr_block.hash()].clone(); 
-                                            for elem in orphans_vec {
-                                                let new_block: Block = elem;
-                                                println!("ADDING ORPHAN, ORPHAN TO BE ADDED TO BC: {:?}", new_block);
-                                                queue.add(new_block);
-                                            }
-                                            // TODO: Remove block from orphans map
-                                            orphans_map_guard.remove(&curr_block.hash());
-                                            std::mem::drop(orphans_map_guard);
-                                        }  
-                                    } 
-                                }
-                                
-                                
-                            } else {
-                                // Parent -> Child orphans map
-                                    // If parent not in map 
-                                    // Add new block to map with parent as key, block as value
-                                let mut orphans_map_guard = self.orphans_map.lock().unwrap();
-
-                                let block_parent = block.header.parent;
-                                
-                                if orphans_map_guard.contains_key(&block.header.parent){
-                                    let mut orphan_vec: Vec<Block> = orphans_map_guard[&block_parent].clone();
-                                    orphan_vec.push(block); 
-                                    orphans_map_guard.insert(block_parent, orphan_vec);
-                                } else {
-                                    let mut vec: Vec<Block> = Vec::new(); 
-                                    vec.push(block);
-                                    orphans_map_guard.insert(block_parent, vec);
-                                }   
-                               
-                                // Getblocks? 
-                                missing_parents.push(block_parent);
-                                std::mem::drop(orphans_map_guard);
-
-                            }
-                        }
+                    // the bodies of a snapshot being downloaded are installed with its state
+                    let mut sync_guard = self.state_sync.lock().unwrap();
+                    let (bodies, blocks): (Vec<Block>, Vec<Block>) =
+                        blocks.into_iter().partition(|b| sync_guard.wants(*peer.addr(), &b.hash()));
+                    let progress = if bodies.is_empty() { None } else { Some(sync_guard.add_bodies(*peer.addr(), bodies)) };
+                    std::mem::drop(sync_guard);
+                    if let Some(progress) = progress {
+                        self.sync_progress(progress, &mut peer);
                     }
-
-                    peer.write(Message::GetBlocks(missing_parents));
-
-                    // println!("{:?} ENTIRE NODES BLOCKCHAIN", blockchain_guard.blocks.keys());
-                    std::mem::drop(blockchain_guard);
-                    if new_block_hashes.len() > 0 {
-                        println!("MISSING BLOCK HASHES: {:?}", new_block_hashes.len());
-                        self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
+                    if !blocks.is_empty() {
+                        self.process_blocks(blocks, &mut peer);
+                    }
+                }
+                Message::SendCompact(compact) => {
+                    peer.set_compact(compact);
+                }
+                // Rebuild the block from our mempool, and only ask the peer for the
+                // transactions we do not have
+                Message::CompactBlock(compact) => {
+                    let block_hash = compact.hash();
+                    peer.mark_known_blocks(&[block_hash]);
+                    if self.blockchain.lock().unwrap().blocks.contains_key(&block_hash) {
+                        continue;
+                    }
+                    let partial = compact.fill(&self.mempool.lock().unwrap());
+                    if let Some(block) = partial.complete() {
+                        self.process_blocks(vec![block], &mut peer);
+                        continue;
+                    }
+                    let missing = partial.missing();
+                    if missing.is_empty() {
+                        // every short id matched, but not the merkle root: get the full block
+                        self.request_blocks(vec![block_hash], &mut peer);
+                    } else {
+                        self.pending_compact.lock().unwrap().insert(block_hash, partial);
+                        peer.write(Message::GetBlockTxn(block_hash, missing));
+                    }
+                }
+                Message::GetBlockTxn(block_hash, indexes) => {
+                    let blockchain_guard = self.blockchain.lock().unwrap();
+                    if let Some(block) = blockchain_guard.blocks.get(&block_hash) {
+                        let txs: Vec<SignedTransaction> = indexes
+                            .iter()
+                            .filter_map(|i| block.content.data.get(*i as usize).cloned())
+                            .collect();
+                        std::mem::drop(blockchain_guard);
+                        peer.write(Message::BlockTxn(block_hash, txs));
+                    }
+                }
+                Message::BlockTxn(block_hash, txs) => {
+                    let partial = self.pending_compact.lock().unwrap().take(&block_hash);
+                    if let Some(mut partial) = partial {
+                        partial.supply(txs);
+                        match partial.complete() {
+                            Some(block) => self.process_blocks(vec![block], &mut peer),
+                            None => self.request_blocks(vec![block_hash], &mut peer),
+                        }
                     }
                 }
                 Message::NewTransactionHashes(tx_hashes) => {
                     println!("NEW TXS HASH MESSAGE RECEIEVED");
                     println!("DEBUG STATEMENT");
+                    peer.mark_known_transactions(&tx_hashes);
                     let mut missing_hashes: Vec<H256> = Vec::new();
                     let mempool_guard = self.mempool.lock().unwrap();
                     println!("DEBUG: Taken mempool mutex in NEWTXHASH MSG");
@@ -299,6 +242,7 @@ impl Worker {
                     }
                     std::mem::drop(mempool_guard);
                     println!("{:?} missing tx hashes, length: {:?}", missing_hashes, missing_hashes.len()); 
+                    let missing_hashes = self.claim_requests(missing_hashes);
                     if missing_hashes.len() > 0 {
                         peer.write(Message::GetTransactions(missing_hashes));
                     }
@@ -319,67 +263,139 @@ impl Worker {
                 }
                 Message::Transactions(txs) => {
                     println!("TX MESSAGE RECEIEVED");
-                    let mut new_tx_hashes: Vec<H256> = Vec::new();
+                    let received_hashes: Vec<H256> = txs.iter().map(|t| t.hash()).collect();
+                    peer.mark_known_transactions(&received_hashes);
+                    let mut in_flight_guard = self.in_flight.lock().unwrap();
+                    for h in received_hashes.iter() {
+                        in_flight_guard.complete(h);
+                    }
+                    std::mem::drop(in_flight_guard);
+                    let mut new_txs: Vec<SignedTransaction> = Vec::new();
                     for tx in txs {
-                        // Verify that the tx is signed correctly
-                        if st_verify(&tx) {
-                            
-                            let blockchain_guard = self.blockchain.lock().unwrap();
-                            let h = digest::digest(&digest::SHA256, tx.pub_key.as_ref());
-                            let hex_h = hex::encode(h).into_bytes();
-
-                            if tx.t.sender == Address::from_public_key_bytes(&hex_h) { // Check if the public key matches the owner's address of the withdrawing account
-                                let curr_state = self.states.lock().unwrap()[&blockchain_guard.tip()].clone();
-                                println!("curr_state: {:?}", curr_state);
-                                if curr_state.contains_key(&tx.t.sender) {
-                                    println!("CURR STATE CONTAINS SENDER ADDR");
-                                    let (s_nonce, bal) = curr_state[&tx.t.sender];
-                                    if (s_nonce+1 >= tx.t.acc_nonce) && (bal >= tx.t.value) { // Check if the balance is enough and the suggested account nonce is equal to one plus the account nonce                                        
-                                        let mut mempool_guard = self.mempool.lock().unwrap();
-                                        let tx_hash = tx.hash();
-                                        mempool_guard.insert(tx_hash, tx);
-                                        new_tx_hashes.push(tx_hash);
-                                        std::mem::drop(mempool_guard);
-                                    }
-                                }
+                        let blockchain_guard = self.blockchain.lock().unwrap();
+                        let curr_state = self.states.lock().unwrap()[&blockchain_guard.tip()].clone();
+                        let events = blockchain_guard.events().clone();
+                        std::mem::drop(blockchain_guard);
+                        // signature, sender, nonce and balance
+                        let mut mempool_guard = self.mempool.lock().unwrap();
+                        let check = check_for_mempool(&tx, &curr_state).and_then(|_| {
+                            if mempool_guard.len() >= self.max_mempool && !mempool_guard.contains_key(&tx.hash()) {
+                                Err(Rejection::MempoolFull)
+                            } else {
+                                Ok(())
+                            }
+                        });
+                        match check {
+                            Ok(()) => {
+                                mempool_guard.insert(tx.hash(), tx.clone());
+                                std::mem::drop(mempool_guard);
+                                events.publish(Event::mempool_added(&tx));
+                                new_txs.push(tx);
                             }
+                            Err(reason) => debug!("Rejected transaction {}: {}", tx.hash(), reason),
                         }
                     }
-                    if new_tx_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewTransactionHashes(new_tx_hashes));
+                    peer.adjust_score(new_txs.len() as i64);
+                    if !new_txs.is_empty() {
+                        self.server.relay_transactions(new_txs);
                     }                  
                 }
-                // A new node coming online has asked the node it connects to for its state
-                Message::RequestState(peer_addr) => {
+                // A joining node asks for a snapshot of a recent block of our longest chain
+                Message::GetSnapshot(peer_addr) => {
                     // We add the requesting node's Address to our peers vec
                     self.peers.lock().unwrap().push(peer_addr);
-                    // We send the node our state and our node's Address
-                    let our_state = self.states.lock().unwrap().clone();
-                    println!("Sending State");
-                    peer.write(Message::RespondState(our_state, self.public_addr));
+                    if let Some(offer) = self.snapshot_offer() {
+                        peer.write(Message::Snapshot(offer));
+                    }
                 }
-                // Node responds to new node's request for state
-                Message::RespondState(requested_state, peer_addr) => {
-                    // Add the responding nodes Address to our peers vec
-                    self.peers.lock().unwrap().push(peer_addr);
-
-                    // Set our state equal to the requested state
-                    let mut states_guard = self.states.lock().unwrap();
-                    states_guard.clear();
-
-                    // Copy of requested_state
-                    let mut copy_requested_state:HashMap<H256, HashMap<Address, (u64, u64)>> = HashMap::new();
-                    for (key, value) in requested_state.into_iter() {
-                        let val_c = value.clone();
-                        states_guard.insert(key, value);
-                        copy_requested_state.insert(key, val_c);
+                Message::Snapshot(offer) => {
+                    // Add the offering node's Address to our peers vec
+                    self.peers.lock().unwrap().push(offer.address);
+                    let blockchain_guard = self.blockchain.lock().unwrap();
+                    let genesis = blockchain_guard.blocks[&blockchain_guard.get_genesis_block_hash()].clone();
+                    let height = blockchain_guard.blocks[&blockchain_guard.tip()].height;
+                    // a node that already follows a chain syncs block by block
+                    let joining = blockchain_guard.tip() == genesis.hash();
+                    std::mem::drop(blockchain_guard);
+                    if !joining {
+                        continue;
+                    }
+                    match self.state_sync.lock().unwrap().offer(*peer.addr(), &genesis, height, offer) {
+                        // the first offer opens the window, the one with the most work is
+                        // downloaded once it closes
+                        Ok(true) => {
+                            let worker = self.clone();
+                            thread::spawn(move || {
+                                thread::sleep(OFFER_WINDOW);
+                                let progress = worker.state_sync.lock().unwrap().choose();
+                                if let Progress::Request(addr, block_hash, indexes, bodies) = progress {
+                                    worker.request_snapshot(addr, block_hash, indexes, bodies);
+                                }
+                            });
+                        }
+                        Ok(false) | Err(SyncError::Busy) | Err(SyncError::NotAhead) => {}
+                        Err(e) => {
+                            warn!("Invalid snapshot offer from {}: {}", peer.addr(), e);
+                            peer.adjust_score(-50);
+                        }
+                    }
+                }
+                Message::GetStateChunk(block_hash, index) => {
+                    let states_guard = self.states.lock().unwrap();
+                    let chunk = self.served_snapshot.lock().unwrap().chunk(block_hash, index, &states_guard);
+                    drop(states_guard);
+                    if let Some(accounts) = chunk {
+                        peer.write(Message::StateChunk(block_hash, index, accounts));
+                    }
+                }
+                Message::StateChunk(block_hash, index, accounts) => {
+                    let progress = self.state_sync.lock().unwrap().add_chunk(*peer.addr(), block_hash, index, accounts);
+                    self.sync_progress(progress, &mut peer);
+                }
+                // A light client asks for the headers it is missing
+                Message::GetHeaders(locator) => {
+                    let blockchain_guard = self.blockchain.lock().unwrap();
+                    let chain = blockchain_guard.all_blocks_in_longest_chain();
+                    let positions: HashMap<&H256, usize> = chain.iter().enumerate().map(|(i, h)| (h, i)).collect();
+                    // start after the highest locator hash on our longest chain, or after genesis
+                    let start = locator.iter().find_map(|h| positions.get(h)).map_or(1, |i| i + 1);
+                    let headers: Vec<Header> = chain
+                        .iter()
+                        .skip(start)
+                        .take(MAX_HEADERS)
+                        .map(|h| blockchain_guard.blocks[h].header)
+                        .collect();
+                    std::mem::drop(blockchain_guard);
+                    if !headers.is_empty() {
+                        peer.write(Message::Headers(headers));
+                    }
+                }
+                Message::GetTxProofs(tx_hashes) => {
+                    let blockchain_guard = self.blockchain.lock().unwrap();
+                    let proofs: Vec<TxProof> = blockchain_guard
+                        .find_transactions(&tx_hashes)
+                        .into_iter()
+                        .filter_map(|(block, index)| TxProof::build(block, index))
+                        .collect();
+                    std::mem::drop(blockchain_guard);
+                    if !proofs.is_empty() {
+                        peer.write(Message::TxProofs(proofs));
+                    }
+                }
+                // A light client only wants to hear about the transactions of its addresses
+                Message::FilterLoad(filter) => {
+                    if filter.is_valid() {
+                        peer.set_filter(Some(filter));
+                    } else {
+                        warn!("Oversized filter from {}", peer.addr());
+                        peer.adjust_score(-50);
                     }
-                    std::mem::drop(states_guard);
-                    println!("Receiving State");
-                    println!("Current Peers {:?}", self.peers.lock().unwrap()); 
-                    // let c_requested_state = requested_state.clone();
-                    self.server.sendToEveryoneButMeAndWhereThisMsgCameFrom(peer, Message::RespondState(copy_requested_state, peer_addr));
                 }
+                Message::FilterClear => {
+                    peer.set_filter(None);
+                }
+                // only light clients follow headers and proofs
+                Message::Headers(_) | Message::TxProofs(_) | Message::MerkleBlock(_) => {}
                 // Message::InitialState(time, addr) => {
                 //     println!("Initial State Message RECEIEVED");
                 //     println!("{:?}, {:?}, {:?}", addr, time, self.node_start_time); 
@@ -418,27 +434,289 @@ impl Worker {
                 //     std::mem::drop(peers_guard);
                 //     std::mem::drop(blockchain_guard);
                 // }
-                _ => unimplemented!(),
             }
         }
     }
+
+    /// Insert the blocks into blockchain if not already in it, and relay the ones that are
+    /// new to this node. Blocks whose parent is unknown are kept as orphans and their parent
+    /// is requested from the peer.
+    fn process_blocks(&self, blocks: Vec<Block>, peer: &mut peer::Handle) {
+        let received_hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+        peer.mark_known_blocks(&received_hashes);
+        let mut in_flight_guard = self.in_flight.lock().unwrap();
+        for h in received_hashes.iter() {
+            in_flight_guard.complete(h);
+        }
+        std::mem::drop(in_flight_guard);
+        let mut blockchain_guard = self.blockchain.lock().unwrap();
+        let mut new_blocks: Vec<Block> = Vec::new();
+        let mut missing_parents: Vec<H256> = Vec::new();
+
+        for block in blocks {
+            if blockchain_guard.blocks.contains_key(&block.hash()) {
+                continue;
+            } else {
+                if blockchain_guard.blocks.contains_key(&block.header.parent) {
+                    // Any time we insert a block that is in our chain, 
+                    // recurisvely check in our orphans map, 
+                    // if any blocks parents are the block that got inserted
+                    let mut queue: Queue<Block> = queue![block]; 
+                    while queue.size() > 0 {
+                        let curr_block: Block = queue.remove().unwrap();
+
+                        if curr_block.hash() <= blockchain_guard.blocks[&curr_block.header.parent].header.difficulty 
+                        && curr_block.header.difficulty == blockchain_guard.blocks[&curr_block.header.parent].header.difficulty {
+                            // Check all blocks transactions are not in our mempool 
+                            // why the block is rejected, if it is
+                            let mut failed_block: Option<&'static str> = None;
+                            
+                            let curr_block_data: Vec<SignedTransaction> = curr_block.content.data.clone(); 
+                            // below a state snapshot, the states of the blocks are unknown
+                            let mut curr_state = match self.states.lock().unwrap().get(&curr_block.header.parent) {
+                                Some(s) => s.clone(),
+                                None => {
+                                    debug!("No state for the parent of block {}", curr_block.hash());
+                                    continue;
+                                }
+                            };
+
+                            for tx in curr_block_data {
+                                if st_verify(&tx) {
+                                    let h = digest::digest(&digest::SHA256, tx.pub_key.as_ref());
+                                    let hex_h = hex::encode(h).into_bytes();
+                                    
+                                    if tx.t.sender == Address::from_public_key_bytes(&hex_h) {
+                                        // let mut curr_state = self.states.lock().unwrap()[&curr_block.header.parent].clone();
+                                        if curr_state.contains_key(&tx.t.sender) {
+                                            let (s_nonce, bal) = curr_state[&tx.t.sender];
+                                            if (s_nonce+1 == tx.t.acc_nonce) && (bal >= tx.t.value) { // Spending check
+                                                curr_state.insert(
+                                                    tx.t.sender, (s_nonce + 1, bal - tx.t.value)
+                                                );
+
+                                                if curr_state.contains_key(&tx.t.receiver) {
+                                                    let (r_nonce, bal) = curr_state[&tx.t.receiver];
+                                                    curr_state.insert(tx.t.receiver, (r_nonce + 1, bal + tx.t.value));
+                                                } else {
+
+                                                    curr_state.insert(
+                                                        tx.t.receiver, (1, tx.t.value)
+                                                    );
+                                                }
+                                            } else {
+                                                failed_block = Some(if s_nonce + 1 != tx.t.acc_nonce { "bad_nonce" } else { "insufficient_balance" });
+                                                break;
+                                            }
+                                            
+                                        } else {
+                                            failed_block = Some("unknown_sender");
+                                            break;
+                                        }
+                                    } else {
+                                        failed_block = Some("wrong_sender");
+                                        break;
+                                    } 
+                                    
+                                } else {
+                                    failed_block = Some("bad_signature");
+                                    break;
+                                }
+                            }
+
+                            // the header must commit to the transactions, light clients prove
+                            // inclusion against it
+                            if failed_block.is_none() && MerkleTree::new(&curr_block.content.data).root() != curr_block.header.merkle_root {
+                                debug!("Block {} has a wrong merkle root", curr_block.hash());
+                                failed_block = Some("merkle_root");
+                            }
+
+                            // the header must commit to the state we computed
+                            if failed_block.is_none() && state::state_root(&curr_state) != curr_block.header.state_root {
+                                debug!("Block {} has a wrong state root", curr_block.hash());
+                                failed_block = Some("state_root");
+                            }
+
+                            if let Some(reason) = failed_block {
+                                // the peer relayed a block with invalid transactions or state root
+                                self.server.metrics().record_rejected_block(reason);
+                                peer.adjust_score(-20);
+                                continue;
+                            } else {
+                                debug!("Inserting block {}, txs: {}", curr_block.hash(), curr_block.content.data.len());
+                                // the transactions of the longest chain leave our mempool
+                                blockchain_guard.insert_with_mempool(&curr_block, &curr_state, &mut self.mempool.lock().unwrap());
+                                self.states.lock().unwrap().insert(curr_block.hash(), curr_state);
+                            }
+                            
+                            new_blocks.push(curr_block.clone());
+                        
+                            let mut orphans_map_guard = self.orphans_map.lock().unwrap();
+                            if orphans_map_guard.contains_key(&curr_block.hash()) {
+                                let orphans_vec: Vec<Block> = orphans_map_guard[&curr_block.hash()].clone(); 
+                                for elem in orphans_vec {
+                                    let new_block: Block = elem;
+                                    debug!("Queueing orphan block {}", new_block.hash());
+                                    if let Err(e) = queue.add(new_block) {
+                                        error!("Error queueing an orphan block: {}", e);
+                                    }
+                                }
+                                // TODO: Remove block from orphans map
+                                orphans_map_guard.remove(&curr_block.hash());
+                                std::mem::drop(orphans_map_guard);
+                            }  
+                        } else if curr_block.header.difficulty != blockchain_guard.blocks[&curr_block.header.parent].header.difficulty {
+                            self.server.metrics().record_rejected_block("difficulty");
+                        } else {
+                            self.server.metrics().record_rejected_block("proof_of_work");
+                        }
+                    }
+                    
+                    
+                } else {
+                    // Parent -> Child orphans map
+                        // If parent not in map 
+                        // Add new block to map with parent as key, block as value
+                    let mut orphans_map_guard = self.orphans_map.lock().unwrap();
+
+                    let block_parent = block.header.parent;
+                    
+                    if orphans_map_guard.contains_key(&block.header.parent){
+                        let mut orphan_vec: Vec<Block> = orphans_map_guard[&block_parent].clone();
+                        orphan_vec.push(block); 
+                        orphans_map_guard.insert(block_parent, orphan_vec);
+                    } else {
+                        let mut vec: Vec<Block> = Vec::new(); 
+                        vec.push(block);
+                        orphans_map_guard.insert(block_parent, vec);
+                    }   
+                   
+                    // Getblocks? 
+                    missing_parents.push(block_parent);
+                    std::mem::drop(orphans_map_guard);
+
+                }
+            }
+        }
+
+        let missing_parents = self.claim_requests(missing_parents);
+        if missing_parents.len() > 0 {
+            peer.write(Message::GetBlocks(missing_parents));
+        }
+
+        // println!("{:?} ENTIRE NODES BLOCKCHAIN", blockchain_guard.blocks.keys());
+        std::mem::drop(blockchain_guard);
+        peer.adjust_score(10 * new_blocks.len() as i64);
+        if new_blocks.len() > 0 {
+            debug!("Relaying {} new blocks", new_blocks.len());
+            self.server.relay_blocks(new_blocks);
+        }
+    }
+
+    /// Offer the state after a block `SNAPSHOT_DEPTH` below our tip, or the closest block above
+    /// it whose state we have. The genesis is never offered, its state is in no header.
+    fn snapshot_offer(&self) -> Option<SnapshotOffer> {
+        let blockchain_guard = self.blockchain.lock().unwrap();
+        let states_guard = self.states.lock().unwrap();
+        let chain = blockchain_guard.all_blocks_in_longest_chain();
+        let depth = SNAPSHOT_DEPTH.min(chain.len() - 1);
+        let target = (chain.len() - 1 - depth..chain.len())
+            .find(|i| *i > 0 && states_guard.contains_key(&chain[*i]))?;
+        Some(SnapshotOffer {
+            headers: chain[1..=target].iter().map(|h| blockchain_guard.blocks[h].header).collect(),
+            num_chunks: state::num_chunks(states_guard[&chain[target]].len()),
+            descendants: chain[target + 1..].to_vec(),
+            address: self.public_addr,
+        })
+    }
+
+    /// Ask the peer of the chosen snapshot offer for state chunks and block bodies.
+    fn request_snapshot(&self, addr: std::net::SocketAddr, block_hash: H256, indexes: Vec<u32>, bodies: Vec<H256>) {
+        debug!("Downloading {} state chunks and {} bodies of snapshot {} from {}", indexes.len(), bodies.len(), block_hash, addr);
+        for index in indexes {
+            self.server.send(addr, Message::GetStateChunk(block_hash, index));
+        }
+        if !bodies.is_empty() {
+            self.server.send(addr, Message::GetBlocks(bodies));
+        }
+    }
+
+    /// Act on the progress of the state download: request chunks and bodies, or install the
+    /// verified snapshot and fetch the blocks on top of it. If the peer sent invalid data, the
+    /// next best offer is downloaded.
+    fn sync_progress(&self, progress: Result<Progress, SyncError>, peer: &mut peer::Handle) {
+        match progress {
+            Ok(Progress::Request(addr, block_hash, indexes, bodies)) => {
+                self.request_snapshot(addr, block_hash, indexes, bodies);
+            }
+            Ok(Progress::Pending) => {}
+            Ok(Progress::Complete(snapshot)) => {
+                info!("Installing state snapshot at block {} with {} accounts", snapshot.block, snapshot.state.len());
+                let mut blockchain_guard = self.blockchain.lock().unwrap();
+                // blocks may have been synced one by one meanwhile
+                if snapshot.blocks.len() <= blockchain_guard.blocks[&blockchain_guard.tip()].height as usize {
+                    info!("Dropping state snapshot at block {}, our chain is as long", snapshot.block);
+                    return;
+                }
+                let mut mempool_guard = self.mempool.lock().unwrap();
+                // the states below the snapshot are unknown, the transactions of the blocks that
+                // leave the longest chain are checked against the snapshot state
+                for block in snapshot.blocks.iter() {
+                    blockchain_guard.insert_with_mempool(block, &snapshot.state, &mut mempool_guard);
+                }
+                std::mem::drop(mempool_guard);
+                // the states of the blocks below the snapshot stay unknown
+                self.states.lock().unwrap().insert(snapshot.block, snapshot.state);
+                std::mem::drop(blockchain_guard);
+                let hashes: Vec<H256> = snapshot.blocks.iter().map(|b| b.hash()).collect();
+                peer.mark_known_blocks(&hashes);
+                let descendants = self.claim_requests(snapshot.descendants);
+                if !descendants.is_empty() {
+                    peer.write(Message::GetBlocks(descendants));
+                }
+            }
+            Err(SyncError::Busy) => {}
+            Err(e) => {
+                warn!("State sync with {} failed: {}", peer.addr(), e);
+                peer.adjust_score(-50);
+                let next = self.state_sync.lock().unwrap().choose();
+                self.sync_progress(Ok(next), peer);
+            }
+        }
+    }
+
+    /// Ask the peer for the blocks no other worker already asked some peer for.
+    fn request_blocks(&self, hashes: Vec<H256>, peer: &mut peer::Handle) {
+        let hashes = self.claim_requests(hashes);
+        if !hashes.is_empty() {
+            peer.write(Message::GetBlocks(hashes));
+        }
+    }
+
+    /// Drop the hashes another worker already asked some peer for, and mark the rest as
+    /// requested.
+    fn claim_requests(&self, hashes: Vec<H256>) -> Vec<H256> {
+        let requested = hashes.len();
+        let claimed = self.in_flight.lock().unwrap().claim(hashes);
+        self.server.metrics().record_suppressed_requests(requested - claimed.len());
+        claimed
+    }
 }
 
 #[cfg(any(test,test_utilities))]
 struct TestMsgSender {
-    s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
+    s: smol::channel::Sender<(Message, peer::Handle)>
 }
 #[cfg(any(test,test_utilities))]
 impl TestMsgSender {
-    fn new() -> (TestMsgSender, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+    fn new() -> (TestMsgSender, smol::channel::Receiver<(Message, peer::Handle)>) {
         let (s,r) = smol::channel::unbounded();
         (TestMsgSender {s}, r)
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
         let (handle, r) = peer::Handle::test_handle();
-        smol::block_on(self.s.send((bytes, handle))).unwrap();
+        smol::block_on(self.s.send((msg, handle))).unwrap();
         r
     }
 }
@@ -452,12 +730,13 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let blockchain = Arc::new(Mutex::new(b_chain));
     let orphans_map:Arc<Mutex<HashMap<H256, Vec<Block>>>> = Arc::new(Mutex::new(HashMap::new()));
     let mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>> = Arc::new(Mutex::new(HashMap::new()));
-    let states: Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>> = Arc::new(Mutex::new(HashMap::new()));
+    let states: Arc<Mutex<HashMap<H256, state::State>>> = Arc::new(Mutex::new(HashMap::new()));
      let peers: Arc<Mutex<Vec<Address>>> = Arc::new(Mutex::new(Vec::new()));
     // let node_start_time: SystemTime = SystemTime::now();
      let public_addr: Address = [0u8,0u8,255u8,255u8,1u8,1u8,0u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8,0u8,1u8,0u8,0u8,0u8].into();
 
-    let worker = Worker::new(1, msg_chan, &server, &blockchain, &orphans_map, &mempool, &states, &peers,/*, &node_start_time*/ &public_addr);
+    let chain = ChainState { blockchain: Arc::clone(&blockchain), states, orphans: orphans_map, mempool };
+    let worker = Worker::new(1, msg_chan, &server, &chain, &MempoolConfig::default(), &peers,/*, &node_start_time*/ &public_addr);
     worker.start(); 
     let vec = blockchain.lock().unwrap().all_blocks_in_longest_chain();
     (test_msg_sender, server_receiver, vec)
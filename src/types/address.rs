@@ -3,7 +3,7 @@ use ring::{digest};
 use hex::FromHex;
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct Address([u8; 20]);
 
 impl std::convert::From<&[u8; 20]> for Address {
@@ -20,6 +20,12 @@ impl std::convert::From<[u8; 20]> for Address {
     }
 }
 
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let start = if let Some(precision) = f.precision() {
@@ -11,7 +11,9 @@ pub struct Header {
     pub nonce: u32,
     pub difficulty: H256,
     pub timestamp: u128,
-    pub merkle_root: H256
+    pub merkle_root: H256,
+    /// Root of the account state after this block, see `types::state::state_root`
+    pub state_root: H256
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -67,7 +69,8 @@ pub fn generate_random_block(parent: &H256) -> Block {
         nonce: rand_nonce,
         difficulty: rand_hash,
         timestamp: timestamp,
-        merkle_root: rand_hash
+        merkle_root: rand_hash,
+        state_root: rand_hash
     };
 
     Block{header: header, content: Content {data: Vec::new()}, height: 0}
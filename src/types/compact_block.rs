@@ -0,0 +1,158 @@
+use serde::{Serialize, Deserialize};
+use crate::types::block::{Block, Header, Content};
+use crate::types::hash::{H256, Hashable};
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::SignedTransaction;
+use ring::digest;
+use std::collections::HashMap;
+
+/// A 6-byte transaction id, salted with the block hash so that ids cannot be ground in advance.
+pub type ShortId = [u8; 6];
+
+/// A block announced by its header and the short ids of its transactions. The receiver fills
+/// the transactions from its own mempool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub short_ids: Vec<ShortId>,
+}
+
+/// A compact block whose transactions are only partially known yet.
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    pub header: Header,
+    pub txs: Vec<Option<SignedTransaction>>,
+}
+
+pub fn short_id(block_hash: &H256, tx_hash: &H256) -> ShortId {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(block_hash.as_ref());
+    ctx.update(tx_hash.as_ref());
+    let mut id = [0u8; 6];
+    id.copy_from_slice(&ctx.finish().as_ref()[0..6]);
+    id
+}
+
+impl CompactBlock {
+    pub fn from_block(block: &Block) -> Self {
+        let block_hash = block.hash();
+        Self {
+            header: block.header,
+            short_ids: block.content.data.iter().map(|tx| short_id(&block_hash, &tx.hash())).collect(),
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+
+    /// Fill in the transactions we have in the mempool. Short ids that match several
+    /// transactions of the mempool are left empty and fetched from the peer.
+    pub fn fill(&self, mempool: &HashMap<H256, SignedTransaction>) -> PartialBlock {
+        let block_hash = self.hash();
+        let mut candidates: HashMap<ShortId, Option<&SignedTransaction>> = HashMap::new();
+        for (tx_hash, tx) in mempool.iter() {
+            candidates
+                .entry(short_id(&block_hash, tx_hash))
+                .and_modify(|c| *c = None)
+                .or_insert(Some(tx));
+        }
+        let txs = self
+            .short_ids
+            .iter()
+            .map(|id| candidates.get(id).cloned().flatten().cloned())
+            .collect();
+        PartialBlock { header: self.header, txs }
+    }
+}
+
+impl PartialBlock {
+    /// Indexes of the transactions still missing.
+    pub fn missing(&self) -> Vec<u32> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Put the transactions returned by the peer, in the order of `missing()`.
+    pub fn supply(&mut self, txs: Vec<SignedTransaction>) {
+        let missing = self.missing();
+        for (idx, tx) in missing.into_iter().zip(txs) {
+            self.txs[idx as usize] = Some(tx);
+        }
+    }
+
+    /// Assemble the block once every transaction is known. Returns `None` if something is
+    /// missing or the transactions do not match the Merkle root of the header, e.g. because of
+    /// a short id collision.
+    pub fn complete(&self) -> Option<Block> {
+        let data: Option<Vec<SignedTransaction>> = self.txs.iter().cloned().collect();
+        let data = data?;
+        if MerkleTree::new(&data).root() != self.header.merkle_root {
+            return None;
+        }
+        Some(Block { header: self.header, content: Content { data }, height: 0 })
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::generate_random_hash;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, sign};
+    use ring::signature::KeyPair;
+
+    fn signed_transactions(n: usize) -> Vec<SignedTransaction> {
+        let key = key_pair::random();
+        (0..n)
+            .map(|_| {
+                let t = generate_random_transaction();
+                let sig = sign(&t, &key).as_ref().to_vec();
+                SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reconstruct_with_missing() {
+        let txs = signed_transactions(4);
+        let mut block = generate_random_block(&generate_random_hash());
+        block.header.merkle_root = MerkleTree::new(&txs).root();
+        block.content.data = txs.clone();
+
+        let compact = CompactBlock::from_block(&block);
+        let mut mempool = HashMap::new();
+        for tx in txs.iter().skip(1).take(2) {
+            mempool.insert(tx.hash(), tx.clone());
+        }
+        let mut partial = compact.fill(&mempool);
+        assert_eq!(partial.missing(), vec![0, 3]);
+        assert!(partial.complete().is_none());
+
+        partial.supply(vec![txs[0].clone(), txs[3].clone()]);
+        let rebuilt = partial.complete().unwrap();
+        assert_eq!(rebuilt.hash(), block.hash());
+        assert_eq!(rebuilt.content.data.len(), 4);
+    }
+
+    #[test]
+    fn reject_wrong_transactions() {
+        let txs = signed_transactions(2);
+        let mut block = generate_random_block(&generate_random_hash());
+        block.header.merkle_root = MerkleTree::new(&txs).root();
+        block.content.data = txs;
+
+        let mut partial = CompactBlock::from_block(&block).fill(&HashMap::new());
+        partial.supply(signed_transactions(2));
+        assert!(partial.complete().is_none());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,5 +1,7 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use std::io::Write;
+use std::path::Path;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -8,6 +10,27 @@ pub fn random() -> Ed25519KeyPair {
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
 
+/// Load a PKCS#8 key pair from a file, generating and saving a new one if it does not exist.
+pub fn load_or_generate(path: &Path) -> std::io::Result<Ed25519KeyPair> {
+    let pkcs8_bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let rng = rand::SystemRandom::new();
+            let bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec();
+            // the key is private, only its owner may read it
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(&bytes)?;
+            bytes
+        }
+        Err(e) => return Err(e),
+    };
+    Ed25519KeyPair::from_pkcs8(&pkcs8_bytes)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid PKCS#8 key"))
+}
+
 // pub fn rec_key_pair() -> Ed25519KeyPair {
 //     let mut rng = rand::thread_rng();
 //     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
@@ -17,3 +40,26 @@ pub fn random() -> Ed25519KeyPair {
 //     // let pkcs8_bytes = Ed25519KeyPair::from_seed_and_public_key("10.2.30.123", "").unwrap();
 //     // return "c78b6d77d85a94490ee5ac63d46444735b0d6fa05289acef4e188006a2e256e5";
 // }
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn saved_key_is_private() {
+        let path = std::env::temp_dir().join(format!("bitcoin-key-{}", ::rand::random::<u32>()));
+        let key = load_or_generate(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert_eq!(load_or_generate(&path).unwrap().public_key().as_ref(), key.public_key().as_ref());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
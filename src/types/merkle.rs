@@ -56,20 +56,52 @@ impl MerkleTree {
     /// Returns the Merkle Proof of data at index i
     pub fn proof(&self, index: usize) -> Vec<H256> {
         let mut proof: Vec<H256> = Vec::new();
-        let mut idx = self.tree.len() - (self.len_leaves - index);
-        while idx > 0 {
-            let parent = (idx-1)/2;
-
-            let left = parent*2 + 1;
-            let right = parent*2 + 2;
+        // the levels are stored from the root down to the leaves, each padded to an even width
+        let mut width = self.len_leaves;
+        let mut end = self.tree.len();
+        let mut idx = index;
+        while width > 1 {
+            let start = end - width;
+            proof.push(self.tree[start + (idx ^ 1)]);
+            idx /= 2;
+            end = start;
+            width /= 2;
+            if width > 1 && width % 2 == 1 {
+                width += 1;
+            }
+        }
+        proof
+    }
 
-            if self.tree[left] != self.tree[idx] {
-                proof.push(self.tree[left]);
-            } else {
-                proof.push(self.tree[right]);
+    /// Returns one proof for the data at all these indexes: the siblings of their paths, level
+    /// by level from the leaves, leaving out the nodes that can be computed from the data.
+    pub fn multi_proof(&self, indexes: &[usize]) -> Vec<H256> {
+        let mut proof: Vec<H256> = Vec::new();
+        let mut known: Vec<usize> = indexes.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        let mut width = self.len_leaves;
+        let mut end = self.tree.len();
+        while width > 1 {
+            let start = end - width;
+            let mut i = 0;
+            while i < known.len() {
+                let idx = known[i];
+                if idx % 2 == 0 && known.get(i + 1) == Some(&(idx + 1)) {
+                    // both children are known, no sibling needed
+                    i += 2;
+                } else {
+                    proof.push(self.tree[start + (idx ^ 1)]);
+                    i += 1;
+                }
+            }
+            known = known.iter().map(|idx| idx / 2).collect();
+            known.dedup();
+            end = start;
+            width /= 2;
+            if width > 1 && width % 2 == 1 {
+                width += 1;
             }
-            
-            idx = parent;
         }
         proof
     }
@@ -78,8 +110,10 @@ impl MerkleTree {
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
 /// index of datum and `leaf_size`, the total number of leaves.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+    if index >= leaf_size || proof.len() != depth(leaf_size) {
+        return false;
+    }
     let mut cur_idx = index;
-    let mut cur_leaves = leaf_size;
     let mut cur: H256 = H256::from(*datum);
     
     for x in proof {
@@ -92,19 +126,141 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
             ctx.update(cur.as_ref());
         }
         cur = H256::from(ctx.finish());
-        cur_idx = cur_idx/2 + cur_leaves;
-        if (((cur_leaves+1)/2)%2 == 1) {
-            cur_leaves = cur_leaves + 1;
-        } 
+        // odd levels are padded at the end, so the position in the level above is just halved
+        cur_idx /= 2;
     } 
 
     return cur == *root;
 }
+
+/// Verify several data at once against the Merkle root, with a proof from
+/// `MerkleTree::multi_proof`. `data` pairs the index of each datum with its hash, and
+/// `leaf_size` is the total number of leaves.
+pub fn verify_multi(root: &H256, data: &[(usize, H256)], proof: &[H256], leaf_size: usize) -> bool {
+    let mut known: Vec<(usize, H256)> = data.to_vec();
+    known.sort_by_key(|(idx, _)| *idx);
+    match known.last() {
+        Some((idx, _)) if *idx < leaf_size => {}
+        _ => return false,
+    }
+    if known.windows(2).any(|w| w[0].0 == w[1].0) {
+        return false;
+    }
+    let mut proof = proof.iter();
+    let mut width = leaf_size + leaf_size % 2;
+    while width > 1 {
+        let mut parents: Vec<(usize, H256)> = Vec::new();
+        let mut i = 0;
+        while i < known.len() {
+            let (idx, cur) = known[i];
+            let (left, right) = match known.get(i + 1) {
+                Some((next, sibling)) if idx % 2 == 0 && *next == idx + 1 => {
+                    i += 2;
+                    (cur, *sibling)
+                }
+                _ => {
+                    i += 1;
+                    let sibling = match proof.next() {
+                        Some(s) => *s,
+                        None => return false,
+                    };
+                    if idx % 2 == 0 { (cur, sibling) } else { (sibling, cur) }
+                }
+            };
+            parents.push((idx / 2, hash_pair(&left, &right)));
+        }
+        known = parents;
+        width = (width + 1) / 2;
+    }
+    proof.next().is_none() && known.len() == 1 && known[0].1 == *root
+}
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    H256::from(ctx.finish())
+}
+
+/// An append-only Merkle tree that only keeps one node per level, so a leaf is added in
+/// O(log n). Its root is the root of `MerkleTree::new` over the same data.
+#[derive(Debug, Default, Clone)]
+pub struct MerkleAccumulator {
+    /// For each level, the last complete node that is a left child
+    branch: Vec<H256>,
+    last_leaf: H256,
+    len_leaves: usize,
+}
+
+impl MerkleAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len_leaves
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len_leaves == 0
+    }
+
+    pub fn push<T>(&mut self, datum: &T) where T: Hashable, {
+        let mut node = datum.hash();
+        self.last_leaf = node;
+        self.len_leaves += 1;
+        let mut size = self.len_leaves;
+        let mut level = 0;
+        // complete the nodes this leaf finishes, until one is a left child
+        while size % 2 == 0 {
+            node = hash_pair(&self.branch[level], &node);
+            size /= 2;
+            level += 1;
+        }
+        if level == self.branch.len() {
+            self.branch.push(node);
+        } else {
+            self.branch[level] = node;
+        }
+    }
+
+    pub fn root(&self) -> H256 {
+        if self.len_leaves == 0 {
+            return MerkleTree::default().root();
+        }
+        // walk up the right edge, pairing odd levels with a copy of their last node like
+        // `MerkleTree::new` does
+        let mut cur = self.last_leaf;
+        let mut width = self.len_leaves;
+        let mut level = 0;
+        while width > 1 || level == 0 {
+            cur = if width % 2 == 1 {
+                hash_pair(&cur, &cur)
+            } else {
+                hash_pair(&self.branch[level], &cur)
+            };
+            width = (width + 1) / 2;
+            level += 1;
+        }
+        cur
+    }
+}
+
+/// Number of levels above the leaves in a tree of `leaf_size` leaves, i.e. the proof length.
+fn depth(leaf_size: usize) -> usize {
+    let mut width = leaf_size + leaf_size % 2;
+    let mut depth = 0;
+    while width > 1 {
+        width = (width + 1) / 2;
+        depth += 1;
+    }
+    depth
+}
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod tests {
-    use crate::types::hash::H256;
+    use crate::types::hash::{H256, generate_random_hash};
     use super::*;
 
     macro_rules! gen_merkle_tree_data {
@@ -153,6 +309,51 @@ mod tests {
         let proof = merkle_tree.proof(0);
         assert!(verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
     }
+
+    #[test]
+    fn merkle_multi_proof() {
+        for n in 1..20 {
+            let input_data: Vec<H256> = (0..n).map(|_| generate_random_hash()).collect();
+            let merkle_tree = MerkleTree::new(&input_data);
+            let indexes: Vec<usize> = (0..n).filter(|i| i % 3 != 1).collect();
+            let data: Vec<(usize, H256)> = indexes.iter().map(|i| (*i, input_data[*i].hash())).collect();
+            let proof = merkle_tree.multi_proof(&indexes);
+            assert!(verify_multi(&merkle_tree.root(), &data, &proof, n));
+            // shared siblings are only sent once
+            assert!(proof.len() <= indexes.len() * merkle_tree.proof(0).len());
+
+            let mut wrong = data.clone();
+            wrong[0].1 = generate_random_hash();
+            assert!(!verify_multi(&merkle_tree.root(), &wrong, &proof, n));
+        }
+    }
+
+    #[test]
+    fn accumulator_matches_tree() {
+        let mut accumulator = MerkleAccumulator::new();
+        assert_eq!(accumulator.root(), MerkleTree::new::<H256>(&[]).root());
+        let mut input_data: Vec<H256> = Vec::new();
+        for _ in 0..40 {
+            let datum = generate_random_hash();
+            accumulator.push(&datum);
+            input_data.push(datum);
+            assert_eq!(accumulator.root(), MerkleTree::new(&input_data).root());
+        }
+        assert_eq!(accumulator.len(), 40);
+    }
+
+    #[test]
+    fn merkle_verifying_odd_sizes() {
+        for n in 1..20 {
+            let input_data: Vec<H256> = (0..n).map(|_| generate_random_hash()).collect();
+            let merkle_tree = MerkleTree::new(&input_data);
+            for i in 0..n {
+                let proof = merkle_tree.proof(i);
+                assert!(verify(&merkle_tree.root(), &input_data[i].hash(), &proof, i, n));
+                assert!(!verify(&merkle_tree.root(), &input_data[(i + 1) % n].hash(), &proof, i, n) || n == 1);
+            }
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
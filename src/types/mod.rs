@@ -1,7 +1,10 @@
 pub mod address;
 pub mod block;
+pub mod compact_block;
 pub mod hash;
 pub mod merkle;
+pub mod sparse_merkle;
 pub mod key_pair;
+pub mod state;
 pub mod transaction;
 pub mod transaction_generator;
\ No newline at end of file
@@ -0,0 +1,358 @@
+use serde::{Serialize, Deserialize};
+use super::hash::H256;
+use ring::digest;
+use std::sync::Arc;
+
+/// Number of levels of the tree, one per bit of the key.
+const DEPTH: usize = 256;
+
+/// A sparse Merkle tree with one leaf for each of the 2^256 keys. Empty subtrees hash to zero,
+/// and a subtree holding a single key is a shortcut leaf, whatever its depth, so a tree of `n`
+/// keys has `n` leaves and `n - 1` branches.
+///
+/// Nodes are shared: cloning a tree is cheap, and updating a key only copies the nodes on its
+/// path, so the states of successive blocks share everything they did not change.
+#[derive(Debug, Default, Clone)]
+pub struct SparseMerkleTree {
+    root: Option<Arc<Node>>,
+}
+
+#[derive(Debug)]
+enum Node {
+    /// A subtree holding a single key
+    Leaf { key: H256, value: H256 },
+    /// A subtree whose keys share their bits above `depth`, and differ at `depth`. `key` is any
+    /// of them, `hash` is the hash of the node at `depth`.
+    Branch { depth: usize, key: H256, left: Arc<Node>, right: Arc<Node>, hash: H256 },
+}
+
+/// Proof that a key holds a value, or is empty, in a tree with a given root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SparseProof {
+    /// Depth of the subtree the path to the key ends at: the leaf of the key, an empty subtree,
+    /// or the shortcut leaf of another key
+    pub depth: usize,
+    /// Bit `d` is set if the sibling of the path at depth `d + 1` is not empty. Only those
+    /// siblings are listed.
+    pub non_empty: [u8; 32],
+    /// The non-empty siblings, from the leaf up
+    pub siblings: Vec<H256>,
+    /// The other key and its value, if the path ends at its shortcut leaf
+    pub leaf: Option<(H256, H256)>,
+}
+
+/// Whether the path to `key` goes right at this depth, counted from the root.
+fn goes_right(key: &H256, depth: usize) -> bool {
+    key.as_ref()[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+/// The first depth at which the paths to two keys part, `None` if the keys are equal.
+fn first_difference(a: &H256, b: &H256) -> Option<usize> {
+    a.as_ref()
+        .iter()
+        .zip(b.as_ref().iter())
+        .position(|(x, y)| x != y)
+        .map(|i| i * 8 + (a.as_ref()[i] ^ b.as_ref()[i]).leading_zeros() as usize)
+}
+
+fn leaf_hash(key: &H256, value: &H256) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[0]);
+    ctx.update(key.as_ref());
+    ctx.update(value.as_ref());
+    H256::from(ctx.finish())
+}
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&[1]);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    H256::from(ctx.finish())
+}
+
+/// Hash `node`, the child at `depth + 1` on the path to `key`, with its sibling.
+fn parent(key: &H256, depth: usize, node: &H256, sibling: &H256) -> H256 {
+    if goes_right(key, depth) {
+        hash_pair(sibling, node)
+    } else {
+        hash_pair(node, sibling)
+    }
+}
+
+impl Node {
+    fn key(&self) -> &H256 {
+        match self {
+            Node::Leaf { key, .. } | Node::Branch { key, .. } => key,
+        }
+    }
+
+    /// The hash of the subtree at `depth` holding this node. A branch deeper than that has empty
+    /// siblings up to `depth`.
+    fn hash_at(&self, depth: usize) -> H256 {
+        match self {
+            Node::Leaf { key, value } => leaf_hash(key, value),
+            Node::Branch { depth: own, key, hash, .. } => {
+                let empty = H256::default();
+                (depth..*own).rev().fold(*hash, |cur, d| parent(key, d, &cur, &empty))
+            }
+        }
+    }
+
+    /// The branch at `depth` over two subtrees whose keys part there.
+    fn branch(depth: usize, a: Arc<Node>, b: Arc<Node>) -> Arc<Node> {
+        let (left, right) = if goes_right(a.key(), depth) { (b, a) } else { (a, b) };
+        let hash = hash_pair(&left.hash_at(depth + 1), &right.hash_at(depth + 1));
+        Arc::new(Node::Branch { depth, key: *left.key(), left, right, hash })
+    }
+
+    fn insert(node: &Arc<Node>, key: &H256, value: H256) -> Arc<Node> {
+        let leaf = || Arc::new(Node::Leaf { key: *key, value });
+        match &**node {
+            Node::Leaf { key: other, .. } => match first_difference(other, key) {
+                Some(d) => Node::branch(d, node.clone(), leaf()),
+                None => leaf(),
+            },
+            Node::Branch { depth, key: other, left, right, .. } => match first_difference(other, key) {
+                Some(d) if d < *depth => Node::branch(d, node.clone(), leaf()),
+                _ if goes_right(key, *depth) => Node::branch(*depth, left.clone(), Node::insert(right, key, value)),
+                _ => Node::branch(*depth, Node::insert(left, key, value), right.clone()),
+            },
+        }
+    }
+
+    /// The subtree without `key`, `None` if it becomes empty.
+    fn remove(node: &Arc<Node>, key: &H256) -> Option<Arc<Node>> {
+        match &**node {
+            Node::Leaf { key: other, .. } if other == key => None,
+            Node::Leaf { .. } => Some(node.clone()),
+            Node::Branch { depth, key: other, .. } if first_difference(other, key).map_or(false, |d| d < *depth) => {
+                Some(node.clone())
+            }
+            Node::Branch { depth, left, right, .. } => {
+                let (child, sibling) = if goes_right(key, *depth) { (right, left) } else { (left, right) };
+                match Node::remove(child, key) {
+                    Some(c) if Arc::ptr_eq(&c, child) => Some(node.clone()),
+                    Some(c) => Some(Node::branch(*depth, c, sibling.clone())),
+                    // a single subtree is left, it moves up
+                    None => Some(sibling.clone()),
+                }
+            }
+        }
+    }
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root.as_ref().map_or_else(H256::default, |node| node.hash_at(0))
+    }
+
+    /// The value of a key, or `None` if it is empty.
+    pub fn get(&self, key: &H256) -> Option<H256> {
+        let mut node = self.root.as_ref()?;
+        loop {
+            match &**node {
+                Node::Leaf { key: k, value } => return if k == key { Some(*value) } else { None },
+                Node::Branch { depth, left, right, .. } => {
+                    node = if goes_right(key, *depth) { right } else { left };
+                }
+            }
+        }
+    }
+
+    /// Set the value of a key. A zero value empties it.
+    pub fn update(&mut self, key: &H256, value: H256) {
+        if value == H256::default() {
+            self.root = self.root.as_ref().and_then(|node| Node::remove(node, key));
+            return;
+        }
+        self.root = Some(match &self.root {
+            Some(node) => Node::insert(node, key, value),
+            None => Arc::new(Node::Leaf { key: *key, value }),
+        });
+    }
+
+    pub fn remove(&mut self, key: &H256) {
+        self.update(key, H256::default());
+    }
+
+    /// Prove the value of a key, or that it is empty.
+    pub fn prove(&self, key: &H256) -> SparseProof {
+        let mut non_empty = [0u8; 32];
+        let mut siblings = Vec::new();
+        let mut add_sibling = |depth: usize, hash: H256| {
+            non_empty[depth / 8] |= 1 << (depth % 8);
+            siblings.push(hash);
+        };
+        let mut node = self.root.as_ref();
+        // depth of `node`, the subtree on the path to the key
+        let mut depth = 0;
+        let mut leaf = None;
+        while let Some(n) = node {
+            match &**n {
+                Node::Leaf { key: k, value } => {
+                    if k != key {
+                        leaf = Some((*k, *value));
+                    }
+                    break;
+                }
+                Node::Branch { depth: own, key: k, left, right, .. } => {
+                    // the path to the key leaves the branch above it, into an empty subtree
+                    if let Some(d) = first_difference(k, key).filter(|d| d < own) {
+                        add_sibling(d, n.hash_at(d + 1));
+                        depth = d + 1;
+                        node = None;
+                    } else {
+                        let (child, sibling) = if goes_right(key, *own) { (right, left) } else { (left, right) };
+                        add_sibling(*own, sibling.hash_at(own + 1));
+                        depth = own + 1;
+                        node = Some(child);
+                    }
+                }
+            }
+        }
+        siblings.reverse();
+        SparseProof { depth, non_empty, siblings, leaf }
+    }
+}
+
+impl SparseProof {
+    /// Check that `key` holds `value` in the tree with this root. A zero value proves that the
+    /// key is empty.
+    pub fn verify(&self, root: &H256, key: &H256, value: H256) -> bool {
+        if self.depth > DEPTH {
+            return false;
+        }
+        let empty = H256::default();
+        let mut cur = match (&self.leaf, value == empty) {
+            (None, true) => empty,
+            (None, false) => leaf_hash(key, &value),
+            // another key alone in the subtree of ours proves that ours is empty
+            (Some((other, v)), true) => {
+                if *v == empty || first_difference(other, key).map_or(true, |d| d < self.depth) {
+                    return false;
+                }
+                leaf_hash(other, v)
+            }
+            (Some(_), false) => return false,
+        };
+        let mut siblings = self.siblings.iter();
+        for depth in (0..self.depth).rev() {
+            let sibling = if self.non_empty[depth / 8] & (1 << (depth % 8)) != 0 {
+                match siblings.next() {
+                    Some(s) => *s,
+                    None => return false,
+                }
+            } else {
+                empty
+            };
+            cur = parent(key, depth, &cur, &sibling);
+        }
+        siblings.next().is_none() && cur == *root
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::{generate_random_hash, Hashable};
+
+    #[test]
+    fn root_is_independent_of_order() {
+        let entries: Vec<(H256, H256)> = (0..20).map(|_| (generate_random_hash(), generate_random_hash())).collect();
+        let mut a = SparseMerkleTree::new();
+        let mut b = SparseMerkleTree::new();
+        for (k, v) in entries.iter() {
+            a.update(k, *v);
+        }
+        for (k, v) in entries.iter().rev() {
+            b.update(k, *v);
+        }
+        assert_eq!(a.root(), b.root());
+        assert_eq!(a.get(&entries[3].0), Some(entries[3].1));
+
+        // removing everything gives back the empty tree
+        for (k, _) in entries.iter() {
+            a.remove(k);
+        }
+        assert_eq!(a.root(), H256::default());
+        assert!(a.root.is_none());
+    }
+
+    #[test]
+    fn clones_share_unchanged_nodes() {
+        let mut a = SparseMerkleTree::new();
+        let keys: Vec<H256> = (0..8).map(|_| generate_random_hash()).collect();
+        for k in keys.iter() {
+            a.update(k, k.hash());
+        }
+        let before = a.root();
+        let mut b = a.clone();
+        b.update(&keys[0], generate_random_hash());
+        assert_eq!(a.root(), before);
+        // the side of the root without the updated key was not copied
+        match (&**a.root.as_ref().unwrap(), &**b.root.as_ref().unwrap()) {
+            (Node::Branch { depth, left: al, right: ar, .. }, Node::Branch { left: bl, right: br, .. }) => {
+                if goes_right(&keys[0], *depth) {
+                    assert!(Arc::ptr_eq(al, bl));
+                } else {
+                    assert!(Arc::ptr_eq(ar, br));
+                }
+            }
+            _ => panic!(),
+        }
+        b.remove(&keys[1]);
+        assert_eq!(a.get(&keys[1]), Some(keys[1].hash()));
+        assert_eq!(b.get(&keys[1]), None);
+    }
+
+    #[test]
+    fn update_changes_root() {
+        let mut tree = SparseMerkleTree::new();
+        let key = generate_random_hash();
+        tree.update(&key, generate_random_hash());
+        let before = tree.root();
+        tree.update(&key, generate_random_hash());
+        assert_ne!(tree.root(), before);
+    }
+
+    #[test]
+    fn membership_and_non_membership() {
+        let mut tree = SparseMerkleTree::new();
+        let keys: Vec<H256> = (0..10).map(|_| generate_random_hash()).collect();
+        for k in keys.iter() {
+            tree.update(k, k.hash());
+        }
+        let root = tree.root();
+        for k in keys.iter() {
+            let proof = tree.prove(k);
+            assert!(proof.verify(&root, k, k.hash()));
+            assert!(!proof.verify(&root, k, H256::default()));
+        }
+        let absent = generate_random_hash();
+        let proof = tree.prove(&absent);
+        assert!(proof.verify(&root, &absent, H256::default()));
+        assert!(!proof.verify(&root, &absent, absent.hash()));
+
+        // a key next to a single other one ends at the shortcut leaf of that one
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(keys[0].as_ref());
+        bytes[31] ^= 1;
+        let near: H256 = bytes.into();
+        let proof = tree.prove(&near);
+        assert_eq!(proof.leaf, Some((keys[0], keys[0].hash())));
+        assert!(proof.verify(&root, &near, H256::default()));
+        assert!(!proof.verify(&root, &keys[0], H256::default()));
+
+        let single = SparseMerkleTree { root: None };
+        assert!(single.prove(&near).verify(&H256::default(), &near, H256::default()));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,227 @@
+use serde::{Serialize, Deserialize};
+use crate::types::address::Address;
+use crate::types::hash::{H256, Hashable};
+use crate::types::sparse_merkle::{SparseMerkleTree, SparseProof};
+use ring::digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+
+/// Number of accounts in one chunk of a state snapshot.
+pub const CHUNK_SIZE: usize = 512;
+
+/// The account state after a block: the nonce and the balance of every address, with the tree
+/// committing to it. The tree is updated with each account, so the root never has to be
+/// recomputed from the whole state, and the copy of the state for the next block shares the
+/// nodes of the tree.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "HashMap<Address, (u64, u64)>", into = "HashMap<Address, (u64, u64)>")]
+pub struct State {
+    accounts: HashMap<Address, (u64, u64)>,
+    tree: SparseMerkleTree,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the nonce and the balance of an address, returning the previous ones.
+    pub fn insert(&mut self, address: Address, account: (u64, u64)) -> Option<(u64, u64)> {
+        let (nonce, balance) = account;
+        self.tree.update(&account_key(&address), Account { address, nonce, balance }.hash());
+        self.accounts.insert(address, account)
+    }
+
+    /// The root of the state tree, committed to in the block header.
+    pub fn root(&self) -> H256 {
+        self.tree.root()
+    }
+}
+
+impl Deref for State {
+    type Target = HashMap<Address, (u64, u64)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.accounts
+    }
+}
+
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.accounts == other.accounts
+    }
+}
+
+impl Eq for State {}
+
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.accounts.fmt(f)
+    }
+}
+
+impl std::iter::FromIterator<(Address, (u64, u64))> for State {
+    fn from_iter<I: IntoIterator<Item = (Address, (u64, u64))>>(iter: I) -> Self {
+        let mut state = State::new();
+        for (address, account) in iter {
+            state.insert(address, account);
+        }
+        state
+    }
+}
+
+impl From<HashMap<Address, (u64, u64)>> for State {
+    fn from(accounts: HashMap<Address, (u64, u64)>) -> Self {
+        accounts.into_iter().collect()
+    }
+}
+
+impl From<State> for HashMap<Address, (u64, u64)> {
+    fn from(state: State) -> Self {
+        state.accounts
+    }
+}
+
+/// One entry of the account state, as committed to by the state root.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+    pub address: Address,
+    pub nonce: u64,
+    pub balance: u64,
+}
+
+impl Hashable for Account {
+    fn hash(&self) -> H256 {
+        let s = bincode::serialize(&self).unwrap();
+        digest::digest(&digest::SHA256, &s).into()
+    }
+}
+
+/// The accounts of a state ordered by address, which is how the state is split into chunks.
+pub fn sorted_accounts(state: &State) -> Vec<Account> {
+    let mut accounts: Vec<Account> = state
+        .iter()
+        .map(|(address, (nonce, balance))| Account { address: *address, nonce: *nonce, balance: *balance })
+        .collect();
+    accounts.sort_by_key(|a| a.address);
+    accounts
+}
+
+/// Where the account of an address is stored in the state tree.
+pub fn account_key(address: &Address) -> H256 {
+    let s = bincode::serialize(address).unwrap();
+    digest::digest(&digest::SHA256, &s).into()
+}
+
+/// The root of the state tree, committed to in the block header.
+pub fn state_root(state: &State) -> H256 {
+    state.root()
+}
+
+/// Proof of the account of an address in the state with a given root, or that it has none.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub address: Address,
+    pub account: Option<Account>,
+    pub proof: SparseProof,
+}
+
+impl AccountProof {
+    pub fn verify(&self, state_root: &H256) -> bool {
+        let value = match &self.account {
+            Some(a) if a.address != self.address => return false,
+            Some(a) => a.hash(),
+            None => H256::default(),
+        };
+        self.proof.verify(state_root, &account_key(&self.address), value)
+    }
+}
+
+/// Prove the nonce and balance of an address, or that it has no account.
+pub fn prove(state: &State, address: &Address) -> AccountProof {
+    AccountProof {
+        address: *address,
+        account: state.get(address).map(|(nonce, balance)| Account { address: *address, nonce: *nonce, balance: *balance }),
+        proof: state.tree.prove(&account_key(address)),
+    }
+}
+
+pub fn num_chunks(num_accounts: usize) -> u32 {
+    ((num_accounts + CHUNK_SIZE - 1) / CHUNK_SIZE) as u32
+}
+
+/// The accounts in chunk `index` of a snapshot, from its `sorted_accounts`, or `None` if there
+/// is no such chunk.
+pub fn chunk(accounts: &[Account], index: u32) -> Option<Vec<Account>> {
+    accounts.chunks(CHUNK_SIZE).nth(index as usize).map(|c| c.to_vec())
+}
+
+pub fn from_accounts(accounts: &[Account]) -> State {
+    accounts.iter().map(|a| (a.address, (a.nonce, a.balance))).collect()
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn random_state(n: usize) -> State {
+        (0..n)
+            .map(|i| {
+                let address: Address = rand::random::<[u8; 20]>().into();
+                (address, (i as u64, 50))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn root_ignores_insertion_order() {
+        let state = random_state(20);
+        let mut reversed = State::new();
+        for account in sorted_accounts(&state).iter().rev() {
+            reversed.insert(account.address, (account.nonce, account.balance));
+        }
+        assert_eq!(state_root(&state), state_root(&reversed));
+
+        let mut changed = state.clone();
+        let (address, (nonce, balance)) = state.iter().next().unwrap();
+        changed.insert(*address, (*nonce, balance + 1));
+        assert_ne!(state_root(&state), state_root(&changed));
+        changed.insert(*address, (*nonce, *balance));
+        assert_eq!(state_root(&state), state_root(&changed));
+    }
+
+    #[test]
+    fn prove_accounts() {
+        let state = random_state(7);
+        let root = state_root(&state);
+        for address in state.keys() {
+            let proof = prove(&state, address);
+            assert_eq!(proof.account.unwrap().address, *address);
+            assert!(proof.verify(&root));
+            let mut forged = proof.clone();
+            forged.account.as_mut().unwrap().balance += 1;
+            assert!(!forged.verify(&root));
+            forged.account = None;
+            assert!(!forged.verify(&root));
+        }
+        let absent = prove(&state, &Address::default());
+        assert!(absent.account.is_none());
+        assert!(absent.verify(&root));
+    }
+
+    #[test]
+    fn chunks_cover_state() {
+        let state = random_state(CHUNK_SIZE * 2 + 3);
+        assert_eq!(num_chunks(state.len()), 3);
+        let sorted = sorted_accounts(&state);
+        let accounts: Vec<Account> = (0..3).flat_map(|i| chunk(&sorted, i).unwrap()).collect();
+        assert_eq!(chunk(&sorted, 2).unwrap().len(), 3);
+        assert!(chunk(&sorted, 3).is_none());
+        assert_eq!(from_accounts(&accounts), state);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
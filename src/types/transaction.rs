@@ -5,7 +5,7 @@ use serde::{Serialize,Deserialize};
 use ring::signature::{self, Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use ring::{digest};
 use rand::Rng;
-use crate::network::message::Message;
+use std::collections::HashMap;
 use crate::network::peer;
 use crate::network::server::Handle as ServerHandle;
 use crate::time;
@@ -77,6 +77,67 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     return ret;
 }
 
+/// Why a transaction is not accepted into the mempool.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    BadSignature,
+    /// The public key is not the one of the sender address
+    WrongSender,
+    /// The sender has no account in the state at the tip
+    UnknownSender,
+    /// The nonce is more than one above the nonce of the sender
+    BadNonce,
+    /// The nonce is not above the nonce of the sender, the transaction was applied already
+    NonceUsed,
+    InsufficientBalance,
+    /// The mempool holds `mempool.max_transactions` already
+    MempoolFull,
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rejection::BadSignature => write!(f, "invalid signature"),
+            Rejection::WrongSender => write!(f, "public key does not match the sender"),
+            Rejection::UnknownSender => write!(f, "sender has no account"),
+            Rejection::BadNonce => write!(f, "nonce too high"),
+            Rejection::NonceUsed => write!(f, "nonce already used"),
+            Rejection::InsufficientBalance => write!(f, "balance too low"),
+            Rejection::MempoolFull => write!(f, "mempool is full"),
+        }
+    }
+}
+
+/// Check a transaction before it enters the mempool, against the state at the tip of the
+/// longest chain.
+pub fn check_for_mempool(st: &SignedTransaction, state: &HashMap<Address, (u64, u64)>) -> Result<(), Rejection> {
+    if !st_verify(st) {
+        return Err(Rejection::BadSignature);
+    }
+    // the public key must be the one of the withdrawing account
+    let h = digest::digest(&digest::SHA256, st.pub_key.as_ref());
+    let hex_h = hex::encode(h).into_bytes();
+    if st.t.sender != Address::from_public_key_bytes(&hex_h) {
+        return Err(Rejection::WrongSender);
+    }
+    let (s_nonce, bal) = match state.get(&st.t.sender) {
+        Some(account) => *account,
+        None => return Err(Rejection::UnknownSender),
+    };
+    if s_nonce + 1 < st.t.acc_nonce {
+        return Err(Rejection::BadNonce);
+    }
+    // a replayed transaction must not fill the mempool
+    if st.t.acc_nonce <= s_nonce {
+        return Err(Rejection::NonceUsed);
+    }
+    if bal < st.t.value {
+        return Err(Rejection::InsufficientBalance);
+    }
+    Ok(())
+}
+
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_transaction() -> Transaction {
     let mut rng = rand::thread_rng();
@@ -125,6 +186,33 @@ mod tests {
         assert!(!verify(&t_2, key.public_key().as_ref(), signature.as_ref()));
         assert!(!verify(&t, key_2.public_key().as_ref(), signature.as_ref()));
     }
+
+    #[test]
+    fn mempool_checks() {
+        let key = key_pair::random();
+        let h = digest::digest(&digest::SHA256, key.public_key().as_ref());
+        let sender = Address::from_public_key_bytes(&hex::encode(h).into_bytes());
+        let signed = |t: Transaction| {
+            let sig = sign(&t, &key).as_ref().to_vec();
+            SignedTransaction::new(t, sig, key.public_key().as_ref().to_vec())
+        };
+        let mut state = HashMap::new();
+        let tx = signed(Transaction::new(sender, Address::default(), 10, 1));
+        assert_eq!(check_for_mempool(&tx, &state), Err(Rejection::UnknownSender));
+        state.insert(sender, (0, 50));
+        assert_eq!(check_for_mempool(&tx, &state), Ok(()));
+        let tx = signed(Transaction::new(sender, Address::default(), 60, 1));
+        assert_eq!(check_for_mempool(&tx, &state), Err(Rejection::InsufficientBalance));
+        let tx = signed(Transaction::new(sender, Address::default(), 10, 2));
+        assert_eq!(check_for_mempool(&tx, &state), Err(Rejection::BadNonce));
+        state.insert(sender, (1, 50));
+        let replayed = signed(Transaction::new(sender, Address::default(), 10, 1));
+        assert_eq!(check_for_mempool(&replayed, &state), Err(Rejection::NonceUsed));
+        state.insert(sender, (0, 50));
+        let mut forged = signed(Transaction::new(sender, Address::default(), 10, 1));
+        forged.t.value = 20;
+        assert_eq!(check_for_mempool(&forged, &state), Err(Rejection::BadSignature));
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
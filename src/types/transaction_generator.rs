@@ -1,21 +1,24 @@
 use log::{debug, info};
 use std::thread;
 use crate::network::server::Handle as ServerHandle;
-use crate::network::message::Message;
 use crate::types::transaction::{SignedTransaction, Transaction, sign};
 use crate::types::address::Address;
 use crate::types::hash::{H256, Hashable};
 use crate::types::key_pair;
+use crate::types::state;
 use serde::{Serialize,Deserialize};
 use ring::signature::{self, Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
 use ring::{digest};
 use rand::Rng;
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{unbounded, Receiver, Sender, RecvTimeoutError};
 use std::time::{SystemTime};
 use std::sync::{Arc, Mutex};
 use crate::time;
 use std::collections::HashMap;
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, ChainState};
+use crate::config::MempoolConfig;
+use crate::blockchain::events::Event;
+use crate::miner::State;
 
 // use std::fs::File;
 // use std::fs::OpenOptions;
@@ -24,75 +27,206 @@ use crate::blockchain::Blockchain;
 use rand::seq::SliceRandom;
 
 
-#[derive(Clone)]
+enum ControlSignal {
+    Start(u64), // the number controls the interval between transactions
+    Pause,
+    Resume, // start again with the theta of the last start
+    Exit,
+}
+
+enum OperatingState {
+    Paused,
+    Run(u64),
+    ShutDown,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub state: State,
+    /// The theta of the last start, kept while paused
+    pub theta: Option<u64>,
+    pub transactions_generated: u64,
+}
+
 pub struct TransactionGenerator {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    operating_state: OperatingState,
+    status: Arc<Mutex<Status>>,
     server: ServerHandle, 
     mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    /// Most transactions in the mempool, none are generated beyond it
+    max_mempool: usize,
     key: Arc<Mutex<Ed25519KeyPair>>,
-    states: Arc<Mutex<HashMap<H256 ,HashMap<Address, (u64, u64)>>>>,
+    states: Arc<Mutex<HashMap<H256, state::State>>>,
     blockchain: Arc<Mutex<Blockchain>>,
     public_addr: Address,
     // node_start_time: SystemTime,
     peers: Arc<Mutex<Vec<Address>>>,
 }
 
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the generator thread
+    control_chan: Sender<ControlSignal>,
+    status: Arc<Mutex<Status>>,
+}
+
+// Sending fails once the generator thread exited, and then there is nothing left to control.
+impl Handle {
+    pub fn exit(&self) {
+        let _ = self.control_chan.send(ControlSignal::Exit);
+    }
+
+    /// Start generating, or change the theta if the generator is already running.
+    pub fn start(&self, theta: u64) {
+        let _ = self.control_chan.send(ControlSignal::Start(theta));
+    }
+
+    pub fn pause(&self) {
+        let _ = self.control_chan.send(ControlSignal::Pause);
+    }
+
+    /// Start again with the theta of the last start. Does nothing if the generator was never started.
+    pub fn resume(&self) {
+        let _ = self.control_chan.send(ControlSignal::Resume);
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+}
+
 impl TransactionGenerator {
 
     pub fn new(
         server: &ServerHandle,
-        mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+        chain: &ChainState,
+        mempool_config: &MempoolConfig,
         key: &Arc<Mutex<Ed25519KeyPair>>,
-        states: &Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>>,
-        blockchain: &Arc<Mutex<Blockchain>>,
         public_addr: &Address,
         // node_start_time: &SystemTime,
         peers: &Arc<Mutex<Vec<Address>>>,
-    ) -> Self {
-        Self {
+    ) -> (Self, Handle) {
+        let (signal_chan_sender, signal_chan_receiver) = unbounded();
+        let status = Arc::new(Mutex::new(Status { state: State::Paused, theta: None, transactions_generated: 0 }));
+        let generator = Self {
+            control_chan: signal_chan_receiver,
+            operating_state: OperatingState::Paused,
+            status: Arc::clone(&status),
             server: server.clone(),
-            mempool: Arc::clone(mempool),
+            mempool: Arc::clone(&chain.mempool),
+            max_mempool: mempool_config.max_transactions,
             key: Arc::clone(key),
-            states: Arc::clone(states),
-            blockchain: Arc::clone(blockchain),
+            states: Arc::clone(&chain.states),
+            blockchain: Arc::clone(&chain.blockchain),
             public_addr: public_addr.clone(),
             // node_start_time: node_start_time.clone(),
             peers: Arc::clone(peers),
-        }
+        };
+        let handle = Handle {
+            control_chan: signal_chan_sender,
+            status,
+        };
+        (generator, handle)
     }
 
-    pub fn start(mut self, theta: u64) {
-        thread::Builder::new()
+    /// Start the generator thread, which exits on `Handle::exit`.
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        let thread = thread::Builder::new()
         .name("transacation-generator".to_string())
         .spawn(move || {
-            self.transaction_loop(3*theta);
+            self.transaction_loop();
         })
         .unwrap();
-        info!("Transaction Generator initialized");
+        info!("Transaction Generator initialized into paused mode");
+        thread
+    }
+
+    fn handle_signal(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::Exit => {
+                info!("Transaction Generator shutting down");
+                self.set_state(OperatingState::ShutDown);
+            }
+            ControlSignal::Start(theta) => {
+                info!("Transaction Generator starting with theta {}", theta);
+                self.set_state(OperatingState::Run(theta));
+            }
+            ControlSignal::Pause => {
+                if let OperatingState::Run(_) = self.operating_state {
+                    info!("Transaction Generator paused");
+                    self.set_state(OperatingState::Paused);
+                }
+            }
+            ControlSignal::Resume => {
+                let theta = self.status.lock().unwrap().theta;
+                if let (OperatingState::Paused, Some(theta)) = (&self.operating_state, theta) {
+                    info!("Transaction Generator resuming with theta {}", theta);
+                    self.set_state(OperatingState::Run(theta));
+                }
+            }
+        }
     }
 
-    pub fn transaction_loop(&mut self, theta: u64) {
+    fn set_state(&mut self, operating_state: OperatingState) {
+        let mut status = self.status.lock().unwrap();
+        match operating_state {
+            OperatingState::Paused => status.state = State::Paused,
+            OperatingState::Run(theta) => {
+                status.state = State::Running;
+                status.theta = Some(theta);
+            }
+            OperatingState::ShutDown => status.state = State::Stopped,
+        }
+        self.operating_state = operating_state;
+    }
+
+    fn transaction_loop(&mut self) {
         loop {
+            match self.operating_state {
+                OperatingState::Paused => {
+                    match self.control_chan.recv() {
+                        Ok(signal) => self.handle_signal(signal),
+                        Err(_) => return,
+                    }
+                    continue;
+                }
+                OperatingState::ShutDown => return,
+                OperatingState::Run(_) => {}
+            }
+
             // let s_tx: SignedTransaction = ;//generate_random_signed_transaction(&self.key, &self.states, &self.blockchain);
             let state_contains_addr = self.states.lock().unwrap()[&self.blockchain.lock().unwrap().tip()].contains_key(&self.public_addr);
-            // Only create transactions if we have something to provide
-            if state_contains_addr {
+            let mempool_full = self.mempool.lock().unwrap().len() >= self.max_mempool;
+            // Only create transactions if we have something to provide, and room for them
+            if mempool_full {
+                debug!("The mempool is full, did not create a transaction.");
+            } else if state_contains_addr {
                 let s_tx = generate_random_signed_transaction(&self.key, &self.public_addr, &self.peers, &self.states, &self.blockchain);
                 
                 let mut mempool_guard = self.mempool.lock().unwrap();
                 let tx_hash = s_tx.hash();
-                mempool_guard.insert(tx_hash, s_tx);
+                mempool_guard.insert(tx_hash, s_tx.clone());
                 std::mem::drop(mempool_guard);
+                self.blockchain.lock().unwrap().events().publish(Event::mempool_added(&s_tx));
 
-                self.server.broadcast(Message::NewTransactionHashes(vec![tx_hash]));
+                self.server.relay_transactions(vec![s_tx]);
+                self.status.lock().unwrap().transactions_generated += 1;
                 // TODO Place this somewhere better
                 // self.server.broadcast(Message::InitialState(self.node_start_time, self.public_addr));
             } else {
                 println!("This node has balance=0, did not recieve ICO or balance from peers. Cannot create txs.");
             }
             
-            thread::sleep(time::Duration::from_millis(theta));
-            // let interval = Duration::from_micros(theta as u64);
-            // thread::sleep(interval);
+            // wait for the next transaction, unless a control signal comes first
+            if let OperatingState::Run(theta) = self.operating_state {
+                match self.control_chan.recv_timeout(time::Duration::from_millis(3 * theta)) {
+                    Ok(signal) => self.handle_signal(signal),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
         }
     }
 }
@@ -101,7 +235,7 @@ pub fn generate_random_signed_transaction(
     key: &Arc<Mutex<Ed25519KeyPair>>, 
     public_addr: &Address, 
     peers: &Arc<Mutex<Vec<Address>>>, 
-    states: &Arc<Mutex<HashMap<H256, HashMap<Address, (u64, u64)>>>>, 
+    states: &Arc<Mutex<HashMap<H256, state::State>>>, 
     blockchain: &Arc<Mutex<Blockchain>>
 ) -> SignedTransaction {
 